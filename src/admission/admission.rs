@@ -1,12 +1,19 @@
 use crate::resources::{
     bgp_router,
-    bgp_router_group
+    bgp_router_group,
+    resources,
 };
 use crate::controllers::controllers;
+use crate::config::config::OperatorConfig;
+use std::sync::Arc;
 use kube::{core::{
     admission::{AdmissionRequest, AdmissionResponse, AdmissionReview},
-    DynamicObject, Resource, ResourceExt,
-}, Client};
+    conversion::{ConversionRequest, ConversionResponse, ConversionReview},
+    DynamicObject, Resource, ResourceExt, Status,
+}, Client, Api};
+use kube::api::{Patch, PatchParams};
+use k8s_openapi::apiextensions_apiserver::pkg::apis::apiextensions::v1::CustomResourceDefinition;
+use tokio::time::{sleep, Duration};
 use crate::cert::cert;
 use k8s_openapi::api::admissionregistration::v1 as adm_v1;
 use std::{convert::Infallible, error::Error, collections::HashMap};
@@ -17,21 +24,21 @@ use k8s_openapi::ByteString;
 
 pub struct AdmissionController{
     address: String,
-    dns: String,
+    config: Arc<OperatorConfig>,
     client: Client,
 }
 
 impl AdmissionController{
-    pub fn new(address: String, dns: String, client: Client) -> Self{
+    pub fn new(address: String, config: Arc<OperatorConfig>, client: Client) -> Self{
         Self{
             address,
-            dns,
+            config,
             client,
         }
     }
     pub async fn admission(&self) -> anyhow::Result<()>{
         info!("Starting admission controller");
-        let (ca, kp) = match cert::create_ca_key_cert(self.dns.clone()){
+        let (ca, kp) = match cert::create_ca_key_cert(self.config.webhook.dns.clone()){
             Ok(ca_cert_pem) => {
                 ca_cert_pem
             },
@@ -49,7 +56,9 @@ impl AdmissionController{
                 return Err(e);
             }
         };
-        let (key, cert) = match cert::create_sign_private_key(self.dns.clone(), self.address.clone(), ca_cert){
+        // the apiserver reaches the conversion webhook through the Service
+        let service_dns = format!("{}.{}.svc", self.config.webhook.dns, self.config.namespace);
+        let (key, cert) = match cert::create_sign_private_key(service_dns, self.address.clone(), ca_cert){
             Ok((key, cert)) => {
                 (key, cert)
             },
@@ -58,17 +67,29 @@ impl AdmissionController{
                 return Err(e);
             }
         };
-        self.adm_registration(ca).await?;
+        self.adm_registration(ca.clone()).await?;
 
-        let routes = warp::path("mutate")
+        let client = self.client.clone();
+        let config = self.config.clone();
+        tokio::spawn(async move {
+            if let Err(e) = conversion_registration(client, config, ca).await{
+                error!("Failed to register conversion webhook: {}", e);
+            }
+        });
+
+        let mutate = warp::path("mutate")
             .and(warp::body::json())
-            .and_then(mutate_handler)
+            .and_then(mutate_handler);
+        let convert = warp::path("convert")
+            .and(warp::body::json())
+            .and_then(convert_handler);
+        let routes = mutate.or(convert)
             .with(warp::trace::request());
     
         // You must generate a certificate for the service / url,
         // encode the CA in the MutatingWebhookConfiguration, and terminate TLS here.
         // See admission_setup.sh + admission_controller.yaml.tpl for how to do this.
-        let addr = format!("{}:8443", self.address.clone());
+        let addr = format!("{}:{}", self.address.clone(), self.config.webhook.port);
         warp::serve(warp::post().and(routes))
             .tls()
             .cert(cert.as_bytes())
@@ -82,18 +103,18 @@ impl AdmissionController{
         info!("Registering admission controller mutating webhook");
         //let ca_pem_64 = general_purpose::STANDARD.encode(&ca_pem.as_bytes());
         let mut mutating_webhook_config = adm_v1::MutatingWebhookConfiguration::default();
-        mutating_webhook_config.metadata.name = Some("cnm-mutating-webhook-config".to_string());
+        mutating_webhook_config.metadata.name = Some(self.config.webhook.name.clone());
         mutating_webhook_config.webhooks = Some(vec![adm_v1::MutatingWebhook{
-            name: "cnm-admission.default.svc".to_string(),
+            name: format!("cnm-admission.{}.svc", self.config.namespace),
             client_config: adm_v1::WebhookClientConfig{
-                url: Some(format!("https://{}:8443/mutate", self.address.clone())),
+                url: Some(format!("https://{}:{}/mutate", self.address.clone(), self.config.webhook.port)),
                 ca_bundle: Some(ByteString(ca_pem.as_bytes().to_vec())),
                 ..Default::default()
             },
             rules: Some(vec![adm_v1::RuleWithOperations{
                 operations: Some(vec!["CREATE".to_string(), "UPDATE".to_string(), "DELETE".to_string()]),
                 api_groups: Some(vec!["cnm.juniper.net".to_string()]),
-                api_versions: Some(vec!["v1".to_string()]),
+                api_versions: Some(resources::SERVED_VERSIONS.iter().map(|version| version.to_string()).collect()),
                 resources: Some(vec!["bgprouters".to_string(), "bgproutergroups".to_string()]),
                 scope: Some("Namespaced".to_string()),
                 ..Default::default()
@@ -104,7 +125,7 @@ impl AdmissionController{
             timeout_seconds: Some(5),
             ..Default::default()
         }]);
-        controllers::create_or_update_cluster::<adm_v1::MutatingWebhookConfiguration>(mutating_webhook_config, self.client.clone()).await?;
+        controllers::apply_cluster::<adm_v1::MutatingWebhookConfiguration>(mutating_webhook_config, "cnm-admission", true, self.client.clone()).await?;
         info!("Admission controller mutating webhook registered");
         Ok(())
     }
@@ -112,6 +133,74 @@ impl AdmissionController{
 
 }
 
+// conversion_registration sets the conversion strategy of the multi version CRDs:
+// the CONVERTED_KINDS are converted by the /convert endpoint behind the webhook
+// Service, the others by the apiserver. The CRDs are installed concurrently, so
+// missing CRDs are retried.
+async fn conversion_registration(client: Client, config: Arc<OperatorConfig>, ca_pem: String) -> anyhow::Result<()>{
+    let crds: Api<CustomResourceDefinition> = Api::all(client);
+    let params = PatchParams{
+        field_manager: Some("cnm-admission".to_string()),
+        ..Default::default()
+    };
+    for (name, kind) in resources::CRDS{
+        let fqdn = format!("{}.{}", name, resources::GROUP);
+        let conversion = conversion(kind, &config, &ca_pem);
+        let mut attempts = 0;
+        loop {
+            match crds.patch(fqdn.as_str(), &params, &Patch::Merge(&conversion)).await{
+                Ok(_) => {
+                    info!("Conversion registered for {}", fqdn);
+                    break;
+                },
+                Err(e) => {
+                    attempts += 1;
+                    if !controllers::is_not_found(&e) || attempts > 30{
+                        return Err(e.into());
+                    }
+                    sleep(Duration::from_secs(1)).await;
+                },
+            }
+        }
+    }
+    Ok(())
+}
+
+// conversion returns the merge patch of the conversion of a CRD. The webhook
+// is reached through its Service, the serving certificate is issued for it.
+fn conversion(kind: &str, config: &OperatorConfig, ca_pem: &str) -> serde_json::Value{
+    if !resources::CONVERTED_KINDS.contains(&kind){
+        return serde_json::json!({
+            "spec": {
+                "conversion": {
+                    "strategy": "None",
+                    "webhook": null,
+                },
+            },
+        });
+    }
+    serde_json::json!({
+        "spec": {
+            "conversion": {
+                "strategy": "Webhook",
+                "webhook": {
+                    "clientConfig": {
+                        "url": null,
+                        "service": {
+                            "namespace": config.namespace,
+                            "name": config.webhook.dns,
+                            "path": "/convert",
+                            "port": config.webhook.port,
+                        },
+                        "caBundle": general_purpose::STANDARD.encode(ca_pem.as_bytes()),
+                    },
+                    "conversionReviewVersions": ["v1"],
+                },
+            },
+        },
+    })
+}
+
 fn mutate(res: AdmissionResponse, obj: &DynamicObject) -> Result<AdmissionResponse, Box<dyn Error>> {
     if let Some(types) = &obj.types{
         info!("Kind: {}", types.kind);
@@ -191,6 +280,54 @@ async fn mutate_handler(body: AdmissionReview<DynamicObject>) -> Result<impl Rep
 }
 
 
+// The /convert handler, converting every object of the review to the desired apiVersion
+async fn convert_handler(review: ConversionReview) -> Result<impl Reply, Infallible> {
+    let req = match ConversionRequest::from_review(review) {
+        Ok(req) => req,
+        Err(err) => {
+            error!("invalid conversion request: {}", err.to_string());
+            return Ok(reply::json(
+                &ConversionResponse::invalid(Status::failure(&err.to_string(), "InvalidRequest")).into_review(),
+            ));
+        }
+    };
+    let desired_api_version = req.desired_api_version.clone();
+    let mut converted_objects = Vec::new();
+    for object in &req.objects{
+        match resources::convert(object.clone(), &desired_api_version){
+            Ok(converted) => converted_objects.push(converted),
+            Err(err) => {
+                warn!("conversion to {} failed: {}", desired_api_version, err);
+                return Ok(reply::json(
+                    &ConversionResponse::for_request(req).failure(Status::failure(&err.to_string(), "ConversionFailed")).into_review(),
+                ));
+            }
+        }
+    }
+    Ok(reply::json(&ConversionResponse::for_request(req).success(converted_objects).into_review()))
+}
+
 // The main handler and core business logic, failures here implies rejected applies
 
 
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn conversion_is_left_to_the_apiserver_for_shared_schemas(){
+        let config = OperatorConfig::default();
+        for (_, kind) in resources::CRDS{
+            let conversion = conversion(kind, &config, "ca");
+            if resources::CONVERTED_KINDS.contains(&kind){
+                assert_eq!(conversion["spec"]["conversion"]["strategy"], "Webhook");
+                assert_eq!(conversion["spec"]["conversion"]["webhook"]["clientConfig"]["service"]["path"], "/convert");
+                continue;
+            }
+            assert_eq!(conversion["spec"]["conversion"]["strategy"], "None");
+            assert!(conversion["spec"]["conversion"]["webhook"].is_null());
+        }
+        assert_eq!(conversion("RoutingInstance", &config, "ca")["spec"]["conversion"]["strategy"], "Webhook");
+    }
+}
@@ -1,14 +1,16 @@
 use cnm_rs::controllers::controllers::Context;
 use cnm_rs::resources;
 use cnm_rs::controllers::{
-    crpd::crpd::CrpdController,
-    bgp_router::BgpRouterController,
-    crpd::bgp_router_group::BgpRouterGroupController,
-    crpd::junos_configuration::JunosConfigurationController,
     controllers,
+    cache::Cache,
+    registry,
 };
 use cnm_rs::admission;
 use cnm_rs::cert::cert;
+use cnm_rs::leader_election::leader_election::LeaderElection;
+use cnm_rs::metrics::metrics;
+use cnm_rs::health::health;
+use cnm_rs::config::config::{OperatorConfig, ControllerKind};
 use kube::Client;
 use k8s_openapi::apimachinery::pkg::apis::meta::v1 as meta_v1;
 use k8s_openapi::api::core::v1 as core_v1;
@@ -16,7 +18,7 @@ use std::collections::BTreeMap;
 use k8s_openapi::ByteString;
 use clap::Parser;
 use std::sync::Arc;
-use tracing::info;
+use tracing::{info, warn};
 
 
 
@@ -26,11 +28,85 @@ struct Args {
     #[arg(short, long, default_value = "cnm")]
     name: Option<String>,
 
-    #[arg(long, default_value = "default")]
+    #[arg(short, long, env = "CNM_CONFIG")]
+    config: Option<String>,
+
+    #[arg(long, env = "POD_NAMESPACE")]
     namespace: Option<String>,
 
     #[arg(short, long)]
     address: Option<String>,
+
+    #[arg(long, env = "CNM_LEASE_NAME")]
+    lease_name: Option<String>,
+
+    #[arg(long)]
+    identity: Option<String>,
+
+    #[arg(long, env = "CNM_METRICS_PORT")]
+    metrics_port: Option<u16>,
+
+    #[arg(long, env = "CNM_WEBHOOK_PORT")]
+    webhook_port: Option<u16>,
+
+    #[arg(long, env = "CNM_JET_PORT")]
+    jet_port: Option<u16>,
+
+    #[arg(long, env = "CNM_CA_SECRET")]
+    ca_secret: Option<String>,
+
+    #[arg(long, env = "CNM_WATCH_NAMESPACES", value_delimiter = ',')]
+    watch_namespaces: Option<Vec<String>>,
+
+    #[arg(long, env = "CNM_CONTROLLERS", value_delimiter = ',')]
+    controllers: Option<Vec<ControllerKind>>,
+
+    #[arg(long, env = "CNM_DISABLE_CONTROLLERS", value_delimiter = ',')]
+    disable_controllers: Option<Vec<ControllerKind>>,
+}
+
+// operator_config loads the config file, if any, and applies the
+// command line and environment overrides on top of it
+fn operator_config(args: &Args) -> anyhow::Result<OperatorConfig>{
+    let mut config = match &args.config{
+        Some(path) => OperatorConfig::from_file(path)?,
+        None => OperatorConfig::default(),
+    };
+    if let Some(namespace) = &args.namespace{
+        config.namespace = namespace.clone();
+    }
+    if let Some(lease_name) = &args.lease_name{
+        config.lease_name = lease_name.clone();
+    }
+    if let Some(metrics_port) = args.metrics_port{
+        config.metrics_port = metrics_port;
+    }
+    if let Some(webhook_port) = args.webhook_port{
+        config.webhook.port = webhook_port;
+    }
+    if let Some(jet_port) = args.jet_port{
+        config.jet.port = jet_port;
+    }
+    if let Some(ca_secret) = &args.ca_secret{
+        config.ca_secret = ca_secret.clone();
+    }
+    if let Some(watch_namespaces) = &args.watch_namespaces{
+        config.watch_namespaces = watch_namespaces.clone();
+    }
+    if let Some(controllers) = &args.controllers{
+        config.controllers = controllers.clone();
+    }
+    if let Some(disable_controllers) = &args.disable_controllers{
+        config.controllers.retain(|controller| !disable_controllers.contains(controller));
+    }
+    let mut controllers = Vec::new();
+    for controller in &config.controllers{
+        if !controllers.contains(controller){
+            controllers.push(*controller);
+        }
+    }
+    config.controllers = controllers;
+    Ok(config)
 }
 
 
@@ -45,6 +121,7 @@ async fn main() -> anyhow::Result<()> {
     .init();
 
     let args = Args::parse();
+    let config = Arc::new(operator_config(&args)?);
 
     let name = if let Some(name) = args.name{
         name
@@ -64,18 +141,21 @@ async fn main() -> anyhow::Result<()> {
         } 
     };
 
-    let namespace = if let Some(namespace) = args.namespace{
-        namespace
-    } else {
-        match std::env::var("POD_NAMESPACE"){
-            Ok(namespace) => { namespace }
-            Err(e) => { return Err(e.into())}
-        } 
+    let namespace = config.namespace.clone();
+
+    let identity = match args.identity{
+        Some(identity) => identity,
+        None => {
+            match std::env::var("POD_NAME").or_else(|_| std::env::var("HOSTNAME")){
+                Ok(identity) => identity,
+                Err(e) => { return Err(e.into())}
+            }
+        }
     };
 
     let client = Client::try_default().await?;
 
-    let secret = match controllers::get::<core_v1::Secret>(namespace.clone(), "cnm-ca".to_string(), client.clone()).await{
+    let secret = match controllers::get::<core_v1::Secret>(namespace.clone(), config.ca_secret.clone(), client.clone()).await{
         Ok(secret) => { secret },
         Err(e) => { return Err(e.into())},
     };
@@ -117,7 +197,7 @@ async fn main() -> anyhow::Result<()> {
             };
             let secret = core_v1::Secret{
                 metadata: meta_v1::ObjectMeta{
-                    name: Some("cnm-ca".to_string()),
+                    name: Some(config.ca_secret.clone()),
                     namespace: Some(namespace.clone()),
                     ..Default::default()
                 },
@@ -129,7 +209,7 @@ async fn main() -> anyhow::Result<()> {
                     ])),
                 ..Default::default()
             };
-            controllers::create_or_update(secret, client.clone()).await?;
+            controllers::apply(secret, "cnm", true, client.clone()).await?;
             (ca, kp)
         },
     };
@@ -146,13 +226,31 @@ async fn main() -> anyhow::Result<()> {
 
     let mut join_handlers = Vec::new();
 
-    let adm = admission::admission::AdmissionController::new(address.clone(),"cnm-admission-controller".to_string(), client.clone());
-    
+    if config.is_enabled(ControllerKind::Admission){
+        let adm = admission::admission::AdmissionController::new(address.clone(), config.clone(), client.clone());
+        join_handlers.push(tokio::spawn(async move {
+            adm.admission().await
+        }));
+    }
+
+    let metrics_port = config.metrics_port;
     join_handlers.push(tokio::spawn(async move {
-        adm.admission().await
+        metrics::serve("0.0.0.0".to_string(), metrics_port).await
     }));
 
-    let mut ctx = Context::new(client.clone());
+    let resource_list: Vec<Box<dyn resources::resources::Resource>> = vec![
+        Box::new(resources::crpd::crpd::CrpdResource::new(client.clone())),
+        Box::new(resources::bgp_router::BgpRouterResource::new(client.clone())),
+        Box::new(resources::bgp_router_group::BgpRouterGroupResource::new(client.clone())),
+        Box::new(resources::routing_instance::RoutingInstanceResource::new(client.clone())),
+    ];
+    resources::resources::init_resources(resource_list).await?;
+
+    // the caches are filled on every replica so a new leader can start
+    // reconciling right away
+    let cache = Arc::new(Cache::start(client.clone(), &config));
+
+    let mut ctx = Context::new(client.clone(), config.clone(), cache.clone());
     ctx.address = Some(address.clone());
     ctx.name = Some(name.clone());
     ctx.namespace = Some(namespace.clone());
@@ -160,27 +258,140 @@ async fn main() -> anyhow::Result<()> {
 
     let ctx = Arc::new(ctx);
 
-    let resource_list: Vec<Box<dyn resources::resources::Resource>> = vec![
-        Box::new(resources::crpd::crpd::CrpdResource::new(client.clone())),
-        Box::new(resources::bgp_router::BgpRouterResource::new(client.clone())),
-        Box::new(resources::bgp_router_group::BgpRouterGroupResource::new(client.clone())),
-    ];
-    resources::resources::init_resources(resource_list).await?;
+    let controller_list = registry::controllers(ctx.clone());
+    if controller_list.is_empty(){
+        // nothing to lead, only wait for the shutdown so that the process
+        // still exits on SIGTERM
+        join_handlers.push(tokio::spawn(async move {
+            health::health().shutdown_requested().await;
+            Ok(())
+        }));
+    } else {
+        let leader_election = LeaderElection::new(client.clone(), config.lease_name.clone(), namespace.clone(), identity);
+        join_handlers.push(
+            tokio::spawn(async move {
+                leader_election.run(async move {
+                    cache.wait_until_ready().await?;
+                    controllers::init_controllers(controller_list).await
+                }).await
+            })
+        );
+    }
 
-    let controller_list: Vec<Box<dyn controllers::Controller>> = vec![
-        Box::new(CrpdController::new(ctx.clone())),
-        Box::new(BgpRouterController::new(ctx.clone())),
-        Box::new(BgpRouterGroupController::new(ctx.clone())),
-        Box::new(JunosConfigurationController::new(ctx.clone())),
-    ];
-    
-    join_handlers.push(
-        tokio::spawn(async move {
-            controllers::init_controllers(controller_list).await
-        })
-    );
+    tokio::spawn(async move {
+        match health::shutdown_signal().await{
+            Ok(_) => health::health().shutdown(),
+            Err(e) => warn!("failed to install signal handler: {}", e),
+        }
+    });
+
+    // the process exits as soon as any task ends: either the controllers
+    // stopped after a shutdown request or something died
+    let (res, _, _) = futures::future::select_all(join_handlers).await;
+    if health::health().is_shutting_down(){
+        info!("shutdown complete");
+        return Ok(());
+    }
+    match res{
+        Ok(Ok(())) => Err(anyhow::anyhow!("task stopped unexpectedly")),
+        Ok(Err(e)) => Err(e),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use std::sync::{Mutex, MutexGuard};
+
+    // ENV serializes the tests parsing Args, clap reads the CNM_ variables
+    // of the whole process
+    static ENV: Mutex<()> = Mutex::new(());
+
+    fn lock_env() -> MutexGuard<'static, ()>{
+        ENV.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    // EnvVar sets a variable while the test holds the ENV lock and removes
+    // it again when dropped, also when the test panics
+    struct EnvVar(&'static str);
+
+    impl EnvVar{
+        fn set(name: &'static str, value: &str) -> Self{
+            std::env::set_var(name, value);
+            EnvVar(name)
+        }
+    }
+
+    impl Drop for EnvVar{
+        fn drop(&mut self){
+            std::env::remove_var(self.0);
+        }
+    }
+
+    // config writes a config file only this test reads
+    fn config(name: &str, content: &str) -> String{
+        let path = std::env::temp_dir().join(format!("cnm-{}-{}.yaml", name, std::process::id()));
+        std::fs::write(&path, content).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn operator_config_applies_file_env_and_cli_in_order(){
+        let _env = lock_env();
+        let path = config("precedence", "metricsPort: 9100\njet:\n  port: 50100\nwatchNamespaces: [ns1]\n");
+
+        // the file overrides the defaults
+        let args = Args::try_parse_from(["cnm", "--config", &path]).unwrap();
+        let config = operator_config(&args).unwrap();
+        assert_eq!(config.metrics_port, 9100);
+        assert_eq!(config.jet.port, 50100);
+        assert_eq!(config.watch_namespaces, vec!["ns1"]);
+        assert_eq!(config.lease_name, "cnm");
+
+        // the environment overrides the file and the command line the environment
+        let metrics_port = EnvVar::set("CNM_METRICS_PORT", "9200");
+        let args = Args::try_parse_from(["cnm", "--config", &path, "--watch-namespaces", "ns2,ns3"]).unwrap();
+        let config = operator_config(&args).unwrap();
+        assert_eq!(config.metrics_port, 9200);
+        assert_eq!(config.watch_namespaces, vec!["ns2", "ns3"]);
+        let args = Args::try_parse_from(["cnm", "--config", &path, "--metrics-port", "9300"]).unwrap();
+        assert_eq!(operator_config(&args).unwrap().metrics_port, 9300);
+        drop(metrics_port);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn operator_config_disables_and_dedupes_controllers(){
+        let _env = lock_env();
+        let path = config("controllers", "controllers: [crpd, bgpRouter, crpd, admission]\n");
+
+        let args = Args::try_parse_from(["cnm", "--config", &path]).unwrap();
+        assert_eq!(operator_config(&args).unwrap().controllers,
+            vec![ControllerKind::Crpd, ControllerKind::BgpRouter, ControllerKind::Admission]);
+
+        // disabled controllers are removed from the configured ones as well
+        // as from the ones given on the command line
+        let args = Args::try_parse_from(["cnm", "--config", &path, "--disable-controllers", "admission"]).unwrap();
+        assert_eq!(operator_config(&args).unwrap().controllers, vec![ControllerKind::Crpd, ControllerKind::BgpRouter]);
+        let args = Args::try_parse_from(["cnm", "--controllers", "routingInstance,crpd,routingInstance", "--disable-controllers", "crpd"]).unwrap();
+        assert_eq!(operator_config(&args).unwrap().controllers, vec![ControllerKind::RoutingInstance]);
+
+        // without a config file every controller runs
+        let args = Args::try_parse_from(["cnm", "--disable-controllers", "junosConfiguration"]).unwrap();
+        let config = operator_config(&args).unwrap();
+        assert!(!config.is_enabled(ControllerKind::JunosConfiguration));
+        assert_eq!(config.controllers.len(), ControllerKind::all().len() - 1);
 
-    futures::future::join_all(join_handlers).await;
+        std::fs::remove_file(&path).unwrap();
+    }
 
-    Ok(())
+    #[test]
+    fn operator_config_rejects_unknown_controllers_and_missing_files(){
+        let _env = lock_env();
+        assert!(Args::try_parse_from(["cnm", "--controllers", "crpd,unknown"]).is_err());
+        let args = Args::try_parse_from(["cnm", "--config", "/nonexistent/cnm.yaml"]).unwrap();
+        assert!(operator_config(&args).is_err());
+    }
 }
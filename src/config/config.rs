@@ -0,0 +1,137 @@
+use serde::{Deserialize, Serialize};
+
+// OperatorConfig holds the settings of the operator. Every field has a
+// default, so a config file only needs to carry the values which differ.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase", default)]
+pub struct OperatorConfig{
+    // namespace of the operator's own objects: the CA secret, the lease and the crpd RBAC
+    pub namespace: String,
+    pub ca_secret: String,
+    pub lease_name: String,
+    pub metrics_port: u16,
+    // namespaces the controllers watch, all namespaces when empty
+    pub watch_namespaces: Vec<String>,
+    // controllers run by this deployment. Deployments running different
+    // controllers need their own lease name.
+    pub controllers: Vec<ControllerKind>,
+    pub webhook: WebhookConfig,
+    pub jet: JetConfig,
+    pub requeue: RequeueConfig,
+}
+
+impl Default for OperatorConfig{
+    fn default() -> Self{
+        Self{
+            namespace: "default".to_string(),
+            ca_secret: "cnm-ca".to_string(),
+            lease_name: "cnm".to_string(),
+            metrics_port: 9090,
+            watch_namespaces: Vec::new(),
+            controllers: ControllerKind::all(),
+            webhook: WebhookConfig::default(),
+            jet: JetConfig::default(),
+            requeue: RequeueConfig::default(),
+        }
+    }
+}
+
+// ControllerKind names a controller which can be enabled or disabled
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+#[serde(rename_all = "camelCase")]
+#[value(rename_all = "camelCase")]
+pub enum ControllerKind{
+    Crpd,
+    BgpRouter,
+    BgpRouterGroup,
+    JunosConfiguration,
+    RoutingInstance,
+    Admission,
+}
+
+impl ControllerKind{
+    pub fn all() -> Vec<ControllerKind>{
+        vec![
+            ControllerKind::Crpd,
+            ControllerKind::BgpRouter,
+            ControllerKind::BgpRouterGroup,
+            ControllerKind::JunosConfiguration,
+            ControllerKind::RoutingInstance,
+            ControllerKind::Admission,
+        ]
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase", default)]
+pub struct WebhookConfig{
+    pub port: u16,
+    // name of the MutatingWebhookConfiguration
+    pub name: String,
+    // name of the Service in front of the webhook in the operator's
+    // namespace, the serving certificate is issued for its dns name
+    pub dns: String,
+}
+
+impl Default for WebhookConfig{
+    fn default() -> Self{
+        Self{
+            port: 8443,
+            name: "cnm-mutating-webhook-config".to_string(),
+            dns: "cnm-admission-controller".to_string(),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase", default)]
+pub struct JetConfig{
+    pub port: u16,
+    // interval at which the operational state of the crpd instances is read
+    pub status_interval_seconds: u64,
+    // bounds connecting to a crpd instance and each call to it
+    pub timeout_seconds: u64,
+}
+
+impl Default for JetConfig{
+    fn default() -> Self{
+        Self{
+            port: 50052,
+            status_interval_seconds: 60,
+            timeout_seconds: 10,
+        }
+    }
+}
+
+// RequeueConfig bounds the exponential backoff of failed reconciliations
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase", default)]
+pub struct RequeueConfig{
+    pub base_seconds: u64,
+    pub max_seconds: u64,
+}
+
+impl Default for RequeueConfig{
+    fn default() -> Self{
+        Self{
+            base_seconds: 5,
+            max_seconds: 5 * 60,
+        }
+    }
+}
+
+impl OperatorConfig{
+    pub fn is_enabled(&self, controller: ControllerKind) -> bool{
+        self.controllers.contains(&controller)
+    }
+    pub fn from_file(path: &str) -> anyhow::Result<Self>{
+        let content = match std::fs::read_to_string(path){
+            Ok(content) => content,
+            Err(e) => return Err(anyhow::anyhow!("failed to read config {}: {}", path, e)),
+        };
+        match serde_yaml::from_str(&content){
+            Ok(config) => Ok(config),
+            Err(e) => Err(anyhow::anyhow!("failed to parse config {}: {}", path, e)),
+        }
+    }
+}
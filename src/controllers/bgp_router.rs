@@ -1,12 +1,14 @@
 use crate::controllers::controllers::{Controller, Context, ReconcileError};
-use crate::resources::bgp_router::{BgpRouter, BgpRouterStatus, BgpPeeringReference, BgpSessionAttributes};
+use crate::metrics::metrics;
+use crate::health::health;
+use kube::runtime::events::EventType;
+use crate::resources::bgp_router::{BgpRouter, BgpRouterStatus, BgpPeeringReference, BgpSessionAttributes, IpFamily};
 use crate::resources::bgp_router_group::BgpRouterGroup;
-use crate::controllers::controllers;
+use crate::controllers::{controllers, cache};
 use async_trait::async_trait;
 use futures::StreamExt;
 use kube::{
     Resource,
-    api::Api,
     client::Client,
     runtime::{
         controller::{Action, Controller as runtime_controller},
@@ -15,82 +17,152 @@ use kube::{
     },
 };
 use std::sync::Arc;
-use tokio::time::Duration;
 use tracing::*;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1 as meta_v1;
 
 pub struct BgpRouterController{
     context: Arc<Context>,
-    resource: Api<BgpRouter>,
 }
 
 impl BgpRouterController{
     pub fn new(context: Arc<Context>) -> Self{
-        let resource = Api::all(context.client.clone());
         let context = context.clone();
-        BgpRouterController{context, resource}
+        BgpRouterController{context}
     }
     async fn reconcile(g: Arc<BgpRouter>, ctx: Arc<Context>) ->  Result<Action, ReconcileError> {
         info!("reconciling BgpRouter {:?}", g.meta().name.as_ref().unwrap().clone());
-        match controllers::get::<BgpRouter>(
-            g.meta().namespace.as_ref().unwrap().clone(),
-            g.meta().name.as_ref().unwrap().clone(),
-            ctx.client.clone())
-            .await{
-            Ok(res) => {
-                match res{
-                    Some((mut bgp_router, _bgp_router_api)) => {
-                        if let Some(labels) = &bgp_router.meta().labels{
-                            if let Some(bgp_router_group_name) = labels.get("cnm.juniper.net/bgpRouterGroup"){
-                                if let Ok(res) = controllers::get::<BgpRouterGroup>(bgp_router.meta().namespace.as_ref().unwrap().clone(), bgp_router_group_name.to_string(), ctx.client.clone()).await{
-                                    if let Some((bgp_router_group, _)) = res{
-                                        if let Some(bgp_router_group_status) = bgp_router_group.status{
-                                            let mut bgp_peering_references = Vec::new();
-                                            for bgp_router_reference in &bgp_router_group_status.bgp_router_references{
-                                                if bgp_router_reference.bgp_router_reference.name.as_ref().unwrap().clone() != bgp_router.meta().name.as_ref().unwrap().clone(){
-                                                    let bgp_peering_reference = BgpPeeringReference{
-                                                        peer_reference: bgp_router_reference.bgp_router_reference.clone(),
-                                                        bgp_router_group: Some(bgp_router_group_name.to_string()),
-                                                        session_attributes: BgpSessionAttributes{
-                                                            local_address: bgp_router.spec.address.as_ref().unwrap().clone(),
-                                                            peer_address: bgp_router_reference.local_address.clone(),
-                                                            local_as: bgp_router.spec.autonomous_system_number,
-                                                            peer_as: bgp_router.spec.autonomous_system_number,
-                                                            address_families: bgp_router.spec.address_families.clone(),
-                                                        }
-                                                    };
-                                                    bgp_peering_references.push(bgp_peering_reference);
-                                                }
-                                            }
-
-                                            if bgp_router.status.is_some(){
-                                                bgp_router.status.as_mut().unwrap().bgp_peer_references = Some(bgp_peering_references);
-                                            } else {
-                                                bgp_router.status = Some(BgpRouterStatus{
-                                                    bgp_peer_references: Some(bgp_peering_references),
-                                                });
-                                            }  
-
-                                            if let Err(e) = controllers::update_status::<BgpRouter>(bgp_router.clone(), ctx.client.clone()).await{
-                                                return Err(e);
-                                            }
+        let mut bgp_router = g.as_ref().clone();
+        if bgp_router.meta().deletion_timestamp.is_some(){
+            return BgpRouterController::cleanup(bgp_router, ctx).await;
+        }
+        controllers::add_finalizer(&bgp_router, controllers::CLEANUP_FINALIZER, ctx.client.clone()).await?;
+        if bgp_router.spec.address.is_none(){
+            return Err(ReconcileError::InvalidSpec(anyhow::anyhow!("BgpRouter has no address")));
+        }
+        if let Some(labels) = &bgp_router.meta().labels{
+            if let Some(bgp_router_group_name) = labels.get("cnm.juniper.net/bgpRouterGroup"){
+                if let Some(bgp_router_group) = cache::get::<BgpRouterGroup>(&ctx.cache.bgp_router_groups, bgp_router.meta().namespace.as_ref().unwrap(), bgp_router_group_name){
+                    if let Some(bgp_router_group_status) = bgp_router_group.status{
+                        let mut bgp_peering_references = Vec::new();
+                        let local_addresses = bgp_router.spec.addresses.clone()
+                            .unwrap_or_else(|| vec![bgp_router.spec.address.as_ref().unwrap().clone()]);
+                        for bgp_router_reference in &bgp_router_group_status.bgp_router_references{
+                            if bgp_router_reference.bgp_router_reference.name.as_ref().unwrap().clone() != bgp_router.meta().name.as_ref().unwrap().clone(){
+                                let peer_addresses = if bgp_router_reference.local_addresses.is_empty(){
+                                    vec![bgp_router_reference.local_address.clone()]
+                                } else {
+                                    bgp_router_reference.local_addresses.clone()
+                                };
+                                // dual-stack routers peer once per family
+                                for (local_address, peer_address) in sessions(&local_addresses, &peer_addresses){
+                                    let bgp_peering_reference = BgpPeeringReference{
+                                        peer_reference: bgp_router_reference.bgp_router_reference.clone(),
+                                        bgp_router_group: Some(bgp_router_group_name.to_string()),
+                                        session_attributes: BgpSessionAttributes{
+                                            local_address,
+                                            peer_address,
+                                            local_as: bgp_router.spec.autonomous_system_number,
+                                            peer_as: bgp_router.spec.autonomous_system_number,
+                                            address_families: bgp_router.spec.address_families.clone(),
                                         }
-                                    }
+                                    };
+                                    bgp_peering_references.push(bgp_peering_reference);
                                 }
                             }
                         }
-                    },
-                    None => {}
+
+                        let current_peers = match &bgp_router.status{
+                            Some(status) => status.bgp_peer_references.clone().unwrap_or_default(),
+                            None => Vec::new(),
+                        };
+                        for bgp_peering_reference in &bgp_peering_references{
+                            if !current_peers.iter().any(|peer| peer.peer_reference.name == bgp_peering_reference.peer_reference.name){
+                                controllers::publish_event(&bgp_router, &ctx, EventType::Normal, "PeerAdded", "AddPeer",
+                                    format!("added peer {}", bgp_peering_reference.peer_reference.name.as_ref().unwrap())).await;
+                            }
+                        }
+                        for peer in &current_peers{
+                            if !bgp_peering_references.iter().any(|bgp_peering_reference| bgp_peering_reference.peer_reference.name == peer.peer_reference.name){
+                                controllers::publish_event(&bgp_router, &ctx, EventType::Normal, "PeerRemoved", "RemovePeer",
+                                    format!("removed peer {}", peer.peer_reference.name.as_ref().unwrap())).await;
+                            }
+                        }
+
+                        let generation = bgp_router.meta().generation;
+                        let peers = bgp_peering_references.len();
+                        let status = bgp_router.status.get_or_insert_with(BgpRouterStatus::default);
+                        status.bgp_peer_references = Some(bgp_peering_references);
+                        status.observed_generation = generation;
+                        let mut conditions = status.conditions.clone().unwrap_or_default();
+                        controllers::set_condition(&mut conditions, controllers::CONDITION_READY, true,
+                            "PeersResolved", format!("{} peers", peers), generation);
+                        controllers::set_condition(&mut conditions, controllers::CONDITION_PROGRESSING, false,
+                            "PeersResolved", "".to_string(), generation);
+                        controllers::set_condition(&mut conditions, controllers::CONDITION_DEGRADED, false,
+                            "ReconcileSucceeded", "".to_string(), generation);
+                        status.conditions = Some(conditions);
+
+                        controllers::update_status::<BgpRouter>(bgp_router.clone(), ctx.client.clone()).await?;
+                    }
                 }
             }
-            Err(e) => {
-               return Err(e)
+        }
+        Ok(Action::await_change())
+    }
+    // cleanup removes the deleted BgpRouter from the status of its peers.
+    // The JunosConfigurationController finds the cRPDs to remove the neighbor
+    // from in that status, so it has to release its finalizer first.
+    // The JUNOS_FINALIZER is only ever released by that controller, which may
+    // run in another deployment.
+    async fn cleanup(bgp_router: BgpRouter, ctx: Arc<Context>) -> Result<Action, ReconcileError> {
+        if !controllers::has_finalizer(&bgp_router, controllers::CLEANUP_FINALIZER){
+            return Ok(Action::await_change())
+        }
+        if controllers::has_finalizer(&bgp_router, controllers::JUNOS_FINALIZER){
+            return Ok(Action::await_change())
+        }
+        info!("cleaning up BgpRouter {:?}", bgp_router.meta().name.as_ref().unwrap().clone());
+        let bgp_router_name = bgp_router.meta().name.as_ref().unwrap().clone();
+        let namespace = bgp_router.meta().namespace.as_ref().unwrap().clone();
+        if let Some(status) = &bgp_router.status{
+            if let Some(bgp_peer_references) = &status.bgp_peer_references{
+                for bgp_peer_reference in bgp_peer_references{
+                    let peer_name = bgp_peer_reference.peer_reference.name.as_ref().unwrap().clone();
+                    if let Some(mut peer) = cache::get::<BgpRouter>(&ctx.cache.bgp_routers, &namespace, &peer_name){
+                        if let Some(peer_status) = peer.status.as_mut(){
+                            if let Some(peer_references) = peer_status.bgp_peer_references.as_mut(){
+                                peer_references.retain(|peer_reference| {
+                                    peer_reference.peer_reference.name.as_ref() != Some(&bgp_router_name)
+                                });
+                            }
+                        }
+                        controllers::publish_event(&peer, &ctx, EventType::Normal, "PeerRemoved", "RemovePeer",
+                            format!("removed deleted peer {}", bgp_router_name)).await;
+                        controllers::update_status::<BgpRouter>(peer, ctx.client.clone()).await?;
+                    }
+                }
             }
         }
+        controllers::remove_finalizer(&bgp_router, controllers::CLEANUP_FINALIZER, ctx.client.clone()).await?;
         Ok(Action::await_change())
     }
     fn error_policy(g: Arc<BgpRouter>, error: &ReconcileError, ctx: Arc<Context>) -> Action {
         warn!("reconcile failed: {:?}", error);
-        Action::requeue(Duration::from_secs(5 * 60))
+        metrics::metrics().reconcile_failure("bgp_router");
+        let action = controllers::error_action("bgp_router", g.as_ref(), error, &ctx);
+        let note = error.to_string();
+        let reason = error.reason();
+        tokio::spawn(async move {
+            controllers::publish_event(g.as_ref(), &ctx, EventType::Warning, "ReconcileFailed", "Reconcile", note.clone()).await;
+            controllers::set_degraded(g.as_ref(), reason, note, &ctx).await;
+        });
+        action
+    }
+}
+
+impl controllers::Conditions for BgpRouter{
+    fn conditions_mut(&mut self) -> &mut Vec<meta_v1::Condition>{
+        self.status.get_or_insert_with(Default::default).conditions.get_or_insert_with(Vec::new)
     }
 }
 
@@ -98,15 +170,27 @@ impl BgpRouterController{
 impl Controller for BgpRouterController{
     async fn run(&self) -> anyhow::Result<()>{
         let reconcile = |g: Arc<BgpRouter>, ctx: Arc<Context>| {
-            async move { BgpRouterController::reconcile(g, ctx).await }
+            async move {
+                let _measurer = metrics::metrics().reconcile_start("bgp_router");
+                let key = controllers::backoff_key("bgp_router", g.as_ref());
+                // a failed object is only reconciled again once its backoff
+                // passed or its spec changed
+                if let Some(pending) = ctx.backoff.pending(&key, g.meta().generation){
+                    return Ok(Action::requeue(pending));
+                }
+                let res = BgpRouterController::reconcile(g, ctx.clone()).await;
+                if res.is_ok(){
+                    ctx.backoff.reset(&key);
+                }
+                res
+            }
         };
         let error_policy = |g: Arc<BgpRouter>, error: &ReconcileError, ctx: Arc<Context>| {
             BgpRouterController::error_policy(g, error, ctx)
         };
-        runtime_controller::new(self.resource.clone(), Config::default())
-            .watches(
-                Api::<BgpRouterGroup>::all(self.context.client.clone()),
-                Config::default(),
+        let controller = controllers::new_controller::<BgpRouter>(&self.context, Config::default())
+            .watches_stream(
+                controllers::watch_stream::<BgpRouterGroup>(&self.context, Config::default()),
                 |bgp_router_group| {
                     info!("crpd event in bgp_router_group controller:");
                     let mut object_ref_list = Vec::new();
@@ -120,7 +204,10 @@ impl Controller for BgpRouterController{
                     }
                     object_ref_list.into_iter()
                 }
-            )
+            );
+        health::track("bgp_router", controller.store());
+        controller
+            .graceful_shutdown_on(health::health().shutdown_requested())
             .run(reconcile, error_policy, self.context.clone())
             .for_each(|res| async move {
                 match res {
@@ -129,10 +216,191 @@ impl Controller for BgpRouterController{
                 }
             })
             .await;
+        health::health().stopped("bgp_router");
         Ok(())
     }
 }
 
+// sessions pairs the local and peer addresses of the same family
+fn sessions(local_addresses: &[String], peer_addresses: &[String]) -> Vec<(String, String)>{
+    let mut sessions = Vec::new();
+    for local_address in local_addresses{
+        let family = IpFamily::of(local_address);
+        if let Some(peer_address) = peer_addresses.iter().find(|peer_address| IpFamily::of(peer_address) == family){
+            sessions.push((local_address.clone(), peer_address.clone()));
+        }
+    }
+    sessions
+}
+
 fn test(obj: &BgpRouterGroup) -> Option<u64>{
     Some(0)
 }
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::controllers::test_utils::{self, ApiServer};
+    use crate::resources::bgp_router_group::{BgpRouterGroupStatus, BgpRouterReference};
+    use crate::config::config::{OperatorConfig, ControllerKind};
+    use k8s_openapi::api::core::v1 as core_v1;
+    use http::Method;
+
+    fn bgp_router_group(members: Vec<(&str, &str)>) -> BgpRouterGroup{
+        let mut bgp_router_group = test_utils::bgp_router_group("group1", true);
+        bgp_router_group.status = Some(BgpRouterGroupStatus{
+            bgp_router_references: members.into_iter().map(|(name, address)| BgpRouterReference{
+                bgp_router_reference: core_v1::ObjectReference{
+                    name: Some(name.to_string()),
+                    ..Default::default()
+                },
+                local_address: address.to_string(),
+                local_addresses: Vec::new(),
+            }).collect(),
+            ..Default::default()
+        });
+        bgp_router_group
+    }
+
+    fn peering(name: &str) -> BgpPeeringReference{
+        BgpPeeringReference{
+            peer_reference: core_v1::ObjectReference{
+                name: Some(name.to_string()),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn reconcile_peers_with_group_members(){
+        let server = ApiServer::new();
+        server.add(&bgp_router_group(vec![("router1", "10.0.0.1"), ("router2", "10.0.0.2")]));
+        let bgp_router = test_utils::bgp_router("router1", Some("10.0.0.1"), Some("group1"));
+        server.add(&bgp_router);
+
+        let action = BgpRouterController::reconcile(Arc::new(bgp_router), server.context()).await.unwrap();
+        assert_eq!(action, Action::await_change());
+
+        let bgp_router = server.get::<BgpRouter>("router1").unwrap();
+        assert!(controllers::has_finalizer(&bgp_router, controllers::CLEANUP_FINALIZER));
+        let status = bgp_router.status.unwrap();
+        let peers = status.bgp_peer_references.unwrap();
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].peer_reference.name, Some("router2".to_string()));
+        assert_eq!(peers[0].session_attributes.local_address, "10.0.0.1");
+        assert_eq!(peers[0].session_attributes.peer_address, "10.0.0.2");
+        assert_eq!(test_utils::condition_status(&status.conditions, controllers::CONDITION_READY), Some("True"));
+        assert_eq!(server.event_reasons(), vec!["PeerAdded"]);
+    }
+
+    #[tokio::test]
+    async fn reconcile_peers_dual_stack_group_members_per_family(){
+        let server = ApiServer::new();
+        let mut bgp_router_group = bgp_router_group(vec![("router1", "10.0.0.1"), ("router2", "10.0.0.2")]);
+        bgp_router_group.status.as_mut().unwrap().bgp_router_references[1].local_addresses = vec!["10.0.0.2".to_string(), "fd00::2".to_string()];
+        server.add(&bgp_router_group);
+        let mut bgp_router = test_utils::bgp_router("router1", Some("fd00::1"), Some("group1"));
+        bgp_router.spec.addresses = Some(vec!["fd00::1".to_string(), "10.0.0.1".to_string()]);
+        server.add(&bgp_router);
+
+        BgpRouterController::reconcile(Arc::new(bgp_router), server.context()).await.unwrap();
+
+        let peers = server.get::<BgpRouter>("router1").unwrap().status.unwrap().bgp_peer_references.unwrap();
+        let sessions: Vec<(&str, &str)> = peers.iter()
+            .map(|peer| (peer.session_attributes.local_address.as_str(), peer.session_attributes.peer_address.as_str()))
+            .collect();
+        assert_eq!(sessions, vec![("fd00::1", "fd00::2"), ("10.0.0.1", "10.0.0.2")]);
+    }
+
+    #[tokio::test]
+    async fn reconcile_rejects_bgp_router_without_address(){
+        let server = ApiServer::new();
+        let bgp_router = test_utils::bgp_router("router1", None, Some("group1"));
+        server.add(&bgp_router);
+
+        let res = BgpRouterController::reconcile(Arc::new(bgp_router), server.context()).await;
+        assert!(matches!(res, Err(ReconcileError::InvalidSpec(_))));
+    }
+
+    #[tokio::test]
+    async fn reconcile_fails_when_status_update_fails(){
+        let server = ApiServer::new();
+        server.add(&bgp_router_group(vec![("router1", "10.0.0.1"), ("router2", "10.0.0.2")]));
+        let bgp_router = test_utils::bgp_router("router1", Some("10.0.0.1"), Some("group1"));
+        server.add(&bgp_router);
+        server.fail(Method::PUT, "/apis/cnm.juniper.net/v1/namespaces/default/bgprouters/router1/status");
+
+        let res = BgpRouterController::reconcile(Arc::new(bgp_router), server.context()).await;
+        assert!(matches!(res, Err(ReconcileError::TransientApi(_))));
+    }
+
+    #[tokio::test]
+    async fn reconcile_removes_deleted_bgp_router_from_peers(){
+        let server = ApiServer::new();
+        let mut bgp_router = test_utils::bgp_router("router1", Some("10.0.0.1"), Some("group1"));
+        bgp_router.metadata.deletion_timestamp = Some(meta_v1::Time(k8s_openapi::chrono::Utc::now()));
+        bgp_router.metadata.finalizers = Some(vec![controllers::CLEANUP_FINALIZER.to_string()]);
+        bgp_router.status = Some(BgpRouterStatus{
+            bgp_peer_references: Some(vec![peering("router2")]),
+            ..Default::default()
+        });
+        server.add(&bgp_router);
+        let mut peer = test_utils::bgp_router("router2", Some("10.0.0.2"), Some("group1"));
+        peer.status = Some(BgpRouterStatus{
+            bgp_peer_references: Some(vec![peering("router1")]),
+            ..Default::default()
+        });
+        server.add(&peer);
+
+        BgpRouterController::reconcile(Arc::new(bgp_router), server.context()).await.unwrap();
+
+        let peer = server.get::<BgpRouter>("router2").unwrap();
+        assert!(peer.status.unwrap().bgp_peer_references.unwrap().is_empty());
+        assert!(!controllers::has_finalizer(&server.get::<BgpRouter>("router1").unwrap(), controllers::CLEANUP_FINALIZER));
+        assert_eq!(server.event_reasons(), vec!["PeerRemoved"]);
+    }
+
+    #[tokio::test]
+    async fn reconcile_waits_for_junos_cleanup(){
+        let server = ApiServer::new();
+        let mut bgp_router = test_utils::bgp_router("router1", Some("10.0.0.1"), Some("group1"));
+        bgp_router.metadata.deletion_timestamp = Some(meta_v1::Time(k8s_openapi::chrono::Utc::now()));
+        bgp_router.metadata.finalizers = Some(vec![controllers::CLEANUP_FINALIZER.to_string(), controllers::JUNOS_FINALIZER.to_string()]);
+        bgp_router.status = Some(BgpRouterStatus{
+            bgp_peer_references: Some(vec![peering("router2")]),
+            ..Default::default()
+        });
+        server.add(&bgp_router);
+        let mut peer = test_utils::bgp_router("router2", Some("10.0.0.2"), Some("group1"));
+        peer.status = Some(BgpRouterStatus{
+            bgp_peer_references: Some(vec![peering("router1")]),
+            ..Default::default()
+        });
+        server.add(&peer);
+
+        BgpRouterController::reconcile(Arc::new(bgp_router), server.context()).await.unwrap();
+
+        // the peers keep the reference the JunosConfigurationController needs
+        let peer = server.get::<BgpRouter>("router2").unwrap();
+        assert_eq!(peer.status.unwrap().bgp_peer_references.unwrap().len(), 1);
+        assert!(controllers::has_finalizer(&server.get::<BgpRouter>("router1").unwrap(), controllers::CLEANUP_FINALIZER));
+    }
+
+    #[tokio::test]
+    async fn reconcile_keeps_junos_finalizer_when_junos_controller_runs_elsewhere(){
+        let server = ApiServer::new();
+        let mut bgp_router = test_utils::bgp_router("router1", Some("10.0.0.1"), Some("group1"));
+        bgp_router.metadata.deletion_timestamp = Some(meta_v1::Time(k8s_openapi::chrono::Utc::now()));
+        bgp_router.metadata.finalizers = Some(vec![controllers::CLEANUP_FINALIZER.to_string(), controllers::JUNOS_FINALIZER.to_string()]);
+        server.add(&bgp_router);
+        let mut config = OperatorConfig::default();
+        config.controllers.retain(|kind| *kind != ControllerKind::JunosConfiguration);
+
+        BgpRouterController::reconcile(Arc::new(bgp_router), server.context_with_config(config)).await.unwrap();
+
+        let bgp_router = server.get::<BgpRouter>("router1").unwrap();
+        assert!(controllers::has_finalizer(&bgp_router, controllers::JUNOS_FINALIZER));
+        assert!(controllers::has_finalizer(&bgp_router, controllers::CLEANUP_FINALIZER));
+    }
+}
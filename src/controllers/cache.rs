@@ -0,0 +1,248 @@
+use crate::controllers::controllers::{self, ReconcileError};
+use crate::config::config::{OperatorConfig, ControllerKind};
+use crate::health::health;
+use crate::resources::crpd::crpd::Crpd;
+use crate::resources::bgp_router::BgpRouter;
+use crate::resources::bgp_router_group::BgpRouterGroup;
+use futures::StreamExt;
+use futures::stream::BoxStream;
+use k8s_openapi::NamespaceResourceScope;
+use k8s_openapi::api::apps::v1 as apps_v1;
+use k8s_openapi::api::core::v1 as core_v1;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1 as meta_v1;
+use kube::{Api, Client, Resource};
+use kube::runtime::{reflector, watcher, WatchStreamExt};
+use kube::runtime::reflector::{ObjectRef, Store};
+use serde::de::DeserializeOwned;
+use std::fmt::Debug;
+use tokio::sync::broadcast;
+use tracing::*;
+
+// buffered changes per controller stream, a controller falling further
+// behind is handed the whole store again
+const APPLIED_BUFFER: usize = 1024;
+
+// Cache holds the reflector stores shared by all reconcilers. Reconcilers
+// read related objects from here and only write to the api. The kinds the
+// CrpdController is driven by pass their changes on to it, see
+// applied_objects, so they are only watched once.
+#[derive(Clone)]
+pub struct Cache{
+    pub crpds: Store<Crpd>,
+    pub bgp_routers: Store<BgpRouter>,
+    pub bgp_router_groups: Store<BgpRouterGroup>,
+    // only the pods and workloads of crpds, labeled app=crpd
+    pub pods: Store<core_v1::Pod>,
+    pub stateful_sets: Store<apps_v1::StatefulSet>,
+    pub daemon_sets: Store<apps_v1::DaemonSet>,
+    pub applied_crpds: broadcast::Sender<Crpd>,
+    pub applied_stateful_sets: broadcast::Sender<apps_v1::StatefulSet>,
+    pub applied_daemon_sets: broadcast::Sender<apps_v1::DaemonSet>,
+}
+
+impl Cache{
+    // start runs one reflector per kind read by an enabled controller in the
+    // background, scoped to the namespaces the operator watches. The stores
+    // of the other kinds stay empty.
+    pub fn start(client: Client, config: &OperatorConfig) -> Self{
+        let crpd = || watcher::Config::default().labels("app=crpd");
+        let used_by = |controllers: &[ControllerKind]| controllers.iter().any(|controller| config.is_enabled(*controller));
+        let applied_crpds = broadcast::channel(APPLIED_BUFFER).0;
+        let applied_stateful_sets = broadcast::channel(APPLIED_BUFFER).0;
+        let applied_daemon_sets = broadcast::channel(APPLIED_BUFFER).0;
+        Self{
+            crpds: match used_by(&[ControllerKind::Crpd, ControllerKind::BgpRouterGroup, ControllerKind::RoutingInstance]){
+                true => reflect(client.clone(), config, watcher::Config::default(), Some(applied_crpds.clone()), "crpd_cache"),
+                false => empty(),
+            },
+            bgp_routers: match used_by(&[ControllerKind::Crpd, ControllerKind::BgpRouter, ControllerKind::JunosConfiguration]){
+                true => reflect(client.clone(), config, watcher::Config::default(), None, "bgp_router_cache"),
+                false => empty(),
+            },
+            bgp_router_groups: match used_by(&[ControllerKind::BgpRouter, ControllerKind::BgpRouterGroup]){
+                true => reflect(client.clone(), config, watcher::Config::default(), None, "bgp_router_group_cache"),
+                false => empty(),
+            },
+            pods: match used_by(&[ControllerKind::Crpd]){
+                true => reflect(client.clone(), config, crpd(), None, "pod_cache"),
+                false => empty(),
+            },
+            stateful_sets: match used_by(&[ControllerKind::Crpd]){
+                true => reflect(client.clone(), config, crpd(), Some(applied_stateful_sets.clone()), "stateful_set_cache"),
+                false => empty(),
+            },
+            daemon_sets: match used_by(&[ControllerKind::Crpd]){
+                true => reflect(client, config, crpd(), Some(applied_daemon_sets.clone()), "daemon_set_cache"),
+                false => empty(),
+            },
+            applied_crpds,
+            applied_stateful_sets,
+            applied_daemon_sets,
+        }
+    }
+    pub async fn wait_until_ready(&self) -> anyhow::Result<()>{
+        self.crpds.wait_until_ready().await?;
+        self.bgp_routers.wait_until_ready().await?;
+        self.bgp_router_groups.wait_until_ready().await?;
+        self.pods.wait_until_ready().await?;
+        self.stateful_sets.wait_until_ready().await?;
+        self.daemon_sets.wait_until_ready().await?;
+        Ok(())
+    }
+}
+
+// reflect fills a store with the objects of K in the namespaces the
+// operator watches and passes the applied objects on to applied
+fn reflect<K>(client: Client, config: &OperatorConfig, watcher_config: watcher::Config, applied: Option<broadcast::Sender<K>>, name: &str) -> Store<K>
+where
+K: Resource<DynamicType = (), Scope = NamespaceResourceScope> + Clone + DeserializeOwned + Debug + Send + Sync + 'static,
+{
+    let (reader, writer) = reflector::store();
+    let events = if config.watch_namespaces.is_empty(){
+        watcher::watcher(Api::<K>::all(client), watcher_config).boxed()
+    } else {
+        controllers::namespaced_events(client, config.watch_namespaces.clone(), watcher_config, reader.clone())
+    };
+    // a store wakes only one task waiting for it to become ready, which is
+    // Cache::wait_until_ready, so the sync is reported from here
+    health::health().register(name);
+    let name = name.to_string();
+    tokio::spawn(async move {
+        let mut events = reflector::reflector(writer, events).default_backoff().boxed();
+        let mut synced = false;
+        while let Some(event) = events.next().await{
+            match event{
+                Ok(event) => {
+                    // the first restart carries every watched namespace
+                    if !synced && matches!(event, watcher::Event::Restarted(_)){
+                        health::health().synced(&name);
+                        synced = true;
+                    }
+                    if let Some(applied) = &applied{
+                        // sending only fails without a controller listening
+                        for object in event.into_iter_applied(){
+                            let _ = applied.send(object);
+                        }
+                    }
+                },
+                Err(e) => warn!("{} watch failed: {:?}", name, e),
+            }
+        }
+    });
+    reader
+}
+
+// empty returns a store which is ready without ever being filled
+fn empty<K>() -> Store<K>
+where
+K: Resource<DynamicType = ()> + Clone,
+{
+    let (reader, mut writer) = reflector::store();
+    writer.apply_watcher_event(&watcher::Event::Restarted(Vec::new()));
+    reader
+}
+
+// applied_objects streams the objects of the store followed by the objects
+// the reflector applies to it, for use as a controller stream
+pub fn applied_objects<K>(store: &Store<K>, applied: &broadcast::Sender<K>) -> BoxStream<'static, Result<K, watcher::Error>>
+where
+K: Resource<DynamicType = ()> + Clone + Send + Sync + 'static,
+{
+    let receiver = applied.subscribe();
+    let objects = snapshot(store);
+    let store = store.clone();
+    let updates = futures::stream::unfold((receiver, store), |(mut receiver, store)| async move {
+        match receiver.recv().await{
+            Ok(object) => Some((vec![object], (receiver, store))),
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                warn!("controller stream fell {} objects behind, resyncing", skipped);
+                let objects = snapshot(&store);
+                Some((objects, (receiver, store)))
+            },
+            Err(broadcast::error::RecvError::Closed) => None,
+        }
+    });
+    futures::stream::iter(objects)
+        .chain(updates.flat_map(futures::stream::iter))
+        .map(Ok)
+        .boxed()
+}
+
+fn snapshot<K>(store: &Store<K>) -> Vec<K>
+where
+K: Resource<DynamicType = ()> + Clone,
+{
+    store.state().iter().map(|object| object.as_ref().clone()).collect()
+}
+
+// get returns the cached object with the given name
+pub fn get<K>(store: &Store<K>, namespace: &str, name: &str) -> Option<K>
+where
+K: Resource<DynamicType = ()> + Clone,
+{
+    store.get(&ObjectRef::new(name).within(namespace)).map(|object| object.as_ref().clone())
+}
+
+// list returns the cached objects of the namespace matching the selector
+pub fn list<K>(store: &Store<K>, namespace: &str, selector: Option<&meta_v1::LabelSelector>) -> Result<Vec<K>, ReconcileError>
+where
+K: Resource<DynamicType = ()> + Clone,
+{
+    let mut objects = Vec::new();
+    for object in store.state(){
+        if object.meta().namespace.as_deref() != Some(namespace){
+            continue;
+        }
+        if let Some(selector) = selector{
+            if !controllers::selector_matches(selector, object.meta().labels.as_ref())?{
+                continue;
+            }
+        }
+        objects.push(object.as_ref().clone());
+    }
+    Ok(objects)
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::controllers::test_utils;
+
+    #[tokio::test]
+    async fn applied_objects_streams_store_then_applied_objects(){
+        let (reader, mut writer) = reflector::store();
+        writer.apply_watcher_event(&watcher::Event::Restarted(vec![test_utils::crpd("crpd1", 1)]));
+        let (applied, _) = broadcast::channel(2);
+
+        let mut objects = applied_objects(&reader, &applied);
+        applied.send(test_utils::crpd("crpd2", 1)).unwrap();
+        let name = |object: Option<Result<Crpd, watcher::Error>>| object.unwrap().unwrap().metadata.name.unwrap();
+        assert_eq!(name(objects.next().await), "crpd1");
+        assert_eq!(name(objects.next().await), "crpd2");
+
+        // a stream falling behind is handed the store again
+        writer.apply_watcher_event(&watcher::Event::Applied(test_utils::crpd("crpd3", 1)));
+        for replicas in 0..3{
+            applied.send(test_utils::crpd("crpd3", replicas)).unwrap();
+        }
+        let mut resynced: Vec<String> = vec![name(objects.next().await), name(objects.next().await)];
+        resynced.sort();
+        assert_eq!(resynced, vec!["crpd1", "crpd3"]);
+    }
+
+    #[tokio::test]
+    async fn start_watches_only_kinds_of_enabled_controllers(){
+        let server = test_utils::ApiServer::new();
+        let config = OperatorConfig{
+            controllers: vec![ControllerKind::RoutingInstance, ControllerKind::Admission],
+            watch_namespaces: vec![test_utils::NAMESPACE.to_string()],
+            ..Default::default()
+        };
+        let cache = Cache::start(server.client(), &config);
+        tokio::time::timeout(std::time::Duration::from_secs(5), cache.wait_until_ready()).await.unwrap().unwrap();
+
+        let requests = server.requests(http::Method::GET);
+        assert!(requests.iter().any(|path| path.contains("/crpds")), "{:?}", requests);
+        assert!(requests.iter().all(|path| path.contains("/crpds")), "{:?}", requests);
+    }
+}
@@ -3,21 +3,222 @@ use kube::{Client, Error, Api, client};
 use k8s_openapi::{NamespaceResourceScope, ClusterResourceScope};
 use kube::api::{ObjectMeta, PostParams, DeleteParams};
 use serde::de::DeserializeOwned;
-use tracing::info;
+use tracing::{info, warn};
+use kube::runtime::events::{Event, EventType, Recorder, Reporter};
+use crate::health::health;
+use crate::metrics::metrics;
+use crate::config::config::OperatorConfig;
+use crate::controllers::cache::Cache;
 use std::collections::BTreeMap;
 use std::{fmt::Debug, borrow::BorrowMut};
 use std::sync::Arc;
 use serde::Serialize;
 use kube::api::{Patch, PatchParams, ListParams, ObjectList};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1 as meta_v1;
+use k8s_openapi::chrono::Utc;
+use kube::runtime::controller::{Action, Controller as runtime_controller};
+use kube::runtime::{reflector, watcher, WatchStreamExt};
+use futures::stream::BoxStream;
+use futures::{StreamExt, TryStreamExt};
+use std::hash::Hash;
+use rand::Rng;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+pub const CLEANUP_FINALIZER: &str = "cnm.juniper.net/cleanup";
+// held by the JunosConfigurationController until the neighbors of a deleted
+// BgpRouter are removed from the cRPDs. It is not the cleanup finalizer of
+// the BgpRouterController: finalizers are a set of names, so with a shared
+// name the first controller done would release the other's hold.
+pub const JUNOS_FINALIZER: &str = "cnm.juniper.net/junos-configuration";
+
+pub const CONDITION_READY: &str = "Ready";
+pub const CONDITION_PROGRESSING: &str = "Progressing";
+pub const CONDITION_DEGRADED: &str = "Degraded";
+pub const CONDITION_CONFIG_APPLIED: &str = "ConfigApplied";
 
 #[derive(Debug)]
-pub struct ReconcileError(pub anyhow::Error);
+pub enum ReconcileError{
+    // the kubernetes api failed, retried with backoff
+    TransientApi(anyhow::Error),
+    // the JET session to a cRPD could not be established, retried with backoff
+    CrpdUnreachable(anyhow::Error),
+    // Junos refused the configuration, retried with backoff
+    CommitRejected(anyhow::Error),
+    // the object can't be reconciled until its spec changes, not retried
+    InvalidSpec(anyhow::Error),
+    // another field manager owns fields the apply sets, retried with backoff
+    // until that manager lets go of them
+    ApplyConflict{
+        field_manager: String,
+        error: anyhow::Error,
+    },
+}
+impl ReconcileError{
+    pub fn reason(&self) -> &'static str{
+        match self{
+            ReconcileError::TransientApi(_) => "TransientApiError",
+            ReconcileError::CrpdUnreachable(_) => "CrpdUnreachable",
+            ReconcileError::CommitRejected(_) => "CommitRejected",
+            ReconcileError::InvalidSpec(_) => "InvalidSpec",
+            ReconcileError::ApplyConflict{..} => "ApplyConflict",
+        }
+    }
+    pub fn is_permanent(&self) -> bool{
+        matches!(self, ReconcileError::InvalidSpec(_))
+    }
+    pub fn error(&self) -> &anyhow::Error{
+        match self{
+            ReconcileError::TransientApi(e) => e,
+            ReconcileError::CrpdUnreachable(e) => e,
+            ReconcileError::CommitRejected(e) => e,
+            ReconcileError::InvalidSpec(e) => e,
+            ReconcileError::ApplyConflict{error, ..} => error,
+        }
+    }
+    pub fn into_error(self) -> anyhow::Error{
+        match self{
+            ReconcileError::TransientApi(e) => e,
+            ReconcileError::CrpdUnreachable(e) => e,
+            ReconcileError::CommitRejected(e) => e,
+            ReconcileError::InvalidSpec(e) => e,
+            ReconcileError::ApplyConflict{error, ..} => error,
+        }
+    }
+}
 impl std::error::Error for ReconcileError {
 
 }
 impl std::fmt::Display for ReconcileError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f,"ReconcileError: {}", self.0)
+        write!(f,"{}: {}", self.reason(), self.error())
+    }
+}
+
+// Backoff tracks failed reconciliations per object to requeue them with
+// an exponentially growing, jittered delay
+pub struct Backoff{
+    attempts: Mutex<HashMap<String, Attempts>>,
+    base: Duration,
+    max: Duration,
+}
+
+struct Attempts{
+    count: u32,
+    // when the object is reconciled again
+    due: Instant,
+    // generation of the failed object, a spec change ends the backoff
+    generation: Option<i64>,
+}
+
+impl Backoff{
+    pub fn new(base: Duration, max: Duration) -> Self{
+        Self{
+            attempts: Mutex::new(HashMap::new()),
+            base,
+            max,
+        }
+    }
+    pub fn next(&self, key: &str, generation: Option<i64>) -> Duration{
+        let mut attempts = self.attempts.lock().unwrap();
+        let now = Instant::now();
+        let mut controllers = vec![key_controller(key).to_string()];
+        // objects which stopped failing without a successful reconcile,
+        // e.g. deleted ones, are long overdue and dropped
+        attempts.retain(|key, attempt| {
+            let overdue = now.saturating_duration_since(attempt.due) >= self.max * 2;
+            if overdue{
+                controllers.push(key_controller(key).to_string());
+            }
+            !overdue
+        });
+        let attempt = attempts.entry(key.to_string()).or_insert(Attempts{
+            count: 0,
+            due: now,
+            generation,
+        });
+        let delay = self.base.saturating_mul(2u32.saturating_pow(attempt.count)).min(self.max);
+        let jitter = rand::thread_rng().gen_range(0..=delay.as_millis() as u64 / 5);
+        let delay = delay + Duration::from_millis(jitter);
+        attempt.count = attempt.count.saturating_add(1);
+        attempt.due = now + delay;
+        attempt.generation = generation;
+        report_queue_depth(&attempts, &controllers);
+        delay
+    }
+    // pending returns the time left until a failed object is due again.
+    // Events which don't change the spec, like the status written after the
+    // failure, must not cut the backoff short.
+    pub fn pending(&self, key: &str, generation: Option<i64>) -> Option<Duration>{
+        let attempts = self.attempts.lock().unwrap();
+        let attempt = attempts.get(key)?;
+        if attempt.generation != generation{
+            return None;
+        }
+        attempt.due.checked_duration_since(Instant::now()).filter(|pending| !pending.is_zero())
+    }
+    pub fn reset(&self, key: &str){
+        let mut attempts = self.attempts.lock().unwrap();
+        if attempts.remove(key).is_some(){
+            report_queue_depth(&attempts, &[key_controller(key).to_string()]);
+        }
+    }
+}
+
+// report_queue_depth exports the number of objects each of the controllers
+// holds back until their requeue
+fn report_queue_depth(attempts: &HashMap<String, Attempts>, controllers: &[String]){
+    for controller in controllers{
+        let depth = attempts.keys().filter(|key| key_controller(key) == controller).count();
+        metrics::metrics().queue_depth(controller, depth);
+    }
+}
+
+// key_controller returns the controller part of a backoff key
+fn key_controller(key: &str) -> &str{
+    key.split('/').next().unwrap_or_default()
+}
+
+// backoff_key identifies an object for a controller, a recreated object
+// starts without backoff
+pub fn backoff_key<T: kube::Resource<DynamicType = ()>>(controller: &str, t: &T) -> String{
+    format!("{}/{}/{}", controller, T::kind(&()), t.meta().uid.clone().unwrap_or_default())
+}
+
+// error_action requeues transient errors with backoff and waits for a spec
+// change on permanent ones
+pub fn error_action<T: kube::Resource<DynamicType = ()>>(controller: &str, t: &T, error: &ReconcileError, ctx: &Context) -> Action{
+    if error.is_permanent(){
+        ctx.backoff.reset(&backoff_key(controller, t));
+        return Action::await_change();
+    }
+    Action::requeue(ctx.backoff.next(&backoff_key(controller, t), t.meta().generation))
+}
+
+// Conditions gives access to the status conditions of a custom resource
+pub trait Conditions{
+    fn conditions_mut(&mut self) -> &mut Vec<meta_v1::Condition>;
+}
+
+// set_degraded records a failed reconciliation as Degraded condition
+pub async fn set_degraded<T>(t: &T, reason: &str, message: String, ctx: &Context)
+where
+T: kube::Resource<Scope = NamespaceResourceScope, DynamicType = ()>,
+T: Clone + DeserializeOwned + Debug + Serialize + Conditions,
+{
+    let mut current = match get::<T>(t.meta().namespace.as_ref().unwrap().clone(), t.meta().name.as_ref().unwrap().clone(), ctx.client.clone()).await{
+        Ok(Some((current, _))) => current,
+        Ok(None) => return,
+        Err(e) => {
+            warn!("failed to get {:?} for status update: {:?}", t.meta().name, e);
+            return;
+        }
+    };
+    let generation = current.meta().generation;
+    set_condition(current.conditions_mut(), CONDITION_DEGRADED, true, reason, message, generation);
+    if let Err(e) = update_status(current, ctx.client.clone()).await{
+        warn!("failed to record {} in status: {:?}", reason, e);
     }
 }
 
@@ -35,10 +236,14 @@ pub struct Context {
     pub key: Option<String>,
     pub cert: Option<String>,
     pub ca: Option<String>,
+    pub backoff: Arc<Backoff>,
+    pub config: Arc<OperatorConfig>,
+    pub cache: Arc<Cache>,
 }
 
 impl Context{
-    pub fn new(client: Client) -> Self{
+    pub fn new(client: Client, config: Arc<OperatorConfig>, cache: Arc<Cache>) -> Self{
+        let backoff = Backoff::new(Duration::from_secs(config.requeue.base_seconds), Duration::from_secs(config.requeue.max_seconds));
         Self{
             client,
             name: None,
@@ -47,20 +252,161 @@ impl Context{
             key: None,
             cert: None,
             ca: None,
+            backoff: Arc::new(backoff),
+            config,
+            cache,
         }
     }
 }
 
+pub async fn publish_event<T>(t: &T, ctx: &Context, type_: EventType, reason: &str, action: &str, note: String)
+where
+T: kube::Resource<DynamicType = ()>,
+{
+    let reporter = Reporter{
+        controller: "cnm".to_string(),
+        instance: ctx.name.clone(),
+    };
+    let recorder = Recorder::new(ctx.client.clone(), reporter, t.object_ref(&()));
+    let event = Event{
+        type_,
+        reason: reason.to_string(),
+        note: Some(note),
+        action: action.to_string(),
+        secondary: None,
+    };
+    // events are informational, failing to publish them must not fail the reconcile
+    if let Err(e) = recorder.publish(event).await{
+        warn!("failed to publish event {}: {:?}", reason, e);
+    }
+}
+
+// init_controllers runs the controllers until the first one ends. The
+// controller tasks are aborted when the returned future is dropped, e.g.
+// when the leader election loses the lease.
 pub async fn init_controllers(controller_list: Vec<Box<dyn Controller>>) -> anyhow::Result<()>{
-    let mut handles = Vec::new();
+    let mut handles = tokio::task::JoinSet::new();
     for controller in controller_list {
-        let handle = tokio::spawn(async move {
+        handles.spawn(async move {
             controller.run().await
         });
-        handles.push(handle);
     }
-    futures::future::join_all(handles).await;
-    Ok(())
+    // a controller which stops outside of a shutdown leaves the others half
+    // broken, so the first one to finish ends all of them
+    let res = match handles.join_next().await{
+        Some(res) => res,
+        None => return Ok(()),
+    };
+    if health::health().is_shutting_down(){
+        while handles.join_next().await.is_some(){}
+        return Ok(());
+    }
+    match res{
+        Ok(Ok(())) => Err(anyhow::anyhow!("controller stopped unexpectedly")),
+        Ok(Err(e)) => Err(e),
+        Err(e) => Err(anyhow::anyhow!("controller task failed: {}", e)),
+    }
+}
+
+// new_controller creates a controller for K in the namespaces the operator is
+// scoped to. Without watch namespaces the whole cluster is watched, otherwise
+// one watcher per namespace is merged into the controller stream.
+pub fn new_controller<K>(ctx: &Context, config: watcher::Config) -> runtime_controller<K>
+where
+K: kube::Resource<DynamicType = (), Scope = NamespaceResourceScope> + Clone + DeserializeOwned + Debug + Send + Sync + 'static,
+{
+    let namespaces = &ctx.config.watch_namespaces;
+    if namespaces.is_empty(){
+        return runtime_controller::new(Api::all(ctx.client.clone()), config);
+    }
+    let (reader, writer) = reflector::store();
+    let events = namespaced_events(ctx.client.clone(), namespaces.clone(), config, reader.clone());
+    runtime_controller::for_stream(reflector::reflector(writer, events).applied_objects(), reader)
+}
+
+// watch_stream returns the objects of K in the namespaces the operator is
+// scoped to, for use with Controller::watches_stream
+pub fn watch_stream<K>(ctx: &Context, config: watcher::Config) -> BoxStream<'static, Result<K, watcher::Error>>
+where
+K: kube::Resource<DynamicType = (), Scope = NamespaceResourceScope> + Clone + DeserializeOwned + Debug + Send + 'static,
+{
+    let namespaces = &ctx.config.watch_namespaces;
+    if namespaces.is_empty(){
+        return watcher::watcher(Api::<K>::all(ctx.client.clone()), config).applied_objects().boxed();
+    }
+    let streams = namespaces.iter().map(|namespace| {
+        watcher::watcher(Api::<K>::namespaced(ctx.client.clone(), namespace), config.clone()).applied_objects().boxed()
+    });
+    futures::stream::select_all(streams).boxed()
+}
+
+// namespaced_events merges the watchers of several namespaces. Until every
+// namespace has been listed the events are held back, so the shared store is
+// first filled by one restart carrying all namespaces. Afterwards a restart of
+// one watcher is turned into a restart which keeps the objects of the other
+// namespaces.
+pub fn namespaced_events<K>(client: Client, namespaces: Vec<String>, config: watcher::Config, reader: reflector::Store<K>) -> BoxStream<'static, Result<watcher::Event<K>, watcher::Error>>
+where
+K: kube::Resource<DynamicType = (), Scope = NamespaceResourceScope> + Clone + DeserializeOwned + Debug + Send + Sync + 'static,
+K::DynamicType: Eq + Hash + Clone,
+{
+    let streams = namespaces.iter().map(|namespace| {
+        let namespace = namespace.clone();
+        watcher::watcher(Api::<K>::namespaced(client.clone(), &namespace), config.clone())
+            .map_ok(move |event| (namespace.clone(), event))
+            .boxed()
+    });
+    merge_namespaced_events(futures::stream::select_all(streams).boxed(), namespaces, reader)
+}
+
+// merge_namespaced_events turns the events of the namespace watchers, tagged
+// with their namespace, into the events of the shared store
+fn merge_namespaced_events<K>(events: BoxStream<'static, Result<(String, watcher::Event<K>), watcher::Error>>, namespaces: Vec<String>, reader: reflector::Store<K>) -> BoxStream<'static, Result<watcher::Event<K>, watcher::Error>>
+where
+K: kube::Resource<DynamicType = ()> + Clone + Send + Sync + 'static,
+{
+    // the objects of the namespaces listed so far, until all of them are
+    let mut listed: Option<HashMap<String, Vec<K>>> = Some(HashMap::new());
+    events
+        .flat_map(move |res| {
+            let events = match (res, listed.as_mut()){
+                (Ok((namespace, watcher::Event::Restarted(objects))), Some(pending)) => {
+                    pending.insert(namespace, objects);
+                    if pending.len() == namespaces.len(){
+                        let all = pending.drain().flat_map(|(_, objects)| objects).collect();
+                        listed = None;
+                        vec![Ok(watcher::Event::Restarted(all))]
+                    } else {
+                        Vec::new()
+                    }
+                },
+                (Ok((namespace, watcher::Event::Applied(object))), Some(pending)) => {
+                    if let Some(objects) = pending.get_mut(&namespace){
+                        objects.retain(|o| o.meta().name != object.meta().name);
+                        objects.push(object);
+                    }
+                    Vec::new()
+                },
+                (Ok((namespace, watcher::Event::Deleted(object))), Some(pending)) => {
+                    if let Some(objects) = pending.get_mut(&namespace){
+                        objects.retain(|o| o.meta().name != object.meta().name);
+                    }
+                    Vec::new()
+                },
+                (Ok((namespace, watcher::Event::Restarted(objects))), None) => {
+                    let mut all: Vec<K> = reader.state().iter()
+                        .filter(|object| object.meta().namespace.as_ref() != Some(&namespace))
+                        .map(|object| object.as_ref().clone())
+                        .collect();
+                    all.extend(objects);
+                    vec![Ok(watcher::Event::Restarted(all))]
+                },
+                (Ok((_, event)), None) => vec![Ok(event)],
+                (Err(e), _) => vec![Err(e)],
+            };
+            futures::stream::iter(events)
+        })
+        .boxed()
 }
 
 pub fn is_not_found(e: &Error) -> bool {
@@ -114,6 +460,13 @@ pub fn already_exists(e: &Error) -> bool {
     }
 }
 
+pub fn is_conflict(e: &Error) -> bool {
+    match e{
+        kube::Error::Api(ae) => ae.code == 409 && ae.reason == "Conflict",
+        _ => false,
+    }
+}
+
 //Result<Option<T>, ReconcileError>
 
 pub async fn get<T: kube::Resource>(namespace: String, name: String, client: Client) -> Result<Option<(T,Api<T>)>, ReconcileError>
@@ -132,7 +485,7 @@ T: Clone + DeserializeOwned + Debug,
             if is_not_found(&e){
                 None
             } else {
-                return Err(ReconcileError(e.into()));
+                return Err(ReconcileError::TransientApi(e.into()));
             }
         },
     };
@@ -144,10 +497,18 @@ where
 T: kube::Resource<Scope = NamespaceResourceScope>,
 <T as kube::Resource>::DynamicType: Default,
 T: Clone + DeserializeOwned + Debug,
+{
+    delete_with::<T>(namespace, name, &DeleteParams::default(), client).await
+}
+
+pub async fn delete_with<T>(namespace: String, name: String, dp: &DeleteParams, client: Client) -> Result<(), ReconcileError>
+where
+T: kube::Resource<Scope = NamespaceResourceScope>,
+<T as kube::Resource>::DynamicType: Default,
+T: Clone + DeserializeOwned + Debug,
 {
     let res_api: Api<T> = Api::namespaced(client.clone(), namespace.as_str());
-    let dp = DeleteParams::default();
-    match res_api.delete(name.as_str(), &dp).await{
+    match res_api.delete(name.as_str(), dp).await{
         Ok(_res) => {
             
         },
@@ -155,7 +516,7 @@ T: Clone + DeserializeOwned + Debug,
             if is_not_found(&e){
                 info!("Resource not found: {:?}", e);
             } else {
-                return Err(ReconcileError(e.into()));
+                return Err(ReconcileError::TransientApi(e.into()));
             }
         },
     }
@@ -178,14 +539,14 @@ T: Clone + DeserializeOwned + Debug,
             if is_not_found(&e){
                 None
             } else {
-                return Err(ReconcileError(e.into()));
+                return Err(ReconcileError::TransientApi(e.into()));
             }
         },
     };
     Ok(res)
 }
 
-pub async fn list<T: kube::Resource>(namespace: String, client: Client, labels: Option<BTreeMap<String, String>>) -> Result<Option<(ObjectList<T>,Api<T>)>, ReconcileError>
+pub async fn list<T>(namespace: String, client: Client, selector: Option<meta_v1::LabelSelector>) -> Result<Option<(ObjectList<T>,Api<T>)>, ReconcileError>
 where
 T: kube::Resource<Scope = NamespaceResourceScope>,
 <T as kube::Resource>::DynamicType: Default,
@@ -193,8 +554,11 @@ T: Clone + DeserializeOwned + Debug,
 {
     let res_api: Api<T> = Api::namespaced(client.clone(), namespace.as_str());
     let mut list_params = ListParams::default();
-    for (k, v) in labels.unwrap().iter(){
-        list_params.label_selector = Some(format!("{}={}", k, v));
+    if let Some(selector) = selector{
+        let selector = label_selector(&selector)?;
+        if !selector.is_empty(){
+            list_params.label_selector = Some(selector);
+        }
     }
     let res = match res_api.list(&list_params).await{
         Ok(res) => {
@@ -204,13 +568,99 @@ T: Clone + DeserializeOwned + Debug,
             if is_not_found(&e){
                 None
             } else {
-                return Err(ReconcileError(e.into()));
+                return Err(ReconcileError::TransientApi(e.into()));
             }
         },
     };
     Ok(res)
 }
 
+// label_selector converts a LabelSelector into its string form, e.g.
+// "app=crpd,tier in (edge,core),!deprecated". An empty selector matches everything.
+pub fn label_selector(selector: &meta_v1::LabelSelector) -> Result<String, ReconcileError>{
+    let mut requirements = Vec::new();
+    if let Some(match_labels) = &selector.match_labels{
+        for (k, v) in match_labels{
+            requirements.push(format!("{}={}", k, v));
+        }
+    }
+    if let Some(match_expressions) = &selector.match_expressions{
+        for expression in match_expressions{
+            let values = expression.values.clone().unwrap_or_default();
+            let requirement = match expression.operator.as_str(){
+                "In" | "NotIn" => {
+                    if values.is_empty(){
+                        return Err(ReconcileError::InvalidSpec(anyhow::anyhow!("operator {} on {} requires values", expression.operator, expression.key)));
+                    }
+                    let mut values = values;
+                    values.sort();
+                    format!("{} {} ({})", expression.key, expression.operator.to_lowercase(), values.join(","))
+                },
+                "Exists" | "DoesNotExist" => {
+                    if !values.is_empty(){
+                        return Err(ReconcileError::InvalidSpec(anyhow::anyhow!("operator {} on {} must not have values", expression.operator, expression.key)));
+                    }
+                    if expression.operator == "Exists"{
+                        expression.key.clone()
+                    } else {
+                        format!("!{}", expression.key)
+                    }
+                },
+                _ => {
+                    return Err(ReconcileError::InvalidSpec(anyhow::anyhow!("unsupported operator {} on {}", expression.operator, expression.key)));
+                },
+            };
+            requirements.push(requirement);
+        }
+    }
+    Ok(requirements.join(","))
+}
+
+// selector_matches evaluates a LabelSelector against the labels of an object
+// the same way the api server does for label_selector
+pub fn selector_matches(selector: &meta_v1::LabelSelector, labels: Option<&BTreeMap<String, String>>) -> Result<bool, ReconcileError>{
+    let empty = BTreeMap::new();
+    let labels = labels.unwrap_or(&empty);
+    if let Some(match_labels) = &selector.match_labels{
+        for (k, v) in match_labels{
+            if labels.get(k) != Some(v){
+                return Ok(false);
+            }
+        }
+    }
+    if let Some(match_expressions) = &selector.match_expressions{
+        for expression in match_expressions{
+            let values = expression.values.clone().unwrap_or_default();
+            let matches = match expression.operator.as_str(){
+                "In" | "NotIn" => {
+                    if values.is_empty(){
+                        return Err(ReconcileError::InvalidSpec(anyhow::anyhow!("operator {} on {} requires values", expression.operator, expression.key)));
+                    }
+                    let found = match labels.get(&expression.key){
+                        Some(value) => values.contains(value),
+                        None => false,
+                    };
+                    if expression.operator == "In" { found } else { !found }
+                },
+                "Exists" | "DoesNotExist" => {
+                    if !values.is_empty(){
+                        return Err(ReconcileError::InvalidSpec(anyhow::anyhow!("operator {} on {} must not have values", expression.operator, expression.key)));
+                    }
+                    let exists = labels.contains_key(&expression.key);
+                    if expression.operator == "Exists" { exists } else { !exists }
+                },
+                _ => {
+                    return Err(ReconcileError::InvalidSpec(anyhow::anyhow!("unsupported operator {} on {}", expression.operator, expression.key)));
+                },
+            };
+            if !matches{
+                return Ok(false);
+            }
+        }
+    }
+    Ok(true)
+}
+
 pub async fn create<T: kube::Resource>(t: Arc<T>, client: Client) -> Result<Option<T>, ReconcileError>
 where
 T: kube::Resource<Scope = NamespaceResourceScope>,
@@ -227,7 +677,7 @@ T: Clone + DeserializeOwned + Debug + Serialize,
             if is_not_found(&e){
                 None
             } else {
-                return Err(ReconcileError(e.into()));
+                return Err(ReconcileError::TransientApi(e.into()));
             }
         },
     };
@@ -250,156 +700,418 @@ T: Clone + DeserializeOwned + Debug + Serialize,
             if is_not_found(&e){
                 None
             } else {
-                return Err(ReconcileError(e.into()));
+                return Err(ReconcileError::TransientApi(e.into()));
             }
         },
     };
     Ok(res)
 }
 
-pub async fn patch<T: kube::Resource>(t: T, client: Client) -> Result<Option<T>, ReconcileError>
+// apply server side applies t as field_manager. Without force, fields owned by
+// another manager are reported as a conflict instead of being taken over.
+pub async fn apply<T>(t: T, field_manager: &str, force: bool, client: Client) -> Result<Option<T>, ReconcileError>
 where
 T: kube::Resource<Scope = NamespaceResourceScope>,
 <T as kube::Resource>::DynamicType: Default,
 T: Clone + DeserializeOwned + Debug + Serialize,
 {
-    let patch = Patch::Merge(&t);
-    let params = PatchParams::apply("crpd");
-    let res_api: Api<T> = Api::namespaced(client.clone(), t.meta().namespace.as_ref().unwrap());
-    let res = match res_api.patch(t.meta().name.as_ref().unwrap(), &params, &patch).await{
-        Ok(res) => {
-            Some(res)
-        },
-        Err(e) => {
-            if is_not_found(&e){
-                None
-            } else {
-                info!("Error updating resource: {:?}", t);
-                return Err(ReconcileError(e.into()));
-            }
-        },
-    };
-    Ok(res)
+    let res_api: Api<T> = Api::namespaced(client, t.meta().namespace.as_ref().unwrap());
+    apply_with(res_api, t, field_manager, force).await
 }
 
-pub async fn patch_cluster<T: kube::Resource>(t: T, client: Client) -> Result<Option<T>, ReconcileError>
+pub async fn apply_cluster<T>(t: T, field_manager: &str, force: bool, client: Client) -> Result<Option<T>, ReconcileError>
 where
 T: kube::Resource<Scope = ClusterResourceScope>,
 <T as kube::Resource>::DynamicType: Default,
 T: Clone + DeserializeOwned + Debug + Serialize,
 {
-    let patch = Patch::Merge(&t);
-    let params = PatchParams::apply("crpd");
-    let res_api: Api<T> = Api::all(client.clone());
-    let res = match res_api.patch(t.meta().name.as_ref().unwrap(), &params, &patch).await{
-        Ok(res) => {
-            Some(res)
-        },
+    let res_api: Api<T> = Api::all(client);
+    apply_with(res_api, t, field_manager, force).await
+}
+
+// apply_with server side applies t through res_api. A conflict with another
+// field manager is reported as such, the user has to resolve it.
+async fn apply_with<T>(res_api: Api<T>, mut t: T, field_manager: &str, force: bool) -> Result<Option<T>, ReconcileError>
+where
+T: kube::Resource,
+<T as kube::Resource>::DynamicType: Default,
+T: Clone + DeserializeOwned + Debug + Serialize,
+{
+    info!("Applying {:?} as {}", t.meta().name.as_ref().unwrap(), field_manager);
+    t.borrow_mut().meta_mut().managed_fields = None;
+    let mut params = PatchParams::apply(field_manager);
+    params.force = force;
+    match res_api.patch(t.meta().name.as_ref().unwrap(), &params, &Patch::Apply(&t)).await{
+        Ok(res) => Ok(Some(res)),
         Err(e) => {
-            if is_not_found(&e){
-                None
-            } else {
-                info!("Error updating resource: {:?}", t);
-                return Err(ReconcileError(e.into()));
+            if is_conflict(&e){
+                warn!("Apply conflict on {:?} for {}: {:?}", t.meta().name.as_ref().unwrap(), field_manager, e);
+                let name = t.meta().name.clone().unwrap_or_default();
+                return Err(ReconcileError::ApplyConflict{
+                    field_manager: field_manager.to_string(),
+                    error: anyhow::Error::new(e).context(format!("apply of {} as {} conflicts with another field manager", name, field_manager)),
+                });
             }
+            Err(ReconcileError::TransientApi(e.into()))
         },
-    };
-    Ok(res)
+    }
 }
 
-pub async fn replace<T: kube::Resource>(mut t: T, client: Client) -> Result<Option<T>, ReconcileError>
+pub async fn update_status<T: kube::Resource>(t: T, client: Client) -> Result<Option<T>, ReconcileError>
 where
 T: kube::Resource<Scope = NamespaceResourceScope>,
 <T as kube::Resource>::DynamicType: Default,
 T: Clone + DeserializeOwned + Debug + Serialize,
 {
-    info!("Updating {:?}", t.meta().name.as_ref().unwrap());
-    t.borrow_mut().meta_mut().managed_fields = None;
+    info!("Updating Status {:?}", t.meta().name.as_ref().unwrap());
+    let patch = serde_json::to_vec(&t).unwrap();
     let params = PostParams::default();
     let res_api: Api<T> = Api::namespaced(client.clone(), t.meta().namespace.as_ref().unwrap());
-    let res = match res_api.replace(t.meta().name.as_ref().unwrap(), &params, &t).await{
+    let res = match res_api.replace_status(t.clone().meta().name.as_ref().unwrap(), &params, patch).await{
         Ok(res) => {
             Some(res)
         },
         Err(e) => {
             if is_not_found(&e){
+                info!("status not found: {:?}", e);
                 None
             } else {
-                return Err(ReconcileError(e.into()));
+                return Err(ReconcileError::TransientApi(e.into()));
             }
         },
     };
     Ok(res)
 }
 
-pub async fn create_or_update<T: kube::Resource>(t: T, client: Client) -> Result<Option<T>, ReconcileError>
+
+pub fn has_finalizer<T: kube::Resource>(t: &T, finalizer: &str) -> bool {
+    match &t.meta().finalizers{
+        Some(finalizers) => finalizers.iter().any(|f| f == finalizer),
+        None => false,
+    }
+}
+
+pub async fn add_finalizer<T>(t: &T, finalizer: &str, client: Client) -> Result<Option<T>, ReconcileError>
 where
 T: kube::Resource<Scope = NamespaceResourceScope>,
 <T as kube::Resource>::DynamicType: Default,
 T: Clone + DeserializeOwned + Debug + Serialize,
 {
-    match get::<T>(t.meta().namespace.as_ref().unwrap().clone(), t.meta().name.as_ref().unwrap().clone(), client.clone()).await{
-        Ok(res) => {
-            match res{
-                Some((mut current, _)) => {                    
-                    patch(t, client).await
-                },
-                None => {
-                    create(Arc::new(t), client).await
-                },
-            }
-        },
-        Err(e) => {
-            Err(e)
-        },
+    if has_finalizer(t, finalizer){
+        return Ok(Some(t.clone()));
     }
+    info!("Adding finalizer {} to {:?}", finalizer, t.meta().name.as_ref().unwrap());
+    let mut finalizers = t.meta().finalizers.clone().unwrap_or_default();
+    finalizers.push(finalizer.to_string());
+    patch_finalizers(t, finalizers, client).await
 }
 
-pub async fn create_or_update_cluster<T: kube::Resource>(t: T, client: Client) -> Result<Option<T>, ReconcileError>
+pub async fn remove_finalizer<T>(t: &T, finalizer: &str, client: Client) -> Result<Option<T>, ReconcileError>
 where
-T: kube::Resource<Scope = ClusterResourceScope>,
+T: kube::Resource<Scope = NamespaceResourceScope>,
 <T as kube::Resource>::DynamicType: Default,
 T: Clone + DeserializeOwned + Debug + Serialize,
 {
-    match get_cluster::<T>(t.meta().name.as_ref().unwrap().clone(), client.clone()).await{
-        Ok(res) => {
-            match res{
-                Some((mut current, _)) => {                    
-                    patch_cluster(t, client).await
-                },
-                None => {
-                    create_cluster(Arc::new(t), client).await
-                },
-            }
-        },
-        Err(e) => {
-            Err(e)
-        },
+    if !has_finalizer(t, finalizer){
+        return Ok(Some(t.clone()));
     }
+    info!("Removing finalizer {} from {:?}", finalizer, t.meta().name.as_ref().unwrap());
+    let finalizers = t.meta().finalizers.clone().unwrap_or_default()
+        .into_iter()
+        .filter(|f| f != finalizer)
+        .collect();
+    patch_finalizers(t, finalizers, client).await
 }
 
-pub async fn update_status<T: kube::Resource>(t: T, client: Client) -> Result<Option<T>, ReconcileError>
+async fn patch_finalizers<T>(t: &T, finalizers: Vec<String>, client: Client) -> Result<Option<T>, ReconcileError>
 where
 T: kube::Resource<Scope = NamespaceResourceScope>,
 <T as kube::Resource>::DynamicType: Default,
 T: Clone + DeserializeOwned + Debug + Serialize,
 {
-    info!("Updating Status {:?}", t.meta().name.as_ref().unwrap());
-    let patch = serde_json::to_vec(&t).unwrap();
-    let params = PostParams::default();
+    let patch = serde_json::json!({
+        "metadata": {
+            "resourceVersion": t.meta().resource_version.clone(),
+            "finalizers": finalizers,
+        }
+    });
     let res_api: Api<T> = Api::namespaced(client.clone(), t.meta().namespace.as_ref().unwrap());
-    let res = match res_api.replace_status(t.clone().meta().name.as_ref().unwrap(), &params, patch).await{
+    let res = match res_api.patch(t.meta().name.as_ref().unwrap(), &PatchParams::default(), &Patch::Merge(&patch)).await{
         Ok(res) => {
             Some(res)
         },
         Err(e) => {
             if is_not_found(&e){
-                info!("status not found: {:?}", e);
                 None
             } else {
-                return Err(ReconcileError(e.into()));
+                return Err(ReconcileError::TransientApi(e.into()));
             }
         },
     };
     Ok(res)
 }
+
+
+// set_condition adds or updates the condition of the given type. The transition
+// time is only moved when the status of the condition changes.
+pub fn set_condition(conditions: &mut Vec<meta_v1::Condition>, type_: &str, status: bool, reason: &str, message: String, observed_generation: Option<i64>){
+    let status = if status { "True".to_string() } else { "False".to_string() };
+    match conditions.iter_mut().find(|condition| condition.type_ == type_){
+        Some(condition) => {
+            if condition.status != status{
+                condition.last_transition_time = meta_v1::Time(Utc::now());
+            }
+            condition.status = status;
+            condition.reason = reason.to_string();
+            condition.message = message;
+            condition.observed_generation = observed_generation;
+        },
+        None => {
+            conditions.push(meta_v1::Condition{
+                type_: type_.to_string(),
+                status,
+                reason: reason.to_string(),
+                message,
+                observed_generation,
+                last_transition_time: meta_v1::Time(Utc::now()),
+            });
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::controllers::test_utils;
+    use crate::resources::crpd::crpd::Crpd;
+    use futures::FutureExt;
+
+    fn expression(key: &str, operator: &str, values: &[&str]) -> meta_v1::LabelSelectorRequirement{
+        meta_v1::LabelSelectorRequirement{
+            key: key.to_string(),
+            operator: operator.to_string(),
+            values: if values.is_empty() { None } else { Some(values.iter().map(|value| value.to_string()).collect()) },
+        }
+    }
+
+    fn selector(match_labels: &[(&str, &str)], match_expressions: Vec<meta_v1::LabelSelectorRequirement>) -> meta_v1::LabelSelector{
+        meta_v1::LabelSelector{
+            match_labels: if match_labels.is_empty() { None } else {
+                Some(match_labels.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect())
+            },
+            match_expressions: if match_expressions.is_empty() { None } else { Some(match_expressions) },
+        }
+    }
+
+    #[test]
+    fn label_selector_formats_requirements(){
+        let cases = vec![
+            (selector(&[], vec![]), ""),
+            (selector(&[("app", "crpd")], vec![]), "app=crpd"),
+            (selector(&[("app", "crpd"), ("tier", "edge")], vec![]), "app=crpd,tier=edge"),
+            (selector(&[], vec![expression("tier", "In", &["edge", "core"])]), "tier in (core,edge)"),
+            (selector(&[], vec![expression("tier", "NotIn", &["edge"])]), "tier notin (edge)"),
+            (selector(&[], vec![expression("deprecated", "Exists", &[])]), "deprecated"),
+            (selector(&[], vec![expression("deprecated", "DoesNotExist", &[])]), "!deprecated"),
+            (selector(&[("app", "crpd")], vec![expression("tier", "In", &["edge", "core"]), expression("deprecated", "DoesNotExist", &[])]),
+                "app=crpd,tier in (core,edge),!deprecated"),
+        ];
+        for (selector, expected) in cases{
+            assert_eq!(label_selector(&selector).unwrap(), expected, "{:?}", selector);
+        }
+    }
+
+    #[test]
+    fn label_selector_rejects_invalid_expressions(){
+        let cases = vec![
+            expression("tier", "In", &[]),
+            expression("tier", "NotIn", &[]),
+            expression("tier", "Exists", &["edge"]),
+            expression("tier", "DoesNotExist", &["edge"]),
+            expression("tier", "Gt", &["1"]),
+        ];
+        for expression in cases{
+            let selector = selector(&[], vec![expression]);
+            assert!(matches!(label_selector(&selector), Err(ReconcileError::InvalidSpec(_))), "{:?}", selector);
+            assert!(matches!(selector_matches(&selector, None), Err(ReconcileError::InvalidSpec(_))), "{:?}", selector);
+        }
+    }
+
+    #[tokio::test]
+    async fn apply_reports_conflicts_with_the_field_manager(){
+        let server = test_utils::ApiServer::new();
+        server.conflict(http::Method::PATCH, "/apis/cnm.juniper.net/v1/namespaces/default/crpds/crpd1");
+        let error = super::apply(test_utils::crpd("crpd1", 1), "cnm-test", false, server.client()).await.unwrap_err();
+        assert_eq!(error.reason(), "ApplyConflict");
+        assert!(error.to_string().contains("cnm-test"), "{}", error);
+        assert!(!error.is_permanent());
+
+        server.fail(http::Method::PATCH, "/apis/cnm.juniper.net/v1/namespaces/default/crpds/crpd2");
+        let error = super::apply(test_utils::crpd("crpd2", 1), "cnm-test", false, server.client()).await.unwrap_err();
+        assert!(matches!(error, ReconcileError::TransientApi(_)), "{:?}", error);
+    }
+
+    #[tokio::test]
+    async fn selector_matches_agrees_with_list(){
+        let cases = vec![
+            (selector(&[], vec![]), vec![("app", "crpd")], true),
+            (selector(&[], vec![]), vec![], true),
+            (selector(&[("app", "crpd")], vec![]), vec![("app", "crpd")], true),
+            (selector(&[("app", "crpd")], vec![]), vec![("app", "other")], false),
+            (selector(&[("app", "crpd")], vec![]), vec![], false),
+            (selector(&[], vec![expression("tier", "In", &["edge", "core"])]), vec![("tier", "core")], true),
+            (selector(&[], vec![expression("tier", "In", &["edge", "core"])]), vec![("tier", "access")], false),
+            (selector(&[], vec![expression("tier", "In", &["edge", "core"])]), vec![], false),
+            (selector(&[], vec![expression("tier", "NotIn", &["edge"])]), vec![("tier", "core")], true),
+            (selector(&[], vec![expression("tier", "NotIn", &["edge"])]), vec![("tier", "edge")], false),
+            // a missing label is not in any set
+            (selector(&[], vec![expression("tier", "NotIn", &["edge"])]), vec![], true),
+            (selector(&[], vec![expression("deprecated", "Exists", &[])]), vec![("deprecated", "")], true),
+            (selector(&[], vec![expression("deprecated", "Exists", &[])]), vec![], false),
+            (selector(&[], vec![expression("deprecated", "DoesNotExist", &[])]), vec![("deprecated", "true")], false),
+            (selector(&[], vec![expression("deprecated", "DoesNotExist", &[])]), vec![], true),
+            (selector(&[("app", "crpd")], vec![expression("tier", "In", &["edge", "core"]), expression("deprecated", "DoesNotExist", &[])]),
+                vec![("app", "crpd"), ("tier", "edge")], true),
+            (selector(&[("app", "crpd")], vec![expression("tier", "In", &["edge", "core"]), expression("deprecated", "DoesNotExist", &[])]),
+                vec![("app", "crpd"), ("tier", "edge"), ("deprecated", "true")], false),
+        ];
+        for (selector, labels, expected) in cases{
+            let labels: BTreeMap<String, String> = labels.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+            assert_eq!(selector_matches(&selector, Some(&labels)).unwrap(), expected, "{:?} {:?}", selector, labels);
+
+            // the test api server filters with the string form
+            let server = test_utils::ApiServer::new();
+            let mut crpd = test_utils::crpd("crpd1", 1);
+            crpd.metadata.labels = Some(labels.clone());
+            server.add(&crpd);
+            let (crpds, _) = list::<Crpd>(test_utils::NAMESPACE.to_string(), server.context().client.clone(), Some(selector.clone())).await.unwrap().unwrap();
+            assert_eq!(crpds.items.len() == 1, expected, "{:?} {:?}", selector, labels);
+        }
+    }
+
+    #[test]
+    fn backoff_holds_back_failed_objects_until_due_or_changed(){
+        let backoff = Backoff::new(Duration::from_secs(60), Duration::from_secs(300));
+        assert_eq!(backoff.pending("crpd/Crpd/uid1", Some(1)), None);
+        let delay = backoff.next("crpd/Crpd/uid1", Some(1));
+        assert!(delay >= Duration::from_secs(60));
+        // a status update keeps the generation
+        let pending = backoff.pending("crpd/Crpd/uid1", Some(1)).unwrap();
+        assert!(pending <= delay && pending > Duration::from_secs(50));
+        assert_eq!(backoff.pending("crpd/Crpd/uid1", Some(2)), None);
+        assert_eq!(backoff.pending("bgp_router/Crpd/uid1", Some(1)), None);
+        backoff.reset("crpd/Crpd/uid1");
+        assert_eq!(backoff.pending("crpd/Crpd/uid1", Some(1)), None);
+    }
+
+    #[test]
+    fn backoff_drops_objects_which_stopped_failing(){
+        let backoff = Backoff::new(Duration::from_millis(1), Duration::from_millis(1));
+        backoff.next("crpd/Crpd/deleted", None);
+        std::thread::sleep(Duration::from_millis(10));
+        backoff.next("crpd/Crpd/uid1", None);
+        let attempts = backoff.attempts.lock().unwrap();
+        assert_eq!(attempts.keys().collect::<Vec<_>>(), vec!["crpd/Crpd/uid1"]);
+    }
+
+    #[test]
+    fn backoff_reports_queue_depth_per_controller(){
+        let backoff = Backoff::new(Duration::from_secs(60), Duration::from_secs(300));
+        let depth = |controller: &str| format!(r#"cnm_reconcile_queue_depth{{controller="{}"}}"#, controller);
+        backoff.next("queue_test/Crpd/uid1", None);
+        backoff.next("queue_test/Crpd/uid2", None);
+        backoff.next("queue_test/Crpd/uid2", None);
+        backoff.next("other_queue_test/Crpd/uid1", None);
+        let rendered = metrics::metrics().render().unwrap();
+        assert!(rendered.contains(&format!("{} 2", depth("queue_test"))), "{}", rendered);
+        assert!(rendered.contains(&format!("{} 1", depth("other_queue_test"))), "{}", rendered);
+        backoff.reset("queue_test/Crpd/uid1");
+        assert!(metrics::metrics().render().unwrap().contains(&format!("{} 1", depth("queue_test"))));
+    }
+
+    fn crpd(namespace: &str, name: &str) -> Crpd{
+        let mut crpd = test_utils::crpd(name, 1);
+        crpd.metadata.namespace = Some(namespace.to_string());
+        crpd
+    }
+
+    fn restarted(namespace: &str, objects: Vec<Crpd>) -> Result<(String, watcher::Event<Crpd>), watcher::Error>{
+        Ok((namespace.to_string(), watcher::Event::Restarted(objects)))
+    }
+
+    // names returns the namespace/name of the objects in the store
+    fn names(reader: &reflector::Store<Crpd>) -> Vec<String>{
+        let mut names: Vec<String> = reader.state().iter()
+            .map(|crpd| format!("{}/{}", crpd.metadata.namespace.as_ref().unwrap(), crpd.metadata.name.as_ref().unwrap()))
+            .collect();
+        names.sort();
+        names
+    }
+
+    // apply feeds the watcher events through merge_namespaced_events into a
+    // store, one at a time like a reflector, and returns the store after each,
+    // None while it is not ready
+    fn apply(events: Vec<Result<(String, watcher::Event<Crpd>), watcher::Error>>) -> Vec<Option<Vec<String>>>{
+        let (reader, mut writer) = reflector::store();
+        let (sender, receiver) = futures::channel::mpsc::unbounded();
+        let mut merged = merge_namespaced_events(receiver.boxed(), vec!["a".to_string(), "b".to_string()], reader.clone());
+        let mut stores = Vec::new();
+        for event in events{
+            sender.unbounded_send(event).unwrap();
+            while let Some(Some(event)) = merged.next().now_or_never(){
+                writer.apply_watcher_event(&event.unwrap());
+            }
+            match reader.wait_until_ready().now_or_never(){
+                Some(_) => stores.push(Some(names(&reader))),
+                None => stores.push(None),
+            }
+        }
+        stores
+    }
+
+    #[test]
+    fn namespaced_events_restart_before_all_namespaces_are_listed(){
+        let stores = apply(vec![
+            restarted("a", vec![crpd("a", "crpd1")]),
+            // a restarts before b was listed, only a's objects are replaced
+            restarted("a", vec![crpd("a", "crpd2")]),
+            restarted("b", vec![crpd("b", "crpd3")]),
+        ]);
+        assert_eq!(stores[0], None);
+        assert_eq!(stores[1], None);
+        assert_eq!(stores[2], Some(vec!["a/crpd2".to_string(), "b/crpd3".to_string()]));
+    }
+
+    #[test]
+    fn namespaced_events_wait_for_empty_namespaces(){
+        let stores = apply(vec![
+            restarted("a", vec![crpd("a", "crpd1")]),
+            Ok(("a".to_string(), watcher::Event::Applied(crpd("a", "crpd2")))),
+            Ok(("a".to_string(), watcher::Event::Deleted(crpd("a", "crpd1")))),
+            // b has nothing to list, the store is only ready once it did
+            restarted("b", vec![]),
+        ]);
+        assert_eq!(stores[0], None);
+        assert_eq!(stores[1], None);
+        assert_eq!(stores[2], None);
+        assert_eq!(stores[3], Some(vec!["a/crpd2".to_string()]));
+    }
+
+    #[test]
+    fn namespaced_events_restart_after_all_namespaces_are_listed(){
+        let stores = apply(vec![
+            restarted("a", vec![crpd("a", "crpd1")]),
+            restarted("b", vec![crpd("b", "crpd2")]),
+            Ok(("a".to_string(), watcher::Event::Applied(crpd("a", "crpd3")))),
+            // b restarts, the objects of a are kept
+            restarted("b", vec![crpd("b", "crpd4")]),
+            restarted("a", vec![]),
+        ]);
+        let names = |names: &[&str]| Some(names.iter().map(|name| name.to_string()).collect::<Vec<String>>());
+        assert_eq!(stores[0], None);
+        assert_eq!(stores[1], names(&["a/crpd1", "b/crpd2"]));
+        assert_eq!(stores[2], names(&["a/crpd1", "a/crpd3", "b/crpd2"]));
+        assert_eq!(stores[3], names(&["a/crpd1", "a/crpd3", "b/crpd4"]));
+        assert_eq!(stores[4], names(&["b/crpd4"]));
+    }
+}
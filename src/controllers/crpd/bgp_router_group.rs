@@ -1,15 +1,17 @@
 use crate::controllers::controllers::{Controller, Context, ReconcileError};
-use crate::controllers::{controllers, bgp_router};
+use crate::metrics::metrics;
+use crate::health::health;
+use kube::runtime::events::EventType;
+use crate::controllers::{controllers, cache};
 use crate::resources::bgp_router_group::BgpRouterGroup;
 use crate::resources::bgp_router_group::BgpRouterGroupStatus;
 use crate::resources::bgp_router_group::BgpRouterReference;
-use crate::resources::bgp_router::BgpRouter;
-use crate::resources::crpd::crpd::Crpd;
+use crate::resources::bgp_router::{BgpRouter, IpFamily};
+use crate::resources::crpd::crpd::{Crpd, Instance};
 use async_trait::async_trait;
 use futures::StreamExt;
 use k8s_openapi::chrono::format;
 use kube::{
-    api::Api,
     client::Client,
     runtime::{
         controller::{Action, Controller as runtime_controller},
@@ -22,151 +24,154 @@ use rand::thread_rng;
 use rand::Rng;
 use rand::distributions::Alphanumeric;
 use std::sync::Arc;
-use tokio::time::Duration;
 use tracing::*;
-use kube::runtime::reflector::ObjectRef;
+use kube::runtime::reflector::{ObjectRef, Store};
 use kube::Resource;
 use k8s_openapi::apimachinery::pkg::apis::meta::v1 as meta_v1;
 use k8s_openapi::api::core::v1 as core_v1;
 
+const FIELD_MANAGER: &str = "cnm-bgp-router-group";
+
 
 pub struct BgpRouterGroupController{
     context: Arc<Context>,
-    resource: Api<BgpRouterGroup>,
 }
 
 impl BgpRouterGroupController{
     pub fn new(context: Arc<Context>) -> Self{
-        let resource = Api::all(context.client.clone());
         let context = context.clone();
-        BgpRouterGroupController{context, resource}
+        BgpRouterGroupController{context}
     }
     async fn reconcile(g: Arc<BgpRouterGroup>, ctx: Arc<Context>) ->  Result<Action, ReconcileError> {
         info!("reconciling BgpRouterGroup {:?}", g.meta().name.as_ref().unwrap().clone());
-        match controllers::get::<BgpRouterGroup>(
-            g.meta().namespace.as_ref().unwrap().clone(),
-            g.meta().name.as_ref().unwrap().clone(),
-            ctx.client.clone())
-            .await{
-            Ok(res) => {
-                match res{
-                    Some((mut bgp_router_group, _api)) => {
-                        if bgp_router_group.spec.discover{
-                            let match_labels = if let Some(match_labels) = &bgp_router_group.spec.selector.match_labels{
-                                match_labels
-                            } else {
-                                return Ok(Action::await_change())
-                            };
+        let mut bgp_router_group = g.as_ref().clone();
+        if bgp_router_group.spec.discover{
+            let crpd_list = cache::list(&ctx.cache.crpds, g.meta().namespace.as_ref().unwrap(), Some(&bgp_router_group.spec.selector))?;
 
-                            let crpd_list = match controllers::list::<Crpd>(g.meta().namespace.as_ref().unwrap().clone(),
-                            ctx.client.clone(),
-                            Some(match_labels.clone())).await{
-                                Ok(res) => {
-                                    match res{
-                                        Some((crpd_list, _api)) => {
-                                            crpd_list
+            // the status covers the instances of all selected crpds
+            let mut bgp_router_references = Vec::new();
+            let mut instance_count = 0;
+            for crpd in &crpd_list{
+                if let Some(status) = &crpd.status{
+                    if let Some(instances) = &status.instances{
+                        instance_count += instances.len();
+                        for instance in instances{
+                            let mut bgp_router_spec = bgp_router_group.spec.bgp_router_template.clone();
+                            let addresses = template_addresses(instance, &bgp_router_spec.ip_families);
+                            let address = match addresses.first(){
+                                Some(address) => address.clone(),
+                                None => {
+                                    warn!("instance {} has no address of the families {:?}", instance.name, bgp_router_spec.ip_families);
+                                    continue;
+                                }
+                            };
+                            // the router id is an IPv4 address, IPv6 only
+                            // routers keep the one of the template
+                            if let Some(ipv4_address) = family_address(instance, IpFamily::IPv4){
+                                bgp_router_spec.router_id = Some(ipv4_address);
+                            }
+                            bgp_router_spec.address = Some(address);
+                            if bgp_router_spec.ip_families.is_some(){
+                                bgp_router_spec.addresses = Some(addresses);
+                            }
+                            let mut bgp_router_labels = bgp_router_group.meta().labels.clone();
+                            bgp_router_labels.as_mut().unwrap().insert("cnm.juniper.net/bgpRouterGroup".to_string(), bgp_router_group.meta().name.as_ref().unwrap().clone());
+                            if bgp_router_spec.managed{
+                                bgp_router_labels.as_mut().unwrap().insert("cnm.juniper.net/bgpRouterManaged".to_string(), "true".to_string());
+                            }
+                            let name_namespace = format!("{}{}", instance.name, crpd.meta().namespace.as_ref().unwrap().clone());
+                            let bgp_router_name = format!("{}-{}-{}", instance.name.clone(), bgp_router_group.meta().name.as_ref().unwrap().clone(), generate_hash(&name_namespace));
+                            let bgp_router = BgpRouter{
+                                metadata: meta_v1::ObjectMeta {
+                                    name: Some(bgp_router_name),
+                                    namespace: Some(g.meta().namespace.as_ref().unwrap().clone()),
+                                    labels: bgp_router_labels,
+                                    owner_references: Some(vec![
+                                        meta_v1::OwnerReference{
+                                            api_version: "v1".to_string(),
+                                            kind: "Pod".to_string(),
+                                            name: instance.name.clone(),
+                                            uid:  instance.uuid.clone(),
+                                            ..Default::default()
                                         },
-                                        None => return Ok(Action::await_change())
-                                    }
+                                    ]),
+                                    ..Default::default()
                                 },
-                                Err(e) => return Err(e)
+                                spec: bgp_router_spec,
+                                status: None,
                             };
-
-                            for crpd in &crpd_list{
-                                if let Some(status) = &crpd.status{
-                                    if let Some(instances) = &status.instances{
-                                        let mut bgp_router_list = Vec::new();
-                                        let mut bgp_router_references = Vec::new();
-                                        for instance in instances{
-                                            let mut bgp_router_spec = bgp_router_group.spec.bgp_router_template.clone();
-                                            bgp_router_spec.address = Some(instance.address.clone());
-                                            bgp_router_spec.router_id = Some(instance.address.clone());
-                                            let mut bgp_router_labels = bgp_router_group.meta().labels.clone();
-                                            bgp_router_labels.as_mut().unwrap().insert("cnm.juniper.net/bgpRouterGroup".to_string(), bgp_router_group.meta().name.as_ref().unwrap().clone());
-                                            if bgp_router_spec.managed{
-                                                bgp_router_labels.as_mut().unwrap().insert("cnm.juniper.net/bgpRouterManaged".to_string(), "true".to_string());
-                                            }
-                                            let name_namespace = format!("{}{}", instance.name, crpd.meta().namespace.as_ref().unwrap().clone().to_string());
-                                            let bgp_router_name = format!("{}-{}-{}", instance.name.clone(), bgp_router_group.meta().name.as_ref().unwrap().clone(), generate_hash(&name_namespace));
-                                            let bgp_router = BgpRouter{
-                                                metadata: meta_v1::ObjectMeta {
-                                                    name: Some(bgp_router_name),
-                                                    namespace: Some(g.meta().namespace.as_ref().unwrap().clone()),
-                                                    labels: bgp_router_labels,
-                                                    owner_references: Some(vec![
-                                                        meta_v1::OwnerReference{
-                                                            api_version: "v1".to_string(),
-                                                            kind: "Pod".to_string(),
-                                                            name: instance.name.clone(),
-                                                            uid:  instance.uuid.clone(),
-                                                            ..Default::default()
-                                                        },
-                                                    ]),
-                                                    ..Default::default()
-                                                },
-                                                spec: bgp_router_spec,
-                                                status: None,
-                                            };
-                                            match controllers::create_or_update(bgp_router, ctx.client.clone()).await{
-                                                Ok(bgp_router) => {
-                                                    if let Some(bgp_router) = bgp_router{
-                                                        let bgp_router_reference = BgpRouterReference { 
-                                                            bgp_router_reference:  core_v1::ObjectReference{
-                                                                api_version: Some("cnm.juniper.net/v1".to_string()),
-                                                                kind: Some("BgpRouter".to_string()),
-                                                                name: Some(bgp_router.meta().name.as_ref().unwrap().clone()),
-                                                                uid: Some(bgp_router.meta().uid.as_ref().unwrap().clone()),
-                                                                ..Default::default()
-                                                            },
-                                                            local_address: bgp_router.spec.address.clone().unwrap(),
-                                                        };
-                                                        bgp_router_references.push(bgp_router_reference);
-                                                        bgp_router_list.push(bgp_router);
-                                                    }
-                                                },
-                                                Err(e) => {
-                                                    return Err(e);
-                                                }
-                                            }
+                            match controllers::apply(bgp_router, FIELD_MANAGER, false, ctx.client.clone()).await{
+                                Ok(bgp_router) => {
+                                    if let Some(bgp_router) = bgp_router{
+                                        let known = match &bgp_router_group.status{
+                                            Some(status) => status.bgp_router_references.iter().any(|bgp_router_reference| {
+                                                bgp_router_reference.bgp_router_reference.name == bgp_router.meta().name
+                                            }),
+                                            None => false,
+                                        };
+                                        if !known{
+                                            controllers::publish_event(&bgp_router_group, &ctx, EventType::Normal, "BgpRouterDiscovered", "DiscoverBgpRouter",
+                                                format!("discovered BgpRouter {} for {}", bgp_router.meta().name.as_ref().unwrap(), instance.name)).await;
                                         }
-                                        if bgp_router_group.status.is_some(){
-                                            bgp_router_group.status.as_mut().unwrap().bgp_router_references = bgp_router_references.clone();
-                                        } else {
-                                            bgp_router_group.status = Some(BgpRouterGroupStatus{
-                                                bgp_router_references: bgp_router_references.clone(),
-                                            });
-                                        }  
-                                        match controllers::update_status(bgp_router_group.clone(), ctx.client.clone()).await {
-                                            Ok(_) => {
-
+                                        let bgp_router_reference = BgpRouterReference { 
+                                            bgp_router_reference:  core_v1::ObjectReference{
+                                                api_version: Some("cnm.juniper.net/v1".to_string()),
+                                                kind: Some("BgpRouter".to_string()),
+                                                name: Some(bgp_router.meta().name.as_ref().unwrap().clone()),
+                                                uid: Some(bgp_router.meta().uid.as_ref().unwrap().clone()),
+                                                ..Default::default()
                                             },
-                                            Err(e) => {
-                                                return Err(e);
-                                            }
-                                        }
+                                            local_address: bgp_router.spec.address.clone().unwrap(),
+                                            local_addresses: bgp_router.spec.addresses.clone().unwrap_or_default(),
+                                        };
+                                        bgp_router_references.push(bgp_router_reference);
                                     }
+                                },
+                                Err(e) => {
+                                    return Err(e);
                                 }
-                            }                   
-                            Ok(Action::await_change())
-                        } else {
-                            Ok(Action::await_change())
+                            }
                         }
-                    },
-                    None => {
-                        info!("crpd bgp_router_group does not exist");
-                        Ok(Action::await_change())
                     }
-                    
                 }
-            },
-            Err(e) => {
-                return Err(e);
-            },
+            }
+            let generation = bgp_router_group.meta().generation;
+            let ready = bgp_router_references.len() == instance_count;
+            let message = format!("{}/{} BgpRouters discovered", bgp_router_references.len(), instance_count);
+            let status = bgp_router_group.status.get_or_insert_with(BgpRouterGroupStatus::default);
+            status.bgp_router_references = bgp_router_references;
+            status.observed_generation = generation;
+            let mut conditions = status.conditions.clone().unwrap_or_default();
+            controllers::set_condition(&mut conditions, controllers::CONDITION_READY, ready,
+                if ready { "BgpRoutersDiscovered" } else { "BgpRoutersPending" }, message.clone(), generation);
+            controllers::set_condition(&mut conditions, controllers::CONDITION_PROGRESSING, !ready,
+                if ready { "BgpRoutersDiscovered" } else { "BgpRoutersPending" }, message, generation);
+            controllers::set_condition(&mut conditions, controllers::CONDITION_DEGRADED, false,
+                "ReconcileSucceeded", "".to_string(), generation);
+            status.conditions = Some(conditions);
+            controllers::update_status(bgp_router_group.clone(), ctx.client.clone()).await?;
+            Ok(Action::await_change())
+        } else {
+            Ok(Action::await_change())
         }
     }
-    fn error_policy(g: Arc<BgpRouterGroup>, error: &ReconcileError, _ctx: Arc<Context>) -> Action {
+    fn error_policy(g: Arc<BgpRouterGroup>, error: &ReconcileError, ctx: Arc<Context>) -> Action {
         warn!("reconcile failed: {:?}", error);
-        Action::requeue(Duration::from_secs(5 * 60))
+        metrics::metrics().reconcile_failure("bgp_router_group");
+        let action = controllers::error_action("bgp_router_group", g.as_ref(), error, &ctx);
+        let note = error.to_string();
+        let reason = error.reason();
+        tokio::spawn(async move {
+            controllers::publish_event(g.as_ref(), &ctx, EventType::Warning, "ReconcileFailed", "Reconcile", note.clone()).await;
+            controllers::set_degraded(g.as_ref(), reason, note, &ctx).await;
+        });
+        action
+    }
+}
+
+impl controllers::Conditions for BgpRouterGroup{
+    fn conditions_mut(&mut self) -> &mut Vec<meta_v1::Condition>{
+        self.status.get_or_insert_with(Default::default).conditions.get_or_insert_with(Vec::new)
     }
 }
 
@@ -174,27 +179,37 @@ impl BgpRouterGroupController{
 impl Controller for BgpRouterGroupController{
     async fn run(&self) -> anyhow::Result<()>{
         let reconcile = |g: Arc<BgpRouterGroup>, ctx: Arc<Context>| {
-            async move { BgpRouterGroupController::reconcile(g, ctx).await }
+            async move {
+                let _measurer = metrics::metrics().reconcile_start("bgp_router_group");
+                let key = controllers::backoff_key("bgp_router_group", g.as_ref());
+                // a failed object is only reconciled again once its backoff
+                // passed or its spec changed
+                if let Some(pending) = ctx.backoff.pending(&key, g.meta().generation){
+                    return Ok(Action::requeue(pending));
+                }
+                let res = BgpRouterGroupController::reconcile(g, ctx.clone()).await;
+                if res.is_ok(){
+                    ctx.backoff.reset(&key);
+                }
+                res
+            }
         };
         let error_policy = |g: Arc<BgpRouterGroup>, error: &ReconcileError, ctx: Arc<Context>| {
             BgpRouterGroupController::error_policy(g, error, ctx)
         };
         let mut config = Config::default();
         config.label_selector = Some("cnm.juniper.net/bgpRouterType=Crpd".to_string());
-        runtime_controller::new(self.resource.clone(), config.clone())
-            .watches(
-                Api::<Crpd>::all(self.context.client.clone()),
-                Config::default(),
-                |crpd| {
+        let bgp_router_groups = self.context.cache.bgp_router_groups.clone();
+        let controller = controllers::new_controller::<BgpRouterGroup>(&self.context, config.clone())
+            .watches_stream(
+                cache::applied_objects(&self.context.cache.crpds, &self.context.cache.applied_crpds),
+                move |crpd| {
                     info!("crpd event in bgp_router_group controller:");
-                    Some(ObjectRef::<BgpRouterGroup>::new(
-                        crpd.meta().name.as_ref().unwrap())
-                        .within(crpd.meta().namespace.as_ref().unwrap()))
+                    selecting_groups(&bgp_router_groups, &crpd)
                 }
             )
-            .watches(
-                Api::<BgpRouter>::all(self.context.client.clone()),
-                Config::default(),
+            .watches_stream(
+                controllers::watch_stream::<BgpRouter>(&self.context, Config::default()),
                 |bgp_router| {
                     info!("crpd event in bgp_router_group controller:");
                     match &bgp_router.meta().labels{
@@ -215,7 +230,10 @@ impl Controller for BgpRouterGroupController{
                         }
                     }
                 }
-            )
+            );
+        health::track("bgp_router_group", controller.store());
+        controller
+            .graceful_shutdown_on(health::health().shutdown_requested())
             .run(reconcile, error_policy, self.context.clone())
             .for_each(|res| async move {
                 match res {
@@ -224,10 +242,53 @@ impl Controller for BgpRouterGroupController{
                 }
             })
             .await;
+        health::health().stopped("bgp_router_group");
         Ok(())
     }
 }
 
+// selecting_groups returns the BgpRouterGroups whose selector matches the crpd
+fn selecting_groups(bgp_router_groups: &Store<BgpRouterGroup>, crpd: &Crpd) -> Vec<ObjectRef<BgpRouterGroup>>{
+    bgp_router_groups.state().iter()
+        .filter(|bgp_router_group| bgp_router_group.meta().namespace == crpd.meta().namespace)
+        .filter(|bgp_router_group| {
+            match controllers::selector_matches(&bgp_router_group.spec.selector, crpd.meta().labels.as_ref()){
+                Ok(matches) => matches,
+                Err(e) => {
+                    warn!("invalid selector in BgpRouterGroup {:?}: {:?}", bgp_router_group.meta().name, e);
+                    false
+                }
+            }
+        })
+        .map(|bgp_router_group| ObjectRef::from_obj(bgp_router_group.as_ref()))
+        .collect()
+}
+
+// family_address returns the address of the instance in the family. Instances
+// reported before the addresses were recorded only have their primary address.
+fn family_address(instance: &Instance, family: IpFamily) -> Option<String>{
+    if instance.addresses.is_empty(){
+        if IpFamily::of(&instance.address) == Some(family){
+            return Some(instance.address.clone());
+        }
+        return None;
+    }
+    instance.addresses.iter()
+        .find(|address| address.family == family)
+        .map(|address| address.address.clone())
+}
+
+// template_addresses returns the addresses of the instance in the families
+// of the template, the primary address when the template selects none
+fn template_addresses(instance: &Instance, ip_families: &Option<Vec<IpFamily>>) -> Vec<String>{
+    match ip_families{
+        Some(ip_families) => ip_families.iter()
+            .filter_map(|family| family_address(instance, *family))
+            .collect(),
+        None => vec![instance.address.clone()],
+    }
+}
+
 fn generate_hash(input: &str) -> String {
     let mut context = ring_context::new(&SHA512);
     context.update(input.as_bytes());
@@ -236,3 +297,148 @@ fn generate_hash(input: &str) -> String {
     let hash = hex[..8].to_string();
     hash
 }
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::controllers::test_utils::{self, ApiServer};
+    use crate::resources::crpd::crpd::InstanceAddress;
+    use http::Method;
+    use std::collections::BTreeMap;
+
+    #[tokio::test]
+    async fn reconcile_discovers_bgp_routers_for_crpd_instances(){
+        let server = ApiServer::new();
+        let bgp_router_group = test_utils::bgp_router_group("group1", true);
+        server.add(&bgp_router_group);
+        server.add(&test_utils::crpd_with_instances("crpd1", vec![("crpd1-0", "10.0.0.1"), ("crpd1-1", "10.0.0.2")]));
+
+        let action = BgpRouterGroupController::reconcile(Arc::new(bgp_router_group), server.context()).await.unwrap();
+        assert_eq!(action, Action::await_change());
+
+        let bgp_routers = server.list::<BgpRouter>();
+        assert_eq!(bgp_routers.len(), 2);
+        for bgp_router in &bgp_routers{
+            let labels = bgp_router.meta().labels.as_ref().unwrap();
+            assert_eq!(labels.get("cnm.juniper.net/bgpRouterGroup"), Some(&"group1".to_string()));
+            assert_eq!(bgp_router.spec.address, bgp_router.spec.router_id);
+        }
+        assert_eq!(server.event_reasons(), vec!["BgpRouterDiscovered", "BgpRouterDiscovered"]);
+
+        let status = server.get::<BgpRouterGroup>("group1").unwrap().status.unwrap();
+        let mut addresses: Vec<String> = status.bgp_router_references.iter().map(|reference| reference.local_address.clone()).collect();
+        addresses.sort();
+        assert_eq!(addresses, vec!["10.0.0.1", "10.0.0.2"]);
+        assert_eq!(test_utils::condition_status(&status.conditions, controllers::CONDITION_READY), Some("True"));
+    }
+
+    #[tokio::test]
+    async fn reconcile_reports_bgp_routers_of_all_selected_crpds(){
+        let server = ApiServer::new();
+        let bgp_router_group = test_utils::bgp_router_group("group1", true);
+        server.add(&bgp_router_group);
+        server.add(&test_utils::crpd_with_instances("crpd1", vec![("crpd1-0", "10.0.0.1")]));
+        server.add(&test_utils::crpd_with_instances("crpd2", vec![("crpd2-0", "10.0.0.2")]));
+
+        BgpRouterGroupController::reconcile(Arc::new(bgp_router_group), server.context()).await.unwrap();
+
+        let status = server.get::<BgpRouterGroup>("group1").unwrap().status.unwrap();
+        let mut addresses: Vec<String> = status.bgp_router_references.iter().map(|reference| reference.local_address.clone()).collect();
+        addresses.sort();
+        assert_eq!(addresses, vec!["10.0.0.1", "10.0.0.2"]);
+        assert_eq!(test_utils::condition_status(&status.conditions, controllers::CONDITION_READY), Some("True"));
+        assert_eq!(server.requests(Method::PUT).iter().filter(|path| path.ends_with("/bgproutergroups/group1/status")).count(), 1);
+    }
+
+    #[tokio::test]
+    async fn reconcile_discovers_dual_stack_bgp_routers(){
+        let server = ApiServer::new();
+        let mut bgp_router_group = test_utils::bgp_router_group("group1", true);
+        bgp_router_group.spec.bgp_router_template.ip_families = Some(vec![IpFamily::IPv6, IpFamily::IPv4]);
+        server.add(&bgp_router_group);
+        let mut crpd = test_utils::crpd_with_instances("crpd1", vec![("crpd1-0", "10.0.0.1")]);
+        crpd.status.as_mut().unwrap().instances.as_mut().unwrap()[0].addresses = vec![
+            InstanceAddress{address: "10.0.0.1".to_string(), family: IpFamily::IPv4},
+            InstanceAddress{address: "fd00::1".to_string(), family: IpFamily::IPv6},
+        ];
+        server.add(&crpd);
+
+        BgpRouterGroupController::reconcile(Arc::new(bgp_router_group), server.context()).await.unwrap();
+
+        let bgp_router = &server.list::<BgpRouter>()[0];
+        assert_eq!(bgp_router.spec.address, Some("fd00::1".to_string()));
+        assert_eq!(bgp_router.spec.router_id, Some("10.0.0.1".to_string()));
+        assert_eq!(bgp_router.spec.addresses, Some(vec!["fd00::1".to_string(), "10.0.0.1".to_string()]));
+        let status = server.get::<BgpRouterGroup>("group1").unwrap().status.unwrap();
+        assert_eq!(status.bgp_router_references[0].local_addresses, vec!["fd00::1", "10.0.0.1"]);
+    }
+
+    #[tokio::test]
+    async fn reconcile_only_discovers_selected_crpds(){
+        let server = ApiServer::new();
+        let bgp_router_group = test_utils::bgp_router_group("group1", true);
+        server.add(&bgp_router_group);
+        let mut crpd = test_utils::crpd_with_instances("crpd1", vec![("crpd1-0", "10.0.0.1")]);
+        crpd.metadata.labels = None;
+        server.add(&crpd);
+
+        BgpRouterGroupController::reconcile(Arc::new(bgp_router_group), server.context()).await.unwrap();
+        assert!(server.list::<BgpRouter>().is_empty());
+    }
+
+    #[test]
+    fn crpd_events_map_to_selecting_groups(){
+        let (reader, mut writer) = kube::runtime::reflector::store();
+        let mut other_group = test_utils::bgp_router_group("group2", true);
+        other_group.spec.selector.match_labels = Some(BTreeMap::from([("app".to_string(), "other".to_string())]));
+        writer.apply_watcher_event(&kube::runtime::watcher::Event::Restarted(vec![
+            test_utils::bgp_router_group("group1", true),
+            other_group,
+        ]));
+
+        let refs = selecting_groups(&reader, &test_utils::crpd("crpd1", 1));
+        assert_eq!(refs, vec![ObjectRef::new("group1").within(test_utils::NAMESPACE)]);
+    }
+
+    #[tokio::test]
+    async fn reconcile_skips_discovery_when_disabled(){
+        let server = ApiServer::new();
+        let bgp_router_group = test_utils::bgp_router_group("group1", false);
+        server.add(&bgp_router_group);
+        server.add(&test_utils::crpd_with_instances("crpd1", vec![("crpd1-0", "10.0.0.1")]));
+
+        let action = BgpRouterGroupController::reconcile(Arc::new(bgp_router_group), server.context()).await.unwrap();
+        assert_eq!(action, Action::await_change());
+        assert!(server.list::<BgpRouter>().is_empty());
+        assert!(server.get::<BgpRouterGroup>("group1").unwrap().status.is_none());
+    }
+
+    #[tokio::test]
+    async fn reconcile_fails_when_bgp_router_apply_fails(){
+        let server = ApiServer::new();
+        let bgp_router_group = test_utils::bgp_router_group("group1", true);
+        server.add(&bgp_router_group);
+        server.add(&test_utils::crpd_with_instances("crpd1", vec![("crpd1-0", "10.0.0.1")]));
+        server.fail(Method::PATCH, "/apis/cnm.juniper.net/v1/namespaces/default/bgprouters");
+
+        let res = BgpRouterGroupController::reconcile(Arc::new(bgp_router_group), server.context()).await;
+        assert!(matches!(res, Err(ReconcileError::TransientApi(_))));
+        assert!(server.get::<BgpRouterGroup>("group1").unwrap().status.is_none());
+    }
+
+    #[tokio::test]
+    async fn reconcile_rejects_invalid_selector(){
+        let server = ApiServer::new();
+        let mut bgp_router_group = test_utils::bgp_router_group("group1", true);
+        bgp_router_group.spec.selector.match_expressions = Some(vec![meta_v1::LabelSelectorRequirement{
+            key: "app".to_string(),
+            operator: "In".to_string(),
+            values: None,
+        }]);
+        server.add(&bgp_router_group);
+        server.add(&test_utils::crpd("crpd1", 1));
+
+        let res = BgpRouterGroupController::reconcile(Arc::new(bgp_router_group), server.context()).await;
+        assert!(matches!(res, Err(ReconcileError::InvalidSpec(_))));
+    }
+}
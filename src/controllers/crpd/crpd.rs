@@ -1,222 +1,388 @@
 use crate::controllers::controllers::{Controller, Context, ReconcileError, self};
-use crate::resources::crpd::crpd::{Crpd, CrpdStatus, Instance};
+use crate::controllers::cache;
+use crate::controllers::crpd::upgrade::{self, Upgrade};
+use crate::controllers::crpd::license;
+use crate::controllers::crpd::instance;
+use crate::metrics::metrics;
+use crate::health::health;
+use crate::config::config::OperatorConfig;
+use kube::runtime::events::EventType;
+use crate::resources::crpd::crpd::{Crpd, CrpdMode, CrpdSpec, CrpdStatus, Instance, InstanceAddress};
+use crate::resources::bgp_router::IpFamily;
 use async_trait::async_trait;
 use futures::StreamExt;
 use kube::Resource;
+use kube::api::DeleteParams;
 use kube::runtime::reflector::ObjectRef;
 use kube::{
-    api::Api,
     client::Client,
     runtime::{
         controller::{Action, Controller as runtime_controller},
-        watcher::Config,
     },
 };
 use std::collections::BTreeMap;
 use std::sync::Arc;
-use tokio::time::Duration;
+use std::time::Duration;
 use tracing::*;
 use k8s_openapi::api::apps::v1 as apps_v1;
 use k8s_openapi::api::core::v1 as core_v1;
+use k8s_openapi::apimachinery::pkg::api::resource::Quantity;
 use k8s_openapi::apimachinery::pkg::apis::meta::v1 as meta_v1;
+use k8s_openapi::apimachinery::pkg::util::intstr::IntOrString;
+use k8s_openapi::api::policy::v1 as policy_v1;
 use k8s_openapi::api::rbac::v1 as rbac_v1;
+use k8s_openapi::NamespaceResourceScope;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::fmt::Debug;
 
 //rbac.authorization.k8s.io/v1
 
+const FIELD_MANAGER: &str = "cnm-crpd";
+const CA_READER_ROLE: &str = "crpd-ca-reader";
+const UPGRADE_REQUEUE: Duration = Duration::from_secs(10);
+
 pub struct CrpdController{
     context: Arc<Context>,
-    resource: Api<Crpd>,
 }
 
 impl CrpdController{
     pub fn new(context: Arc<Context>) -> Self{
-        let resource = Api::all(context.client.clone());
         let context = context.clone();
-        CrpdController{context, resource}
+        CrpdController{context}
     }
     async fn reconcile(g: Arc<Crpd>, ctx: Arc<Context>) ->  Result<Action, ReconcileError> {
-        match controllers::get::<Crpd>(g.meta().namespace.as_ref().unwrap().clone(),
-            g.meta().name.as_ref().unwrap().clone(),
-            ctx.client.clone())
-            .await{
-            Ok(res) => {
-                match res{
-                    Some((mut crpd, _crpd_api)) => {
-                        let sts = apps_v1::StatefulSet::from(crpd.clone());
-                        match controllers::create_or_update(sts.clone(), ctx.client.clone()).await{
-                            Ok(sts) => {
-                                info!("sts created");
-                            },
-                            Err(e) => {
-                                return Err(e);
-                            },
-                        }
-                        match controllers::get::<apps_v1::StatefulSet>(sts.meta().namespace.as_ref().unwrap().clone(),
-                            sts.meta().name.as_ref().unwrap().clone(),
-                            ctx.client.clone())
-                            .await{
-                            Ok(res) => {
-                                match res{
-                                    Some((sts, _)) => {
-                                        let status = match crpd.clone().status{
-                                            Some(mut status) => {
-                                                status.stateful_set = sts.status.clone();
-                                                status
-                                            },
-                                            None => {
-                                                let status = Some(CrpdStatus{
-                                                    stateful_set: sts.status.clone(),
-                                                    ..Default::default()
-                                                });
-                                                status.unwrap()
-                                            },
-                                        };
-                                        crpd.status = Some(status.clone());
-                                        let labels = BTreeMap::from_iter(vec![
-                                            ("app".to_string(), "crpd".to_string()),
-                                            ("crpd".to_string(), crpd.metadata.name.as_ref().unwrap().clone()),
-                                        ]);
-                                        let mut instances = Vec::new();
-                                        match controllers::list::<core_v1::Pod>(
-                                            sts.meta().namespace.as_ref().unwrap().clone(),
-                                            ctx.client.clone(),
-                                            Some(labels))
-                                            .await{
-                                                Ok(pod_list) => {
-                                                    match pod_list {
-                                                        Some((pod_list, _)) => {
-                                                            for pod in pod_list.items{
-                                                                if pod.status.as_ref().unwrap().pod_ip.is_some(){
-                                                                    let instance = Instance{
-                                                                        name: pod.meta().name.as_ref().unwrap().clone(),
-                                                                        address: pod.status.as_ref().unwrap().pod_ip.as_ref().unwrap().clone(),
-                                                                        uuid: pod.meta().uid.as_ref().unwrap().clone(),
-                                                                    };
-                                                                    instances.push(instance);
-                                                                }
-                                                            }
-                                                        },
-                                                        None => {},
-                                                    }
-                                                },
-                                                Err(e) => {
-                                                    return Err(e);
-                                                },
-                                        }
-                                        crpd.status.as_mut().unwrap().instances = Some(instances);                                        
-                                        match controllers::update_status(crpd, ctx.client.clone()).await{
-                                            Ok(crpd) => {
-                                            },
-                                            Err(e) => {
-                                                return Err(e);
-                                            },
-                                        }
-                                    },
-                                    None => {},
-                                }
-                            },
-                            Err(e) => {
-                                return Err(e);
-                            },
+        let mut crpd = g.as_ref().clone();
+        // a Crpd with an invalid spec must still be deletable
+        if crpd.meta().deletion_timestamp.is_some(){
+            return CrpdController::cleanup(crpd, ctx).await;
+        }
+        if crpd.spec.replicas < 0{
+            return Err(ReconcileError::InvalidSpec(anyhow::anyhow!("replicas must not be negative, got {}", crpd.spec.replicas)));
+        }
+        validate_pod_template(&crpd.spec)?;
+        controllers::add_finalizer(&crpd, controllers::CLEANUP_FINALIZER, ctx.client.clone()).await?;
+        CrpdController::apply_rbac(&crpd, &ctx).await?;
+        let pdb = policy_v1::PodDisruptionBudget::from(crpd.clone());
+        controllers::apply(pdb, FIELD_MANAGER, true, ctx.client.clone()).await?;
+        let namespace = crpd.meta().namespace.as_ref().unwrap().clone();
+        let name = crpd.meta().name.as_ref().unwrap().clone();
+        // the workload of the other mode is removed when the mode changes
+        let (ready_instances, desired_instances, sts) = match crpd.spec.mode{
+            CrpdMode::StatefulSet => {
+                if cache::get(&ctx.cache.daemon_sets, &namespace, &name).is_some(){
+                    controllers::delete::<apps_v1::DaemonSet>(namespace.clone(), name.clone(), ctx.client.clone()).await?;
+                }
+                let mut sts = apps_v1::StatefulSet::from(crpd.clone());
+                if let Some(spec) = sts.spec.as_mut(){
+                    operator_env(&mut spec.template, &ctx.config);
+                }
+                let existing = cache::get(&ctx.cache.stateful_sets, &namespace, &name);
+                // volumeClaimTemplates are immutable. Adding or removing the
+                // config storage recreates the StatefulSet, its pods are kept
+                // and rolled by the upgrade. The claims of existing pods keep
+                // their size and class, so changing those is refused.
+                if let Some(existing) = &existing{
+                    let (existing_claims, claims) = (volume_claims(existing), volume_claims(&sts));
+                    if existing_claims != claims{
+                        if !existing_claims.is_empty() && !claims.is_empty(){
+                            return Err(ReconcileError::InvalidSpec(anyhow::anyhow!(
+                                "configStorage can't be changed once claimed, resize the PersistentVolumeClaims instead")));
                         }
-                        Ok(Action::await_change())
-                    },
-                    None => {
-                        info!("crpd does not exist");
-                        Ok(Action::await_change())
-                    },
+                        controllers::delete_with::<apps_v1::StatefulSet>(namespace.clone(), name.clone(), &DeleteParams::orphan(), ctx.client.clone()).await?;
+                        controllers::publish_event(&crpd, &ctx, EventType::Normal, "StatefulSetDeleted", "DeleteStatefulSet",
+                            format!("deleted StatefulSet {} to change its config storage, its pods are kept", name)).await;
+                        return Ok(Action::await_change());
+                    }
+                }
+                let exists = existing.is_some();
+                let sts = match CrpdController::apply_workload(&crpd, sts, exists, &ctx).await?{
+                    Some(sts) => sts,
+                    None => return Ok(Action::await_change()),
+                };
+                let status = crpd.status.get_or_insert_with(CrpdStatus::default);
+                status.stateful_set = sts.status.clone();
+                status.daemon_set = None;
+                let ready_replicas = match &sts.status{
+                    Some(sts_status) => sts_status.ready_replicas.unwrap_or(0),
+                    None => 0,
+                };
+                (ready_replicas, crpd.spec.replicas, Some(sts))
+            },
+            CrpdMode::DaemonSet => {
+                if cache::get(&ctx.cache.stateful_sets, &namespace, &name).is_some(){
+                    controllers::delete::<apps_v1::StatefulSet>(namespace.clone(), name.clone(), ctx.client.clone()).await?;
                 }
+                let mut ds = apps_v1::DaemonSet::from(crpd.clone());
+                if let Some(spec) = ds.spec.as_mut(){
+                    operator_env(&mut spec.template, &ctx.config);
+                }
+                let exists = cache::get(&ctx.cache.daemon_sets, &namespace, &name).is_some();
+                let ds = match CrpdController::apply_workload(&crpd, ds, exists, &ctx).await?{
+                    Some(ds) => ds,
+                    None => return Ok(Action::await_change()),
+                };
+                let status = crpd.status.get_or_insert_with(CrpdStatus::default);
+                status.daemon_set = ds.status.clone();
+                status.stateful_set = None;
+                match &ds.status{
+                    Some(ds_status) => (ds_status.number_ready, ds_status.desired_number_scheduled, None),
+                    None => (0, 0, None),
+                }
+            },
+        };
+        let labels = BTreeMap::from_iter(vec![
+            ("app".to_string(), "crpd".to_string()),
+            ("crpd".to_string(), crpd.metadata.name.as_ref().unwrap().clone()),
+        ]);
+        let mut instances = Vec::new();
+        let pod_list = cache::list(&ctx.cache.pods, &namespace, Some(&meta_v1::LabelSelector{
+            match_labels: Some(labels),
+            ..Default::default()
+        }))?;
+        for pod in &pod_list{
+            if let Some(pod_ip) = pod.status.as_ref().and_then(|status| status.pod_ip.clone()){
+                let instance = Instance{
+                    name: pod.meta().name.as_ref().unwrap().clone(),
+                    addresses: pod_addresses(pod, &pod_ip),
+                    address: pod_ip,
+                    uuid: pod.meta().uid.as_ref().unwrap().clone(),
+                    license: None,
+                    operational: None,
+                };
+                instances.push(instance);
+            }
+        }
+        let license = license::license(&crpd, &ctx).await?;
+        instance::operational(&mut instances, &license, &ctx).await;
+        let upgrade = match &sts{
+            Some(sts) => upgrade::upgrade(&mut crpd, sts, &pod_list, &instances, &ctx).await?,
+            None => Upgrade::Complete,
+        };
+        let ready = ready_instances == desired_instances;
+        let generation = crpd.meta().generation;
+        let has_instances = !instances.is_empty();
+        let status = crpd.status.as_mut().unwrap();
+        status.instances = Some(instances);
+        status.observed_generation = generation;
+        let mut conditions = status.conditions.clone().unwrap_or_default();
+        let upgrade_failed = conditions.iter().any(|condition| condition.type_ == controllers::CONDITION_DEGRADED && condition.reason == "UpgradeFailed");
+        let message = format!("{}/{} instances ready", ready_instances, desired_instances);
+        controllers::set_condition(&mut conditions, controllers::CONDITION_READY, ready,
+            if ready { "InstancesReady" } else { "InstancesNotReady" }, message.clone(), generation);
+        match &upgrade{
+            Upgrade::Complete => {
+                controllers::set_condition(&mut conditions, controllers::CONDITION_PROGRESSING, !ready,
+                    if ready { "RolloutComplete" } else { "RolloutInProgress" }, message, generation);
+                controllers::set_condition(&mut conditions, controllers::CONDITION_DEGRADED, false,
+                    "ReconcileSucceeded", "".to_string(), generation);
             },
-            Err(e) => {
-                return Err(e);
+            Upgrade::InProgress(upgrade_message) => {
+                controllers::set_condition(&mut conditions, controllers::CONDITION_PROGRESSING, true,
+                    "UpgradeInProgress", upgrade_message.clone(), generation);
+                controllers::set_condition(&mut conditions, controllers::CONDITION_DEGRADED, false,
+                    "ReconcileSucceeded", "".to_string(), generation);
+            },
+            Upgrade::Failed(upgrade_message) => {
+                controllers::set_condition(&mut conditions, controllers::CONDITION_PROGRESSING, false,
+                    "UpgradeHalted", upgrade_message.clone(), generation);
+                controllers::set_condition(&mut conditions, controllers::CONDITION_DEGRADED, true,
+                    "UpgradeFailed", upgrade_message.clone(), generation);
+                if !upgrade_failed{
+                    controllers::publish_event(g.as_ref(), &ctx, EventType::Warning, "UpgradeFailed", "UpgradeInstance",
+                        upgrade_message.clone()).await;
+                }
             },
         }
+        status.conditions = Some(conditions);
+        controllers::update_status(crpd, ctx.client.clone()).await?;
+        match upgrade{
+            // the BgpRouters of the replaced instance aren't watched
+            Upgrade::InProgress(_) | Upgrade::Failed(_) => Ok(Action::requeue(UPGRADE_REQUEUE)),
+            // the operational state of the instances changes without any event
+            Upgrade::Complete if has_instances => Ok(Action::requeue(Duration::from_secs(ctx.config.jet.status_interval_seconds))),
+            Upgrade::Complete => Ok(Action::await_change()),
+        }
     }
-    fn error_policy(g: Arc<Crpd>, error: &ReconcileError, ctx: Arc<Context>) -> Action {
-        warn!("reconcile failed: {:?}", error);
-        Action::requeue(Duration::from_secs(5 * 60))
-    }
-}
+    // apply_rbac gives the pods of the Crpd their own ServiceAccount, allowed
+    // to manage their certificate secrets, named after the pods, and to read
+    // the CA secret in the operator namespace
+    async fn apply_rbac(crpd: &Crpd, ctx: &Context) -> Result<(), ReconcileError> {
+        let namespace = crpd.meta().namespace.as_ref().unwrap().clone();
+        let name = service_account_name(crpd);
+        let service_account = core_v1::ServiceAccount{
+            metadata: meta_v1::ObjectMeta{
+                name: Some(name.clone()),
+                namespace: Some(namespace.clone()),
+                owner_references: Some(vec![owner_reference(crpd)]),
+                ..Default::default()
+            },
+            automount_service_account_token: Some(true),
+            ..Default::default()
+        };
+        controllers::apply(service_account, FIELD_MANAGER, true, ctx.client.clone()).await?;
 
-#[async_trait]
-impl Controller for CrpdController{
-    async fn run(&self) -> anyhow::Result<()>{
         let role = rbac_v1::Role{
             metadata: meta_v1::ObjectMeta{
-                name: Some("crpd".to_string()),
-                namespace: Some("default".to_string()),
+                name: Some(name.clone()),
+                namespace: Some(namespace.clone()),
+                owner_references: Some(vec![owner_reference(crpd)]),
                 ..Default::default()
             },
-            rules: Some(vec![rbac_v1::PolicyRule{
-                api_groups: Some(vec!["*".to_string()]),
-                resources: Some(vec!["*".to_string()]),
-                verbs: vec!["*".to_string()],
-                ..Default::default()
-            }]),
-            ..Default::default()   
+            rules: Some(secret_rules(pod_names(crpd, ctx)?)),
         };
-        controllers::create_or_update(role, self.context.client.clone()).await?;
+        controllers::apply(role, FIELD_MANAGER, true, ctx.client.clone()).await?;
 
-        let service_account = core_v1::ServiceAccount{
-            metadata: meta_v1::ObjectMeta {
-                name: Some("crpd".to_string()),
-                namespace: Some("default".to_string()),
+        let role_binding = rbac_v1::RoleBinding{
+            metadata: meta_v1::ObjectMeta{
+                name: Some(name.clone()),
+                namespace: Some(namespace.clone()),
+                owner_references: Some(vec![owner_reference(crpd)]),
                 ..Default::default()
             },
-            automount_service_account_token: Some(true),
-            ..Default::default()
+            role_ref: rbac_v1::RoleRef{
+                api_group: "rbac.authorization.k8s.io".to_string(),
+                kind: "Role".to_string(),
+                name: name.clone(),
+            },
+            subjects: Some(vec![rbac_v1::Subject{
+                kind: "ServiceAccount".to_string(),
+                name: name.clone(),
+                namespace: Some(namespace.clone()),
+                ..Default::default()
+            }]),
         };
-        controllers::create_or_update(service_account, self.context.client.clone()).await?;
+        controllers::apply(role_binding, FIELD_MANAGER, true, ctx.client.clone()).await?;
 
-        let role_binding = rbac_v1::RoleBinding{
+        // owner references can't cross namespaces, the binding to the CA
+        // reader role is removed by cleanup
+        let ca_role_binding = rbac_v1::RoleBinding{
             metadata: meta_v1::ObjectMeta{
-                name: Some("crpd".to_string()),
-                namespace: Some("default".to_string()),
+                name: Some(ca_role_binding_name(crpd)),
+                namespace: Some(ctx.config.namespace.clone()),
                 ..Default::default()
             },
             role_ref: rbac_v1::RoleRef{
                 api_group: "rbac.authorization.k8s.io".to_string(),
                 kind: "Role".to_string(),
-                name: "crpd".to_string(),
+                name: CA_READER_ROLE.to_string(),
             },
             subjects: Some(vec![rbac_v1::Subject{
                 kind: "ServiceAccount".to_string(),
-                name: "crpd".to_string(),
-                namespace: Some("default".to_string()),
+                name,
+                namespace: Some(namespace),
                 ..Default::default()
             }]),
         };
-        controllers::create_or_update(role_binding, self.context.client.clone()).await?;
+        controllers::apply(ca_role_binding, FIELD_MANAGER, true, ctx.client.clone()).await?;
+        Ok(())
+    }
+    // cleanup removes the CA reader binding of a deleted Crpd, everything
+    // else is owned by the Crpd and garbage collected
+    async fn cleanup(crpd: Crpd, ctx: Arc<Context>) -> Result<Action, ReconcileError> {
+        if !controllers::has_finalizer(&crpd, controllers::CLEANUP_FINALIZER){
+            return Ok(Action::await_change())
+        }
+        info!("cleaning up Crpd {:?}", crpd.meta().name.as_ref().unwrap().clone());
+        controllers::delete::<rbac_v1::RoleBinding>(ctx.config.namespace.clone(), ca_role_binding_name(&crpd), ctx.client.clone()).await?;
+        controllers::remove_finalizer(&crpd, controllers::CLEANUP_FINALIZER, ctx.client.clone()).await?;
+        Ok(Action::await_change())
+    }
+    // apply_workload applies the StatefulSet or DaemonSet of the Crpd and
+    // reports its creation
+    async fn apply_workload<K>(crpd: &Crpd, workload: K, exists: bool, ctx: &Context) -> Result<Option<K>, ReconcileError>
+    where
+    K: Resource<DynamicType = (), Scope = NamespaceResourceScope> + Clone + DeserializeOwned + Debug + Serialize,
+    {
+        let kind = K::kind(&()).to_string();
+        let applied = controllers::apply(workload.clone(), FIELD_MANAGER, true, ctx.client.clone()).await?;
+        info!("{} applied", kind);
+        if !exists{
+            controllers::publish_event(crpd, ctx, EventType::Normal, &format!("{}Created", kind), &format!("Create{}", kind),
+                format!("created {} {}", kind, workload.meta().name.as_ref().unwrap())).await;
+        }
+        Ok(applied)
+    }
+    fn error_policy(g: Arc<Crpd>, error: &ReconcileError, ctx: Arc<Context>) -> Action {
+        warn!("reconcile failed: {:?}", error);
+        metrics::metrics().reconcile_failure("crpd");
+        let action = controllers::error_action("crpd", g.as_ref(), error, &ctx);
+        let note = error.to_string();
+        let reason = error.reason();
+        tokio::spawn(async move {
+            controllers::publish_event(g.as_ref(), &ctx, EventType::Warning, "ReconcileFailed", "Reconcile", note.clone()).await;
+            controllers::set_degraded(g.as_ref(), reason, note, &ctx).await;
+        });
+        action
+    }
+}
+
+impl controllers::Conditions for Crpd{
+    fn conditions_mut(&mut self) -> &mut Vec<meta_v1::Condition>{
+        self.status.get_or_insert_with(Default::default).conditions.get_or_insert_with(Vec::new)
+    }
+}
+
+#[async_trait]
+impl Controller for CrpdController{
+    async fn run(&self) -> anyhow::Result<()>{
+        // the pods of every Crpd are bound to this role to read the CA secret
+        let role = rbac_v1::Role{
+            metadata: meta_v1::ObjectMeta{
+                name: Some(CA_READER_ROLE.to_string()),
+                namespace: Some(self.context.config.namespace.clone()),
+                ..Default::default()
+            },
+            rules: Some(vec![rbac_v1::PolicyRule{
+                api_groups: Some(vec!["".to_string()]),
+                resources: Some(vec!["secrets".to_string()]),
+                resource_names: Some(vec![self.context.config.ca_secret.clone()]),
+                verbs: vec!["get".to_string()],
+                ..Default::default()
+            }]),
+        };
+        controllers::apply(role, FIELD_MANAGER, true, self.context.client.clone()).await?;
+
+        // earlier versions shared a "crpd" role granting everything
+        controllers::delete::<rbac_v1::RoleBinding>(self.context.config.namespace.clone(), "crpd".to_string(), self.context.client.clone()).await?;
+        controllers::delete::<rbac_v1::Role>(self.context.config.namespace.clone(), "crpd".to_string(), self.context.client.clone()).await?;
 
         let reconcile = |g: Arc<Crpd>, ctx: Arc<Context>| {
-            async move { CrpdController::reconcile(g, ctx).await }
+            async move {
+                let _measurer = metrics::metrics().reconcile_start("crpd");
+                let key = controllers::backoff_key("crpd", g.as_ref());
+                // a failed object is only reconciled again once its backoff
+                // passed or its spec changed
+                if let Some(pending) = ctx.backoff.pending(&key, g.meta().generation){
+                    return Ok(Action::requeue(pending));
+                }
+                let res = CrpdController::reconcile(g, ctx.clone()).await;
+                if res.is_ok(){
+                    ctx.backoff.reset(&key);
+                }
+                res
+            }
         };
 
 
         let error_policy = |g: Arc<Crpd>, error: &ReconcileError, ctx: Arc<Context>| {
             CrpdController::error_policy(g, error, ctx)
         };
-        runtime_controller::new(self.resource.clone(), Config::default())
-            .watches(
-                Api::<apps_v1::StatefulSet>::all(self.context.client.clone()),
-                Config::default(),
-                |sts| {
-                    match &sts.meta().labels{
-                        Some(labels) => {
-                            if labels.contains_key("app") && labels["app"] == "crpd"{
-                                Some(ObjectRef::<Crpd>::new(
-                                    sts.meta().name.as_ref().unwrap())
-                                    .within(sts.meta().namespace.as_ref().unwrap()))
-                            } else {
-                                None
-                            }
-                        },
-                        None => {
-                            None
-                        },
-                    }
-                }
+        // the crpds and their workloads are watched by the cache, which
+        // passes their changes on
+        let cache = &self.context.cache;
+        let controller = runtime_controller::for_stream(cache::applied_objects(&cache.crpds, &cache.applied_crpds), cache.crpds.clone())
+            .watches_stream(
+                cache::applied_objects(&cache.stateful_sets, &cache.applied_stateful_sets),
+                workload_crpd,
             )
+            .watches_stream(
+                cache::applied_objects(&cache.daemon_sets, &cache.applied_daemon_sets),
+                workload_crpd,
+            );
+        health::track("crpd", controller.store());
+        controller
+            .graceful_shutdown_on(health::health().shutdown_requested())
             .run(reconcile, error_policy, self.context.clone())
             .for_each(|res| async move {
                 match res {
@@ -225,39 +391,333 @@ impl Controller for CrpdController{
                 }
             })
             .await;
+        health::health().stopped("crpd");
         Ok(())
     }
 }
 
-impl From<Crpd> for apps_v1::StatefulSet{
+// operator_env hands the operator settings crpd-init depends on to the init containers
+fn operator_env(template: &mut core_v1::PodTemplateSpec, config: &OperatorConfig){
+    let env = vec![
+        core_v1::EnvVar{
+            name: "CNM_NAMESPACE".to_string(),
+            value: Some(config.namespace.clone()),
+            ..Default::default()
+        },
+        core_v1::EnvVar{
+            name: "CNM_CA_SECRET".to_string(),
+            value: Some(config.ca_secret.clone()),
+            ..Default::default()
+        },
+        core_v1::EnvVar{
+            name: "CNM_JET_PORT".to_string(),
+            value: Some(config.jet.port.to_string()),
+            ..Default::default()
+        },
+    ];
+    if let Some(pod_spec) = template.spec.as_mut(){
+        for init_container in pod_spec.init_containers.iter_mut().flatten(){
+            init_container.env.get_or_insert_with(Vec::new).extend(env.clone());
+        }
+    }
+}
+
+// pod_addresses returns the pod ips with their family, podIPs is empty on
+// clusters which don't fill it
+fn pod_addresses(pod: &core_v1::Pod, pod_ip: &str) -> Vec<InstanceAddress>{
+    let mut pod_ips: Vec<String> = pod.status.iter()
+        .flat_map(|status| status.pod_ips.iter().flatten())
+        .filter_map(|pod_ip| pod_ip.ip.clone())
+        .collect();
+    if pod_ips.is_empty(){
+        pod_ips.push(pod_ip.to_string());
+    }
+    pod_ips.into_iter().filter_map(|address| {
+        IpFamily::of(&address).map(|family| InstanceAddress{address, family})
+    }).collect()
+}
+
+fn owner_reference(crpd: &Crpd) -> meta_v1::OwnerReference{
+    meta_v1::OwnerReference{
+        api_version: "cnm.juniper.net/v1".to_string(),
+        kind: "Crpd".to_string(),
+        name: crpd.metadata.name.as_ref().unwrap().clone(),
+        uid: crpd.metadata.uid.as_ref().unwrap().clone(),
+        ..Default::default()
+    }
+}
+
+// pod_names returns the names of the pods of the Crpd: the ones a StatefulSet
+// will create and the ones which exist. DaemonSet pods get random names and
+// are only known once they exist, their crpd-init retries until then.
+fn pod_names(crpd: &Crpd, ctx: &Context) -> Result<Vec<String>, ReconcileError>{
+    let name = crpd.metadata.name.as_ref().unwrap();
+    let mut pod_names = Vec::new();
+    if crpd.spec.mode == CrpdMode::StatefulSet{
+        for ordinal in 0..crpd.spec.replicas{
+            pod_names.push(format!("{}-{}", name, ordinal));
+        }
+    }
+    let pods = cache::list(&ctx.cache.pods, crpd.metadata.namespace.as_ref().unwrap(), Some(&meta_v1::LabelSelector{
+        match_labels: Some(BTreeMap::from([("crpd".to_string(), name.clone())])),
+        ..Default::default()
+    }))?;
+    for pod in pods{
+        let pod_name = pod.metadata.name.clone().unwrap_or_default();
+        if !pod_names.contains(&pod_name){
+            pod_names.push(pod_name);
+        }
+    }
+    Ok(pod_names)
+}
+
+// secret_rules allows to create secrets and to read, patch and delete the
+// ones of the pods. create can't be limited by name.
+fn secret_rules(pod_names: Vec<String>) -> Vec<rbac_v1::PolicyRule>{
+    let mut rules = vec![rbac_v1::PolicyRule{
+        api_groups: Some(vec!["".to_string()]),
+        resources: Some(vec!["secrets".to_string()]),
+        verbs: vec!["create".to_string()],
+        ..Default::default()
+    }];
+    // an empty resourceNames list would allow every secret
+    if !pod_names.is_empty(){
+        rules.push(rbac_v1::PolicyRule{
+            api_groups: Some(vec!["".to_string()]),
+            resources: Some(vec!["secrets".to_string()]),
+            resource_names: Some(pod_names),
+            verbs: vec!["get".to_string(), "patch".to_string(), "delete".to_string()],
+            ..Default::default()
+        });
+    }
+    rules
+}
+
+fn service_account_name(crpd: &Crpd) -> String{
+    format!("crpd-{}", crpd.metadata.name.as_ref().unwrap())
+}
+
+// ca_role_binding_name is unique across namespaces, the binding lives in
+// the operator namespace
+fn ca_role_binding_name(crpd: &Crpd) -> String{
+    format!("crpd-{}-{}", crpd.metadata.namespace.as_ref().unwrap(), crpd.metadata.name.as_ref().unwrap())
+}
+
+// workload_crpd maps a StatefulSet or DaemonSet to the Crpd it was generated for
+fn workload_crpd<K: Resource>(workload: K) -> Option<ObjectRef<Crpd>>{
+    match &workload.meta().labels{
+        Some(labels) if labels.get("app").map(String::as_str) == Some("crpd") => {
+            Some(ObjectRef::<Crpd>::new(
+                workload.meta().name.as_ref().unwrap())
+                .within(workload.meta().namespace.as_ref().unwrap()))
+        },
+        _ => None,
+    }
+}
+
+// volumes the generated pod template always carries
+const TEMPLATE_VOLUMES: [&str; 2] = ["certs", "config"];
+
+// validate_pod_template rejects pod template settings the api server would
+// refuse on the StatefulSet
+fn validate_pod_template(spec: &CrpdSpec) -> Result<(), ReconcileError>{
+    let volumes: Vec<&str> = spec.volumes.iter().flatten().map(|volume| volume.name.as_str()).collect();
+    for volume in &volumes{
+        if TEMPLATE_VOLUMES.contains(volume){
+            return Err(ReconcileError::InvalidSpec(anyhow::anyhow!("volume name {} is reserved", volume)));
+        }
+    }
+    if spec.config_storage.is_some() && spec.mode == CrpdMode::DaemonSet{
+        return Err(ReconcileError::InvalidSpec(anyhow::anyhow!("configStorage is not supported in DaemonSet mode")));
+    }
+    if spec.upgrade_timeout_seconds.is_some() && spec.mode == CrpdMode::DaemonSet{
+        return Err(ReconcileError::InvalidSpec(anyhow::anyhow!("upgradeTimeoutSeconds is not supported in DaemonSet mode, the DaemonSet rolls its pods")));
+    }
+    for volume_mount in spec.volume_mounts.iter().flatten(){
+        if !volumes.contains(&volume_mount.name.as_str()) && !TEMPLATE_VOLUMES.contains(&volume_mount.name.as_str()){
+            return Err(ReconcileError::InvalidSpec(anyhow::anyhow!("volumeMount {} has no volume", volume_mount.name)));
+        }
+    }
+    Ok(())
+}
+
+// config_storage replaces the config emptyDir with a volume claim template
+fn config_storage(sts: &mut apps_v1::StatefulSet, spec: &CrpdSpec){
+    let config_storage = match &spec.config_storage{
+        Some(config_storage) => config_storage,
+        None => return,
+    };
+    let sts_spec = match sts.spec.as_mut(){
+        Some(sts_spec) => sts_spec,
+        None => return,
+    };
+    if let Some(volumes) = sts_spec.template.spec.as_mut().and_then(|pod_spec| pod_spec.volumes.as_mut()){
+        volumes.retain(|volume| volume.name != "config");
+    }
+    sts_spec.volume_claim_templates = Some(vec![core_v1::PersistentVolumeClaim{
+        metadata: meta_v1::ObjectMeta{
+            name: Some("config".to_string()),
+            ..Default::default()
+        },
+        spec: Some(core_v1::PersistentVolumeClaimSpec{
+            access_modes: Some(vec!["ReadWriteOnce".to_string()]),
+            storage_class_name: config_storage.storage_class_name.clone(),
+            resources: Some(core_v1::ResourceRequirements{
+                requests: Some(BTreeMap::from([("storage".to_string(), config_storage.size.clone())])),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }),
+        ..Default::default()
+    }]);
+}
+
+// volume_claims returns name, storage class and size of the volume claim
+// templates, the fields the api server doesn't default
+fn volume_claims(sts: &apps_v1::StatefulSet) -> Vec<(Option<String>, Option<String>, Option<Quantity>)>{
+    let mut claims = Vec::new();
+    if let Some(volume_claim_templates) = sts.spec.as_ref().and_then(|spec| spec.volume_claim_templates.as_ref()){
+        for claim in volume_claim_templates{
+            let spec = claim.spec.clone().unwrap_or_default();
+            let size = spec.resources
+                .and_then(|resources| resources.requests)
+                .and_then(|requests| requests.get("storage").cloned());
+            claims.push((claim.metadata.name.clone(), spec.storage_class_name, size));
+        }
+    }
+    claims
+}
+
+// pod_template merges the pod settings of the CrpdSpec into the generated template
+fn pod_template(sts: &mut apps_v1::StatefulSet, spec: &CrpdSpec){
+    let (template, selector) = match sts.spec.as_mut(){
+        Some(sts_spec) => (&mut sts_spec.template, sts_spec.selector.clone()),
+        None => return,
+    };
+    if let Some(annotations) = &spec.annotations{
+        template.metadata.get_or_insert_with(Default::default)
+            .annotations.get_or_insert_with(BTreeMap::new)
+            .extend(annotations.clone());
+    }
+    let pod_spec = match template.spec.as_mut(){
+        Some(pod_spec) => pod_spec,
+        None => return,
+    };
+    if spec.node_selector.is_some(){
+        pod_spec.node_selector = spec.node_selector.clone();
+    }
+    match (&spec.affinity, &spec.topology_spread_constraints){
+        (Some(affinity), _) => pod_spec.affinity = Some(affinity.clone()),
+        (None, None) => pod_spec.affinity = Some(spread_affinity(&selector)),
+        (None, Some(_)) => {},
+    }
+    if let Some(constraints) = &spec.topology_spread_constraints{
+        let mut constraints = constraints.clone();
+        for constraint in constraints.iter_mut().filter(|constraint| constraint.label_selector.is_none()){
+            constraint.label_selector = Some(selector.clone());
+        }
+        pod_spec.topology_spread_constraints = Some(constraints);
+    }
+    if spec.image_pull_secrets.is_some(){
+        pod_spec.image_pull_secrets = spec.image_pull_secrets.clone();
+    }
+    if spec.priority_class_name.is_some(){
+        pod_spec.priority_class_name = spec.priority_class_name.clone();
+    }
+    if let Some(tolerations) = &spec.tolerations{
+        pod_spec.tolerations.get_or_insert_with(Vec::new).extend(tolerations.clone());
+    }
+    if let Some(volumes) = &spec.volumes{
+        pod_spec.volumes.get_or_insert_with(Vec::new).extend(volumes.clone());
+    }
+    for container in pod_spec.containers.iter_mut().filter(|container| container.name == "crpd"){
+        if spec.resources.is_some(){
+            container.resources = spec.resources.clone();
+        }
+        if let Some(env) = &spec.env{
+            let container_env = container.env.get_or_insert_with(Vec::new);
+            container_env.retain(|var| !env.iter().any(|e| e.name == var.name));
+            container_env.extend(env.clone());
+        }
+        if let Some(volume_mounts) = &spec.volume_mounts{
+            container.volume_mounts.get_or_insert_with(Vec::new).extend(volume_mounts.clone());
+        }
+    }
+}
+
+// spread_affinity prefers nodes and, less so, zones without another
+// instance of the Crpd
+fn spread_affinity(selector: &meta_v1::LabelSelector) -> core_v1::Affinity{
+    let term = |weight: i32, topology_key: &str| core_v1::WeightedPodAffinityTerm{
+        weight,
+        pod_affinity_term: core_v1::PodAffinityTerm{
+            label_selector: Some(selector.clone()),
+            topology_key: topology_key.to_string(),
+            ..Default::default()
+        },
+    };
+    core_v1::Affinity{
+        pod_anti_affinity: Some(core_v1::PodAntiAffinity{
+            preferred_during_scheduling_ignored_during_execution: Some(vec![
+                term(100, "kubernetes.io/hostname"),
+                term(50, "topology.kubernetes.io/zone"),
+            ]),
+            ..Default::default()
+        }),
+        ..Default::default()
+    }
+}
+
+impl From<Crpd> for policy_v1::PodDisruptionBudget{
+    // a node drain evicts at most maxUnavailable instances at once
     fn from(crpd: Crpd) -> Self{
-        let mut labels = match crpd.metadata.clone().labels{
-            Some(labels) => {
-                labels
+        let name = crpd.metadata.name.as_ref().unwrap().clone();
+        policy_v1::PodDisruptionBudget{
+            metadata: meta_v1::ObjectMeta{
+                name: Some(name.clone()),
+                namespace: crpd.metadata.namespace.clone(),
+                labels: Some(BTreeMap::from([
+                    ("app".to_string(), "crpd".to_string()),
+                    ("crpd".to_string(), name.clone()),
+                ])),
+                owner_references: Some(vec![owner_reference(&crpd)]),
+                ..Default::default()
             },
-            None => {
-                BTreeMap::new()
-            }
-        };
+            spec: Some(policy_v1::PodDisruptionBudgetSpec{
+                max_unavailable: Some(crpd.spec.max_unavailable.clone().unwrap_or(IntOrString::Int(1))),
+                selector: Some(meta_v1::LabelSelector{
+                    match_labels: Some(BTreeMap::from([("crpd".to_string(), name)])),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+}
+
+impl From<Crpd> for apps_v1::StatefulSet{
+    fn from(crpd: Crpd) -> Self{
+        let spec = crpd.spec.clone();
+        let mut labels = crpd.metadata.clone().labels.unwrap_or_default();
         labels.insert("app".to_string(), "crpd".to_string());
         labels.insert("crpd".to_string(), crpd.metadata.name.as_ref().unwrap().clone());
 
-        apps_v1::StatefulSet{
+        let mut sts = apps_v1::StatefulSet{
             metadata: meta_v1::ObjectMeta{
                 name: Some(crpd.metadata.name.as_ref().unwrap().clone()),
-                namespace: crpd.metadata.namespace,
+                namespace: crpd.metadata.namespace.clone(),
                 labels: Some(labels.clone()),
-                owner_references: Some(vec![meta_v1::OwnerReference{
-                    api_version: "cnm.juniper.net/v1".to_string(),
-                    kind: "Crpd".to_string(),
-                    name: crpd.metadata.name.as_ref().unwrap().clone(),
-                    uid: crpd.metadata.uid.as_ref().unwrap().clone(),
-                    ..Default::default()
-                }]),
+                owner_references: Some(vec![owner_reference(&crpd)]),
                 ..Default::default()
             },
             spec: Some(apps_v1::StatefulSetSpec{
                 replicas: Some(crpd.spec.replicas),
+                // upgrades are driven by the CrpdController, see upgrade.rs
+                update_strategy: Some(apps_v1::StatefulSetUpdateStrategy{
+                    type_: Some("OnDelete".to_string()),
+                    ..Default::default()
+                }),
                 selector: meta_v1::LabelSelector { 
                     match_expressions: None,
                     match_labels: Some(BTreeMap::from([("crpd".to_string(), crpd.metadata.name.as_ref().unwrap().clone())])),
@@ -284,7 +744,7 @@ impl From<Crpd> for apps_v1::StatefulSet{
                                 ..Default::default()
                             },
                         ]),
-                        service_account_name: Some("crpd".to_string()),
+                        service_account_name: Some(service_account_name(&crpd)),
                         host_network: Some(true),
                         tolerations: Some(vec![core_v1::Toleration{
                             effect: Some("NoSchedule".to_string()),
@@ -397,6 +857,468 @@ impl From<Crpd> for apps_v1::StatefulSet{
                 ..Default::default()
             }),
             ..Default::default()
+        };
+        pod_template(&mut sts, &spec);
+        config_storage(&mut sts, &spec);
+        sts
+    }
+}
+impl From<Crpd> for apps_v1::DaemonSet{
+    fn from(crpd: Crpd) -> Self{
+        // both modes run the same pods, only the workload differs. A
+        // DaemonSet has no volume claims, the config stays an emptyDir.
+        let mut crpd = crpd;
+        crpd.spec.config_storage = None;
+        let sts = apps_v1::StatefulSet::from(crpd);
+        let sts_spec = sts.spec.unwrap_or_default();
+        apps_v1::DaemonSet{
+            metadata: sts.metadata,
+            spec: Some(apps_v1::DaemonSetSpec{
+                selector: sts_spec.selector,
+                template: sts_spec.template,
+                ..Default::default()
+            }),
+            ..Default::default()
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::controllers::test_utils::{self, ApiServer};
+    use crate::resources::crpd::crpd::ConfigStorage;
+    use http::Method;
+
+    #[tokio::test]
+    async fn reconcile_creates_stateful_set_and_reports_instances(){
+        let server = ApiServer::new();
+        let crpd = test_utils::crpd("crpd1", 2);
+        server.add(&crpd);
+        server.add(&test_utils::pod(&crpd, "crpd1-0", Some("10.0.0.1")));
+        server.add(&test_utils::pod(&crpd, "crpd1-1", None));
+
+        let action = CrpdController::reconcile(Arc::new(crpd), server.context()).await.unwrap();
+        // the operational state of the instance is refreshed
+        assert_eq!(action, Action::requeue(Duration::from_secs(60)));
+
+        let sts = server.get::<apps_v1::StatefulSet>("crpd1").unwrap();
+        assert_eq!(sts.spec.unwrap().replicas, Some(2));
+        assert_eq!(server.event_reasons(), vec!["StatefulSetCreated"]);
+
+        let status = server.get::<Crpd>("crpd1").unwrap().status.unwrap();
+        let instances = status.instances.unwrap();
+        assert_eq!(instances.len(), 1);
+        assert_eq!(instances[0].address, "10.0.0.1");
+        assert_eq!(instances[0].addresses, vec![InstanceAddress{address: "10.0.0.1".to_string(), family: IpFamily::IPv4}]);
+        // the test context has no CA to connect with
+        assert!(!instances[0].operational.as_ref().unwrap().authenticated);
+        assert_eq!(status.observed_generation, Some(1));
+        assert_eq!(test_utils::condition_status(&status.conditions, controllers::CONDITION_READY), Some("False"));
+        assert_eq!(test_utils::condition_status(&status.conditions, controllers::CONDITION_PROGRESSING), Some("True"));
+    }
+
+    #[tokio::test]
+    async fn reconcile_reports_dual_stack_addresses(){
+        let server = ApiServer::new();
+        let crpd = test_utils::crpd("crpd1", 1);
+        server.add(&crpd);
+        let mut pod = test_utils::pod(&crpd, "crpd1-0", Some("10.0.0.1"));
+        pod.status.as_mut().unwrap().pod_ips = Some(vec![
+            core_v1::PodIP{ip: Some("10.0.0.1".to_string())},
+            core_v1::PodIP{ip: Some("fd00::1".to_string())},
+        ]);
+        server.add(&pod);
+
+        CrpdController::reconcile(Arc::new(crpd), server.context()).await.unwrap();
+
+        let instances = server.get::<Crpd>("crpd1").unwrap().status.unwrap().instances.unwrap();
+        assert_eq!(instances[0].addresses, vec![
+            InstanceAddress{address: "10.0.0.1".to_string(), family: IpFamily::IPv4},
+            InstanceAddress{address: "fd00::1".to_string(), family: IpFamily::IPv6},
+        ]);
+    }
+
+    #[tokio::test]
+    async fn reconcile_reports_ready_when_all_replicas_are_ready(){
+        let server = ApiServer::new();
+        let crpd = test_utils::crpd("crpd1", 2);
+        server.add(&crpd);
+        server.add(&test_utils::stateful_set(&crpd, 2));
+
+        CrpdController::reconcile(Arc::new(crpd), server.context()).await.unwrap();
+
+        let status = server.get::<Crpd>("crpd1").unwrap().status.unwrap();
+        assert!(server.event_reasons().is_empty());
+        assert_eq!(status.stateful_set.unwrap().ready_replicas, Some(2));
+        assert_eq!(test_utils::condition_status(&status.conditions, controllers::CONDITION_READY), Some("True"));
+        assert_eq!(test_utils::condition_status(&status.conditions, controllers::CONDITION_DEGRADED), Some("False"));
+    }
+
+    #[tokio::test]
+    async fn reconcile_reports_only_own_pods_from_cache(){
+        let server = ApiServer::new();
+        let crpd = test_utils::crpd("crpd1", 1);
+        let other = test_utils::crpd("crpd2", 1);
+        server.add(&crpd);
+        server.add(&test_utils::pod(&crpd, "crpd1-0", Some("10.0.0.1")));
+        server.add(&test_utils::pod(&other, "crpd2-0", Some("10.0.0.2")));
+
+        CrpdController::reconcile(Arc::new(crpd), server.context()).await.unwrap();
+
+        let instances = server.get::<Crpd>("crpd1").unwrap().status.unwrap().instances.unwrap();
+        assert_eq!(instances.len(), 1);
+        assert_eq!(instances[0].name, "crpd1-0");
+        assert!(server.requests(Method::GET).iter().all(|path| !path.contains("/pods")));
+    }
+
+    #[tokio::test]
+    async fn reconcile_replaces_stateful_set_with_daemon_set(){
+        let server = ApiServer::new();
+        let mut crpd = test_utils::crpd("crpd1", 1);
+        server.add(&test_utils::stateful_set(&crpd, 1));
+        crpd.spec.mode = CrpdMode::DaemonSet;
+        server.add(&crpd);
+        server.add(&test_utils::pod(&crpd, "crpd1-abcde", Some("10.0.0.1")));
+
+        CrpdController::reconcile(Arc::new(crpd), server.context()).await.unwrap();
+
+        assert!(server.get::<apps_v1::StatefulSet>("crpd1").is_none());
+        let ds = server.get::<apps_v1::DaemonSet>("crpd1").unwrap();
+        assert_eq!(ds.spec.unwrap().template.spec.unwrap().containers[0].name, "crpd");
+        assert_eq!(server.event_reasons(), vec!["DaemonSetCreated"]);
+
+        let status = server.get::<Crpd>("crpd1").unwrap().status.unwrap();
+        assert!(status.stateful_set.is_none());
+        assert_eq!(status.instances.unwrap()[0].address, "10.0.0.1");
+    }
+
+    #[tokio::test]
+    async fn reconcile_reports_daemon_set_readiness(){
+        let server = ApiServer::new();
+        let mut crpd = test_utils::crpd("crpd1", 0);
+        crpd.spec.mode = CrpdMode::DaemonSet;
+        server.add(&crpd);
+        server.add(&test_utils::daemon_set(&crpd, 1, 2));
+
+        CrpdController::reconcile(Arc::new(crpd), server.context()).await.unwrap();
+
+        let status = server.get::<Crpd>("crpd1").unwrap().status.unwrap();
+        assert!(server.event_reasons().is_empty());
+        assert_eq!(status.daemon_set.unwrap().desired_number_scheduled, 2);
+        assert_eq!(test_utils::condition_status(&status.conditions, controllers::CONDITION_READY), Some("False"));
+        assert_eq!(test_utils::condition_status(&status.conditions, controllers::CONDITION_PROGRESSING), Some("True"));
+    }
+
+    #[tokio::test]
+    async fn reconcile_claims_config_storage(){
+        let server = ApiServer::new();
+        let mut crpd = test_utils::crpd("crpd1", 1);
+        crpd.spec.config_storage = Some(ConfigStorage{
+            storage_class_name: Some("local".to_string()),
+            size: Quantity("1Gi".to_string()),
+        });
+        server.add(&crpd);
+
+        CrpdController::reconcile(Arc::new(crpd), server.context()).await.unwrap();
+
+        let sts_spec = server.get::<apps_v1::StatefulSet>("crpd1").unwrap().spec.unwrap();
+        let claim = &sts_spec.volume_claim_templates.unwrap()[0];
+        assert_eq!(claim.metadata.name, Some("config".to_string()));
+        assert_eq!(claim.spec.as_ref().unwrap().storage_class_name, Some("local".to_string()));
+        let volumes = sts_spec.template.spec.unwrap().volumes.unwrap();
+        assert!(volumes.iter().all(|volume| volume.name != "config"));
+    }
+
+    #[tokio::test]
+    async fn reconcile_recreates_stateful_set_when_config_storage_changes(){
+        let server = ApiServer::new();
+        let mut crpd = test_utils::crpd("crpd1", 1);
+        server.add(&test_utils::stateful_set(&crpd, 1));
+        crpd.spec.config_storage = Some(ConfigStorage{
+            storage_class_name: None,
+            size: Quantity("1Gi".to_string()),
+        });
+        server.add(&crpd);
+
+        let action = CrpdController::reconcile(Arc::new(crpd), server.context()).await.unwrap();
+        assert_eq!(action, Action::await_change());
+        assert!(server.get::<apps_v1::StatefulSet>("crpd1").is_none());
+        assert_eq!(server.delete_bodies()[0]["propagationPolicy"], "Orphan");
+        assert_eq!(server.event_reasons(), vec!["StatefulSetDeleted"]);
+    }
+
+    #[tokio::test]
+    async fn reconcile_rejects_changing_claimed_config_storage(){
+        let server = ApiServer::new();
+        let mut crpd = test_utils::crpd("crpd1", 1);
+        crpd.spec.config_storage = Some(ConfigStorage{
+            storage_class_name: None,
+            size: Quantity("1Gi".to_string()),
+        });
+        server.add(&test_utils::stateful_set(&crpd, 1));
+        crpd.spec.config_storage.as_mut().unwrap().size = Quantity("2Gi".to_string());
+        server.add(&crpd);
+
+        let res = CrpdController::reconcile(Arc::new(crpd), server.context()).await;
+        assert!(matches!(res, Err(ReconcileError::InvalidSpec(_))));
+        assert!(server.get::<apps_v1::StatefulSet>("crpd1").is_some());
+    }
+
+    #[tokio::test]
+    async fn reconcile_rejects_config_storage_in_daemon_set_mode(){
+        let server = ApiServer::new();
+        let mut crpd = test_utils::crpd("crpd1", 1);
+        crpd.spec.mode = CrpdMode::DaemonSet;
+        crpd.spec.config_storage = Some(ConfigStorage{
+            storage_class_name: None,
+            size: Quantity("1Gi".to_string()),
+        });
+        server.add(&crpd);
+
+        let res = CrpdController::reconcile(Arc::new(crpd), server.context()).await;
+        assert!(matches!(res, Err(ReconcileError::InvalidSpec(_))));
+    }
+
+    #[tokio::test]
+    async fn reconcile_rejects_upgrade_timeout_in_daemon_set_mode(){
+        let server = ApiServer::new();
+        let mut crpd = test_utils::crpd("crpd1", 1);
+        crpd.spec.mode = CrpdMode::DaemonSet;
+        crpd.spec.upgrade_timeout_seconds = Some(300);
+        server.add(&crpd);
+
+        let res = CrpdController::reconcile(Arc::new(crpd), server.context()).await;
+        assert!(matches!(res, Err(ReconcileError::InvalidSpec(_))));
+    }
+
+    #[tokio::test]
+    async fn reconcile_creates_least_privilege_rbac(){
+        let server = ApiServer::new();
+        let crpd = test_utils::crpd("crpd1", 1);
+        server.add(&crpd);
+
+        CrpdController::reconcile(Arc::new(crpd), server.context()).await.unwrap();
+
+        let service_account = server.get::<core_v1::ServiceAccount>("crpd-crpd1").unwrap();
+        assert_eq!(service_account.metadata.owner_references.unwrap()[0].kind, "Crpd");
+        let rules = server.get::<rbac_v1::Role>("crpd-crpd1").unwrap().rules.unwrap();
+        assert_eq!(rules.len(), 2);
+        assert_eq!(rules[0].verbs, vec!["create"]);
+        assert_eq!(rules[0].resource_names, None);
+        assert_eq!(rules[1].resources, Some(vec!["secrets".to_string()]));
+        assert_eq!(rules[1].resource_names, Some(vec!["crpd1-0".to_string()]));
+        assert!(!rules[1].verbs.contains(&"*".to_string()));
+        assert!(server.get::<rbac_v1::RoleBinding>("crpd-crpd1").is_some());
+        let ca_role_binding = server.get::<rbac_v1::RoleBinding>("crpd-default-crpd1").unwrap();
+        assert_eq!(ca_role_binding.role_ref.name, CA_READER_ROLE);
+        let sts = server.get::<apps_v1::StatefulSet>("crpd1").unwrap();
+        assert_eq!(sts.spec.unwrap().template.spec.unwrap().service_account_name, Some("crpd-crpd1".to_string()));
+        assert!(controllers::has_finalizer(&server.get::<Crpd>("crpd1").unwrap(), controllers::CLEANUP_FINALIZER));
+    }
+
+    #[tokio::test]
+    async fn reconcile_limits_secrets_of_daemon_set_to_its_pods(){
+        let server = ApiServer::new();
+        let mut crpd = test_utils::crpd("crpd1", 0);
+        crpd.spec.mode = CrpdMode::DaemonSet;
+        server.add(&crpd);
+
+        CrpdController::reconcile(Arc::new(crpd.clone()), server.context()).await.unwrap();
+        let rules = server.get::<rbac_v1::Role>("crpd-crpd1").unwrap().rules.unwrap();
+        assert_eq!(rules.len(), 1);
+        assert_eq!(rules[0].verbs, vec!["create"]);
+
+        server.add(&test_utils::pod(&crpd, "crpd1-x7k2p", Some("10.0.0.1")));
+        CrpdController::reconcile(Arc::new(crpd), server.context()).await.unwrap();
+        let rules = server.get::<rbac_v1::Role>("crpd-crpd1").unwrap().rules.unwrap();
+        assert_eq!(rules[1].resource_names, Some(vec!["crpd1-x7k2p".to_string()]));
+    }
+
+    #[tokio::test]
+    async fn reconcile_cleans_up_deleted_crpd_with_invalid_spec(){
+        let server = ApiServer::new();
+        let mut crpd = test_utils::crpd("crpd1", -1);
+        crpd.metadata.finalizers = Some(vec![controllers::CLEANUP_FINALIZER.to_string()]);
+        crpd.metadata.deletion_timestamp = Some(meta_v1::Time(k8s_openapi::chrono::Utc::now()));
+        server.add(&crpd);
+
+        CrpdController::reconcile(Arc::new(crpd), server.context()).await.unwrap();
+        assert!(!controllers::has_finalizer(&server.get::<Crpd>("crpd1").unwrap(), controllers::CLEANUP_FINALIZER));
+    }
+
+    #[tokio::test]
+    async fn reconcile_removes_ca_role_binding_of_deleted_crpd(){
+        let server = ApiServer::new();
+        let mut crpd = test_utils::crpd("crpd1", 1);
+        crpd.metadata.finalizers = Some(vec![controllers::CLEANUP_FINALIZER.to_string()]);
+        crpd.metadata.deletion_timestamp = Some(meta_v1::Time(k8s_openapi::chrono::Utc::now()));
+        server.add(&crpd);
+        server.add(&rbac_v1::RoleBinding{
+            metadata: meta_v1::ObjectMeta{
+                name: Some("crpd-default-crpd1".to_string()),
+                namespace: Some(test_utils::NAMESPACE.to_string()),
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+
+        CrpdController::reconcile(Arc::new(crpd), server.context()).await.unwrap();
+
+        assert!(server.get::<rbac_v1::RoleBinding>("crpd-default-crpd1").is_none());
+        assert!(!controllers::has_finalizer(&server.get::<Crpd>("crpd1").unwrap(), controllers::CLEANUP_FINALIZER));
+        assert!(server.get::<apps_v1::StatefulSet>("crpd1").is_none());
+    }
+
+    #[tokio::test]
+    async fn reconcile_reports_upgrade_in_progress(){
+        let server = ApiServer::new();
+        let crpd = test_utils::crpd("crpd1", 1);
+        server.add(&crpd);
+        let mut sts = test_utils::stateful_set(&crpd, 1);
+        sts.status.as_mut().unwrap().update_revision = Some("rev2".to_string());
+        server.add(&sts);
+        let mut pod = test_utils::pod(&crpd, "crpd1-0", Some("10.0.0.1"));
+        pod.metadata.labels.as_mut().unwrap().insert("controller-revision-hash".to_string(), "rev1".to_string());
+        server.add(&pod);
+
+        let action = CrpdController::reconcile(Arc::new(crpd), server.context()).await.unwrap();
+        assert_eq!(action, Action::requeue(UPGRADE_REQUEUE));
+        assert!(server.get::<core_v1::Pod>("crpd1-0").is_none());
+
+        let status = server.get::<Crpd>("crpd1").unwrap().status.unwrap();
+        assert_eq!(status.upgrade.unwrap().instance, Some("crpd1-0".to_string()));
+        assert_eq!(test_utils::condition_status(&status.conditions, controllers::CONDITION_PROGRESSING), Some("True"));
+        let sts = server.get::<apps_v1::StatefulSet>("crpd1").unwrap();
+        assert_eq!(sts.spec.unwrap().update_strategy.unwrap().type_, Some("OnDelete".to_string()));
+    }
+
+    #[tokio::test]
+    async fn reconcile_rejects_negative_replicas(){
+        let server = ApiServer::new();
+        let crpd = test_utils::crpd("crpd1", -1);
+        server.add(&crpd);
+
+        let res = CrpdController::reconcile(Arc::new(crpd), server.context()).await;
+        assert!(matches!(res, Err(ReconcileError::InvalidSpec(_))));
+        assert!(server.get::<apps_v1::StatefulSet>("crpd1").is_none());
+    }
+
+    #[tokio::test]
+    async fn reconcile_merges_pod_template(){
+        let server = ApiServer::new();
+        let mut crpd = test_utils::crpd("crpd1", 1);
+        crpd.spec.node_selector = Some(BTreeMap::from([("node-role".to_string(), "routing".to_string())]));
+        crpd.spec.priority_class_name = Some("system-node-critical".to_string());
+        crpd.spec.annotations = Some(BTreeMap::from([("team".to_string(), "network".to_string())]));
+        crpd.spec.tolerations = Some(vec![core_v1::Toleration{
+            key: Some("routing".to_string()),
+            operator: Some("Exists".to_string()),
+            ..Default::default()
+        }]);
+        crpd.spec.env = Some(vec![core_v1::EnvVar{
+            name: "LOG_LEVEL".to_string(),
+            value: Some("debug".to_string()),
+            ..Default::default()
+        }]);
+        crpd.spec.volumes = Some(vec![core_v1::Volume{
+            name: "license".to_string(),
+            ..Default::default()
+        }]);
+        crpd.spec.volume_mounts = Some(vec![core_v1::VolumeMount{
+            name: "license".to_string(),
+            mount_path: "/license".to_string(),
+            ..Default::default()
+        }]);
+        server.add(&crpd);
+
+        CrpdController::reconcile(Arc::new(crpd), server.context()).await.unwrap();
+
+        let template = server.get::<apps_v1::StatefulSet>("crpd1").unwrap().spec.unwrap().template;
+        assert_eq!(template.metadata.unwrap().annotations.unwrap().get("team"), Some(&"network".to_string()));
+        let pod_spec = template.spec.unwrap();
+        assert_eq!(pod_spec.node_selector.unwrap().get("node-role"), Some(&"routing".to_string()));
+        assert_eq!(pod_spec.priority_class_name, Some("system-node-critical".to_string()));
+        assert_eq!(pod_spec.tolerations.unwrap().len(), 2);
+        assert_eq!(pod_spec.volumes.unwrap().len(), 3);
+        let container = &pod_spec.containers[0];
+        assert!(container.env.as_ref().unwrap().iter().any(|var| var.name == "LOG_LEVEL"));
+        assert_eq!(container.volume_mounts.as_ref().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn reconcile_creates_pod_disruption_budget(){
+        let server = ApiServer::new();
+        let mut crpd = test_utils::crpd("crpd1", 3);
+        crpd.spec.max_unavailable = Some(IntOrString::String("50%".to_string()));
+        server.add(&crpd);
+
+        CrpdController::reconcile(Arc::new(crpd), server.context()).await.unwrap();
+
+        let pdb = server.get::<policy_v1::PodDisruptionBudget>("crpd1").unwrap();
+        assert_eq!(pdb.metadata.owner_references.unwrap()[0].kind, "Crpd");
+        let spec = pdb.spec.unwrap();
+        assert_eq!(spec.max_unavailable, Some(IntOrString::String("50%".to_string())));
+        assert_eq!(spec.selector.unwrap().match_labels.unwrap().get("crpd"), Some(&"crpd1".to_string()));
+    }
+
+    #[tokio::test]
+    async fn reconcile_spreads_instances(){
+        let server = ApiServer::new();
+        let crpd = test_utils::crpd("crpd1", 3);
+        server.add(&crpd);
+
+        CrpdController::reconcile(Arc::new(crpd.clone()), server.context()).await.unwrap();
+
+        let pod_spec = server.get::<apps_v1::StatefulSet>("crpd1").unwrap().spec.unwrap().template.spec.unwrap();
+        let terms = pod_spec.affinity.unwrap().pod_anti_affinity.unwrap()
+            .preferred_during_scheduling_ignored_during_execution.unwrap();
+        let topology_keys: Vec<String> = terms.into_iter().map(|term| term.pod_affinity_term.topology_key).collect();
+        assert_eq!(topology_keys, vec!["kubernetes.io/hostname", "topology.kubernetes.io/zone"]);
+
+        // configured constraints replace the default anti-affinity
+        let server = ApiServer::new();
+        let mut crpd = crpd;
+        crpd.spec.topology_spread_constraints = Some(vec![core_v1::TopologySpreadConstraint{
+            max_skew: 1,
+            topology_key: "topology.kubernetes.io/zone".to_string(),
+            when_unsatisfiable: "DoNotSchedule".to_string(),
+            ..Default::default()
+        }]);
+        server.add(&crpd);
+
+        CrpdController::reconcile(Arc::new(crpd), server.context()).await.unwrap();
+
+        let pod_spec = server.get::<apps_v1::StatefulSet>("crpd1").unwrap().spec.unwrap().template.spec.unwrap();
+        let constraints = pod_spec.topology_spread_constraints.unwrap();
+        assert_eq!(constraints[0].label_selector.as_ref().unwrap().match_labels.as_ref().unwrap().get("crpd"), Some(&"crpd1".to_string()));
+        assert!(pod_spec.affinity.is_none());
+    }
+
+    #[tokio::test]
+    async fn reconcile_rejects_volume_mount_without_volume(){
+        let server = ApiServer::new();
+        let mut crpd = test_utils::crpd("crpd1", 1);
+        crpd.spec.volume_mounts = Some(vec![core_v1::VolumeMount{
+            name: "license".to_string(),
+            mount_path: "/license".to_string(),
+            ..Default::default()
+        }]);
+        server.add(&crpd);
+
+        let res = CrpdController::reconcile(Arc::new(crpd), server.context()).await;
+        assert!(matches!(res, Err(ReconcileError::InvalidSpec(_))));
+        assert!(server.get::<apps_v1::StatefulSet>("crpd1").is_none());
+    }
+
+    #[tokio::test]
+    async fn reconcile_fails_when_stateful_set_apply_fails(){
+        let server = ApiServer::new();
+        let crpd = test_utils::crpd("crpd1", 1);
+        server.add(&crpd);
+        server.fail(Method::PATCH, "/apis/apps/v1/namespaces/default/statefulsets");
+
+        let res = CrpdController::reconcile(Arc::new(crpd), server.context()).await;
+        assert!(matches!(res, Err(ReconcileError::TransientApi(_))));
+        assert!(server.get::<Crpd>("crpd1").unwrap().status.is_none());
+    }
+}
@@ -0,0 +1,146 @@
+use crate::controllers::controllers::Context;
+use crate::controllers::crpd::junos;
+use crate::controllers::crpd::junos::op;
+use crate::controllers::crpd::license::License;
+use crate::resources::crpd::crpd::{Instance, OperationalStatus};
+use tracing::*;
+
+// The CrpdController reads the operational state of every instance over
+// JET and reports it in the Instance status, see license.rs for the license
+// installed over the same session. The Crpd is requeued every
+// jet.statusIntervalSeconds to keep it current.
+
+// connect opens a JET session to the instance
+pub async fn connect(instance: &Instance, ctx: &Context) -> anyhow::Result<junos::client::Client>{
+    let ca = match &ctx.ca{
+        Some(ca) => ca.clone(),
+        None => return Err(anyhow::anyhow!("no CA to connect to {}", instance.name)),
+    };
+    junos::client::Client::new(
+        instance.address.clone(),
+        ctx.config.jet.port,
+        std::time::Duration::from_secs(ctx.config.jet.timeout_seconds),
+        instance.name.clone(),
+        ctx.key.clone().unwrap_or_default(),
+        ca,
+        ctx.cert.clone().unwrap_or_default()).await
+}
+
+// operational records the operational state of each instance and installs
+// the license over the same JET session. The instances are read
+// concurrently, an instance which can't be read is reported, not failed on.
+pub async fn operational(instances: &mut [Instance], license: &License, ctx: &Context){
+    futures::future::join_all(instances.iter_mut().map(|instance| async move {
+        let mut client = match connect(instance, ctx).await{
+            Ok(client) => client,
+            Err(e) => {
+                warn!("failed to connect to {}: {:?}", instance.name, e);
+                instance.operational = Some(OperationalStatus{
+                    authenticated: false,
+                    message: Some(e.to_string()),
+                    ..Default::default()
+                });
+                instance.license = license.not_installed(e.to_string());
+                return;
+            }
+        };
+        let mut status = OperationalStatus{
+            authenticated: true,
+            ..Default::default()
+        };
+        if let Err(e) = read(&mut client, &mut status).await{
+            warn!("failed to read the state of {}: {:?}", instance.name, e);
+            status.message = Some(e.to_string());
+        }
+        instance.operational = Some(status);
+        instance.license = license.install(instance, &mut client).await;
+    })).await;
+}
+
+async fn read(client: &mut junos::client::Client, status: &mut OperationalStatus) -> anyhow::Result<()>{
+    if let Some(software_information) = client.software_information().await?{
+        status.version = parse_version(&software_information)?;
+    }
+    if let Some(system_uptime) = client.system_uptime().await?{
+        (status.uptime, status.last_commit) = parse_system_uptime(&system_uptime)?;
+    }
+    if let Some(bgp_summary) = client.bgp_summary().await?{
+        status.established_bgp_sessions = Some(parse_established_bgp_sessions(&bgp_summary)?);
+    }
+    Ok(())
+}
+
+// parse_version reads the junos version of get-software-information
+pub fn parse_version(software_information: &str) -> anyhow::Result<Option<String>>{
+    let software_information: serde_json::Value = serde_json::from_str(software_information)?;
+    Ok(op::values(&software_information, "software-information").iter()
+        .find_map(|information| op::data(information, "junos-version")))
+}
+
+// parse_system_uptime reads the uptime and the time of the last commit of
+// get-system-uptime-information
+pub fn parse_system_uptime(system_uptime: &str) -> anyhow::Result<(Option<String>, Option<String>)>{
+    let system_uptime: serde_json::Value = serde_json::from_str(system_uptime)?;
+    let uptime = op::values(&system_uptime, "system-booted-time").iter()
+        .find_map(|booted| op::data(booted, "time-length"));
+    let last_commit = op::values(&system_uptime, "last-configured-time").iter()
+        .find_map(|configured| op::data(configured, "date-time"));
+    Ok((uptime, last_commit))
+}
+
+// parse_established_bgp_sessions counts the established peers of
+// get-bgp-summary-information
+pub fn parse_established_bgp_sessions(bgp_summary: &str) -> anyhow::Result<i32>{
+    let bgp_summary: serde_json::Value = serde_json::from_str(bgp_summary)?;
+    let established = op::values(&bgp_summary, "bgp-peer").iter()
+        .filter(|peer| op::data(peer, "peer-state").as_deref() == Some("Established"))
+        .count();
+    Ok(established as i32)
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn parse_operational_state(){
+        let software_information = r#"{
+            "software-information": [{
+                "host-name": [{"data": "crpd1-0"}],
+                "product-model": [{"data": "crpd"}],
+                "junos-version": [{"data": "23.2R1.13"}]
+            }]
+        }"#;
+        assert_eq!(parse_version(software_information).unwrap(), Some("23.2R1.13".to_string()));
+
+        let system_uptime = r#"{
+            "system-uptime-information": [{
+                "system-booted-time": [{
+                    "date-time": [{"data": "2023-09-01 10:00:00 UTC"}],
+                    "time-length": [{"data": "2d 03:04"}]
+                }],
+                "last-configured-time": [{
+                    "date-time": [{"data": "2023-09-03 12:30:00 UTC"}],
+                    "time-length": [{"data": "00:34:00"}],
+                    "user": [{"data": "root"}]
+                }]
+            }]
+        }"#;
+        let (uptime, last_commit) = parse_system_uptime(system_uptime).unwrap();
+        assert_eq!(uptime, Some("2d 03:04".to_string()));
+        assert_eq!(last_commit, Some("2023-09-03 12:30:00 UTC".to_string()));
+
+        let bgp_summary = r#"{
+            "bgp-information": [{
+                "peer-count": [{"data": "3"}],
+                "bgp-peer": [
+                    {"peer-address": [{"data": "10.0.0.1"}], "peer-state": [{"data": "Established"}]},
+                    {"peer-address": [{"data": "10.0.0.2"}], "peer-state": [{"data": "Active"}]},
+                    {"peer-address": [{"data": "10.0.0.3"}], "peer-state": [{"data": "Established"}]}
+                ]
+            }]
+        }"#;
+        assert_eq!(parse_established_bgp_sessions(bgp_summary).unwrap(), 2);
+        assert_eq!(parse_established_bgp_sessions(r#"{"bgp-information": [{}]}"#).unwrap(), 0);
+    }
+}
@@ -1,10 +1,11 @@
 use super::proto::jnx::jet::management as junos_mgmt;
 use super::proto::jnx::jet::authentication as junos_auth;
-use tonic::transport::{Certificate, Channel, ClientTlsConfig};
+use tonic::transport::{Certificate, Channel, ClientTlsConfig, Identity};
 use tonic::Request;
 use tonic::metadata::MetadataMap;
 use tracing::info;
 use super::bgp;
+use crate::metrics::metrics;
 use tracing::warn;
 
 pub struct Client {
@@ -12,20 +13,27 @@ pub struct Client {
 }
 
 impl Client{
-    pub async fn new(address: String, domain_name: String, key: String, ca: String, cert: String) -> anyhow::Result<Client>{
-        //let client_identity = Identity::from_pem(cert, key);
+    pub async fn new(address: String, port: u16, timeout: std::time::Duration, domain_name: String, key: String, ca: String, cert: String) -> anyhow::Result<Client>{
         let mut map = MetadataMap::new();
         map.insert("client-id", "cnm".parse().unwrap());
-        let tls = ClientTlsConfig::new()
+        let mut tls = ClientTlsConfig::new()
             .domain_name(domain_name)
             .ca_certificate(Certificate::from_pem(ca));
+        // a client certificate is only presented by callers which have one
+        if !cert.is_empty() && !key.is_empty(){
+            tls = tls.identity(Identity::from_pem(cert, key));
+        }
 
-        let ep_address = format!("http://{}:50052",address);
+        let ep_address = endpoint(&address, port);
         info!("Connecting to {}", ep_address);
         let channel = Channel::from_shared(ep_address)?
+            .connect_timeout(timeout)
+            .timeout(timeout)
             .tls_config(tls)?
             .connect()
-            .await?;
+            .await;
+        metrics::metrics().jet_call("connect", &channel);
+        let channel = channel?;
 
         //c := auth.NewLoginClient(conn)
         let login_request = junos_auth::LoginRequest{
@@ -35,7 +43,9 @@ impl Client{
             client_id: "cnm".to_string(),
         };
 
-        let login_response = match junos_auth::authentication_client::AuthenticationClient::new(channel.clone()).login(login_request).await{
+        let login_response = junos_auth::authentication_client::AuthenticationClient::new(channel.clone()).login(login_request).await;
+        metrics::metrics().jet_call("login", &login_response);
+        let login_response = match login_response{
             Ok(res) => {
                 res
             },
@@ -56,20 +66,85 @@ impl Client{
         let mut request = junos_mgmt::ConfigSetRequest::default();
         let json_config = serde_json::to_string(&config)?;
         request.config = Some(junos_mgmt::config_set_request::Config::JsonConfig(json_config));
-        self.client.config_set(request).await?;
+        let res = self.client.config_set(request).await;
+        metrics::metrics().jet_call("config_set", &res);
+        res?;
         Ok(())
     }
+    // delete removes the given configuration hierarchies and commits the result
+    pub async fn delete(&mut self, paths: Vec<String>) -> anyhow::Result<()>{
+        let mut request = junos_mgmt::ConfigSetRequest::default();
+        let text_config = paths.iter().map(|path| format!("delete {}", path)).collect::<Vec<String>>().join("\n");
+        info!("deleting config {:#?}", text_config);
+        request.config = Some(junos_mgmt::config_set_request::Config::TextConfig(text_config));
+        request.set_load_type(junos_mgmt::ConfigLoadType::ConfigLoadSet);
+        request.commit = Some(junos_mgmt::ConfigCommit{
+            r#type: junos_mgmt::ConfigCommitType::ConfigCommit.into(),
+            comment: "cnm cleanup".to_string(),
+        });
+        let mut request = Request::new(request);
+        request.metadata_mut().insert("client-id", "cnm".parse().unwrap());
+        let res = self.client.config_set(request).await;
+        metrics::metrics().jet_call("config_set", &res);
+        res?;
+        Ok(())
+    }
+    // add_license commits the license key as system license key
+    pub async fn add_license(&mut self, key: &str) -> anyhow::Result<()>{
+        let mut request = junos_mgmt::ConfigSetRequest::default();
+        // a key spans several lines, junos takes it as one quoted word
+        if key.contains(['"', '\\']){
+            return Err(anyhow::anyhow!("license key must not contain quotes or backslashes"));
+        }
+        let key = key.split_whitespace().collect::<Vec<&str>>().join(" ");
+        request.config = Some(junos_mgmt::config_set_request::Config::TextConfig(format!("set system license keys key \"{}\"", key)));
+        request.set_load_type(junos_mgmt::ConfigLoadType::ConfigLoadSet);
+        request.commit = Some(junos_mgmt::ConfigCommit{
+            r#type: junos_mgmt::ConfigCommitType::ConfigCommit.into(),
+            comment: "cnm license".to_string(),
+        });
+        let mut request = Request::new(request);
+        request.metadata_mut().insert("client-id", "cnm".parse().unwrap());
+        let res = self.client.config_set(request).await;
+        metrics::metrics().jet_call("config_set", &res);
+        res?;
+        Ok(())
+    }
+    // license_summary returns the license summary of the instance as json
+    pub async fn license_summary(&mut self) -> anyhow::Result<Option<String>>{
+        self.op_command("<get-license-summary-information></get-license-summary-information>").await
+    }
+    // license_keys returns the installed license keys of the instance as json
+    pub async fn license_keys(&mut self) -> anyhow::Result<Option<String>>{
+        self.op_command("<get-license-key-information></get-license-key-information>").await
+    }
+    pub async fn software_information(&mut self) -> anyhow::Result<Option<String>>{
+        self.op_command("<get-software-information></get-software-information>").await
+    }
+    // system_uptime returns boot and last commit time of the instance as json
+    pub async fn system_uptime(&mut self) -> anyhow::Result<Option<String>>{
+        self.op_command("<get-system-uptime-information></get-system-uptime-information>").await
+    }
+    pub async fn bgp_summary(&mut self) -> anyhow::Result<Option<String>>{
+        self.op_command("<get-bgp-summary-information></get-bgp-summary-information>").await
+    }
     pub async fn get(&mut self) -> anyhow::Result<Option<String>>{
-        let mut op_command_request = junos_mgmt::OpCommandGetRequest::default();
-        op_command_request.command = Some(junos_mgmt::op_command_get_request::Command::XmlCommand("<get-configuration></get-configuration>".to_string()));
+        self.op_command("<get-configuration></get-configuration>").await
+    }
+    async fn op_command(&mut self, command: &str) -> anyhow::Result<Option<String>>{
+        let mut op_command_request = junos_mgmt::OpCommandGetRequest{
+            command: Some(junos_mgmt::op_command_get_request::Command::XmlCommand(command.to_string())),
+            ..Default::default()
+        };
         op_command_request.set_out_format(junos_mgmt::OpCommandOutputFormat::OpCommandOutputJson);
         //op_command_request.command = Some(junos_mgmt::op_command_get_request::Command::CliCommand("show configuration".to_string()));
         //op_command_request.set_out_format(junos_mgmt::OpCommandOutputFormat::OpCommandOutputCli);
         let mut request = Request::new(op_command_request);
         request.metadata_mut().insert("client-id", "cnm".parse().unwrap());
 
-        let mut response = match self.client
-        .op_command_get(request).await{
+        let response = self.client.op_command_get(request).await;
+        metrics::metrics().jet_call("op_command_get", &response);
+        let mut response = match response{
             Ok(stream) => {
                 stream.into_inner()
             },
@@ -87,10 +162,31 @@ impl Client{
             }
         };
         if let Some(msg) = msg{
-            info!("got config {:#?}", msg);
+            info!("got {} response {:#?}", command, msg);
             return Ok(Some(msg.data));
         }
-        info!("got empty config {:#?}", msg);
+        info!("got empty {} response {:#?}", command, msg);
         Ok(None)
     }
-}
\ No newline at end of file
+}
+
+// endpoint builds the uri of the JET endpoint, ipv6 addresses are put in
+// brackets
+fn endpoint(address: &str, port: u16) -> String{
+    match address.parse::<std::net::IpAddr>(){
+        Ok(ip) => format!("http://{}", std::net::SocketAddr::new(ip, port)),
+        Err(_) => format!("http://{}:{}", address, port),
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn endpoint_brackets_ipv6_addresses(){
+        assert_eq!(endpoint("10.0.0.1", 50052), "http://10.0.0.1:50052");
+        assert_eq!(endpoint("fd00::1", 50052), "http://[fd00::1]:50052");
+        assert_eq!(endpoint("crpd1-0.crpd1", 50052), "http://crpd1-0.crpd1:50052");
+    }
+}
@@ -1,6 +1,7 @@
 pub mod bgp;
 pub mod proto;
 pub mod client;
+pub mod op;
 pub mod routing_instance;
 pub mod common;
 pub mod family;
@@ -0,0 +1,36 @@
+// Helpers to read the json output of op commands, in which every element is
+// a list and every leaf a list of {"data": value} objects.
+
+// values returns the entries of every list named key anywhere in value
+pub fn values<'a>(value: &'a serde_json::Value, key: &str) -> Vec<&'a serde_json::Value>{
+    let mut found = Vec::new();
+    collect(value, key, &mut found);
+    found
+}
+
+fn collect<'a>(value: &'a serde_json::Value, key: &str, found: &mut Vec<&'a serde_json::Value>){
+    match value{
+        serde_json::Value::Object(object) => {
+            for (k, v) in object{
+                if k == key{
+                    if let Some(items) = v.as_array(){
+                        found.extend(items.iter());
+                    }
+                } else {
+                    collect(v, key, found);
+                }
+            }
+        },
+        serde_json::Value::Array(items) => {
+            for item in items{
+                collect(item, key, found);
+            }
+        },
+        _ => {},
+    }
+}
+
+// data returns the value of the leaf key of value
+pub fn data(value: &serde_json::Value, key: &str) -> Option<String>{
+    value.get(key)?.get(0)?.get("data")?.as_str().map(|data| data.to_string())
+}
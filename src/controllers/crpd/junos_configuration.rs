@@ -1,13 +1,15 @@
 use crate::controllers::controllers::{Controller, Context, ReconcileError};
-use crate::controllers::controllers;
+use crate::metrics::metrics;
+use crate::health::health;
+use kube::runtime::events::EventType;
+use crate::controllers::{controllers, cache};
 use crate::cert;
 use crate::controllers::crpd::junos;
-use crate::resources::bgp_router::BgpRouter;
+use crate::resources::bgp_router::{BgpRouter, BgpRouterStatus, BgpRouterType};
 use kube::Resource;
 use async_trait::async_trait;
 use futures::StreamExt;
 use kube::{
-    api::Api,
     client::Client,
     runtime::{
         controller::{Action, Controller as runtime_controller},
@@ -16,92 +18,299 @@ use kube::{
 };
 use std::f32::consts::E;
 use std::sync::Arc;
-use tokio::time::Duration;
 use tracing::*;
 use k8s_openapi::api::core::v1 as core_v1;
 use k8s_openapi::apimachinery::pkg::apis::meta::v1 as meta_v1;
 use std::collections::BTreeMap;
 use k8s_openapi::ByteString;
 
+const FIELD_MANAGER: &str = "cnm-junos-configuration";
+
 pub struct JunosConfigurationController{
     context: Arc<Context>,
-    resource: Api<BgpRouter>,
 }
 
 impl JunosConfigurationController{
     pub fn new(context: Arc<Context>) -> Self{
-        let resource = Api::all(context.client.clone());
         let context = context.clone();
-        JunosConfigurationController{context, resource}
+        JunosConfigurationController{context}
     }
     async fn reconcile(g: Arc<BgpRouter>, ctx: Arc<Context>) ->  Result<Action, ReconcileError> {
-        match controllers::get::<BgpRouter>(
-            g.meta().namespace.as_ref().unwrap().clone(),
-            g.meta().name.as_ref().unwrap().clone(),
-            ctx.client.clone())
-            .await{
-            Ok(res) => {
-                match res{
-                    Some((bgp_router, _)) => {
-                        info!("junos config controller reconciles bgprouter config");
-                        if let Some(address) = &bgp_router.spec.address{
-                            let mut pod_name = None;
-                            bgp_router.meta().owner_references.as_ref().unwrap().iter().for_each(|owner: &meta_v1::OwnerReference| {
-                                info!("owner: {:#?}", owner);
-                                if owner.kind == "Pod"{
-                                    pod_name = Some(owner.name.clone())
-                                }
-                            });
-                            match junos::client::Client::new(
-                                address.clone(),
-                                pod_name.as_ref().unwrap().clone(),
-                                ctx.key.as_ref().unwrap().clone(),
-                                ctx.ca.as_ref().unwrap().clone(),
-                                ctx.cert.as_ref().unwrap().clone()).await{
-                                Ok(mut client) => {
-                                    match client.get().await{
-                                        Ok(config) => {
-                                            info!("JUNOS config: {:#?}", config);
-                                        },
-                                        Err(e) => { return Err(ReconcileError(e.into()))}
-                                    }
-                                },
-                                Err(e) => {
-                                    return Err(ReconcileError(e.into()))
-                                },
+        let bgp_router = g.as_ref().clone();
+        if bgp_router.meta().deletion_timestamp.is_some(){
+            return JunosConfigurationController::cleanup(bgp_router, ctx).await;
+        }
+        // a managed router holds the finalizer for its own groups and for the
+        // neighbors it has of its peers, unmanaged routers only get it from
+        // a managed peer
+        if !is_managed(&bgp_router){
+            return Ok(Action::await_change());
+        }
+        controllers::add_finalizer(&bgp_router, controllers::JUNOS_FINALIZER, ctx.client.clone()).await?;
+        let namespace = bgp_router.meta().namespace.as_ref().unwrap().clone();
+        for bgp_peer_reference in bgp_router.status.iter().flat_map(|status| status.bgp_peer_references.iter().flatten()){
+            let peer_name = match &bgp_peer_reference.peer_reference.name{
+                Some(peer_name) => peer_name,
+                None => continue,
+            };
+            if let Some(peer) = cache::get::<BgpRouter>(&ctx.cache.bgp_routers, &namespace, peer_name){
+                if peer.meta().deletion_timestamp.is_none(){
+                    controllers::add_finalizer(&peer, controllers::JUNOS_FINALIZER, ctx.client.clone()).await?;
+                }
+            }
+        }
+        info!("junos config controller reconciles bgprouter config");
+        match JunosConfigurationController::connect(&bgp_router, &ctx).await{
+            Ok(Some(mut client)) => {
+                match client.get().await{
+                    Ok(config) => {
+                        let configured = match configured_neighbors(&config.unwrap_or_default()){
+                            Ok(configured) => configured,
+                            Err(e) => {
+                                JunosConfigurationController::set_config_applied(&bgp_router, &ctx, false, "GetConfigFailed", e.to_string()).await?;
+                                return Err(ReconcileError::CrpdUnreachable(e))
                             }
+                        };
+                        // the config is compared again, neighbors can be
+                        // removed on the cRPD without the BgpRouter changing
+                        let missing = missing_neighbors(&expected_neighbors(&bgp_router), &configured);
+                        if !missing.is_empty(){
+                            let missing = missing.iter().map(|(group, neighbor)| format!("{}/{}", group, neighbor)).collect::<Vec<String>>().join(", ");
+                            JunosConfigurationController::set_config_applied(&bgp_router, &ctx, false, "NeighborsMissing",
+                                format!("neighbors {} are not configured", missing)).await?;
+                        } else {
+                            JunosConfigurationController::set_config_applied(&bgp_router, &ctx, true, "ConfigSynced", "".to_string()).await?;
                         }
-                        if let Some(status) = bgp_router.status{
-                            
-                        }
+                        return Ok(Action::requeue(std::time::Duration::from_secs(ctx.config.jet.status_interval_seconds)));
                     },
-                    None => {}
+                    Err(e) => {
+                        JunosConfigurationController::set_config_applied(&bgp_router, &ctx, false, "GetConfigFailed", e.to_string()).await?;
+                        return Err(ReconcileError::CrpdUnreachable(e))
+                    }
                 }
             },
+            Ok(None) => {},
             Err(e) => {
-                return Err(e);
+                JunosConfigurationController::set_config_applied(&bgp_router, &ctx, false, "Unreachable", e.to_string()).await?;
+                return Err(e)
+            },
+        }
+        Ok(Action::await_change())
+    }
+    async fn set_config_applied(bgp_router: &BgpRouter, ctx: &Context, applied: bool, reason: &str, message: String) -> Result<(), ReconcileError> {
+        let mut bgp_router = bgp_router.clone();
+        let generation = bgp_router.meta().generation;
+        let status = bgp_router.status.get_or_insert_with(BgpRouterStatus::default);
+        let mut conditions = status.conditions.clone().unwrap_or_default();
+        controllers::set_condition(&mut conditions, controllers::CONDITION_CONFIG_APPLIED, applied, reason, message, generation);
+        status.conditions = Some(conditions);
+        controllers::update_status(bgp_router, ctx.client.clone()).await?;
+        Ok(())
+    }
+    // connect opens a JET session to the cRPD pod owning the BgpRouter
+    async fn connect(bgp_router: &BgpRouter, ctx: &Context) -> Result<Option<junos::client::Client>, ReconcileError> {
+        let address = match &bgp_router.spec.address{
+            Some(address) => address,
+            None => return Ok(None),
+        };
+        let mut pod_name = None;
+        if let Some(owner_references) = &bgp_router.meta().owner_references{
+            owner_references.iter().for_each(|owner: &meta_v1::OwnerReference| {
+                if owner.kind == "Pod"{
+                    pod_name = Some(owner.name.clone())
+                }
+            });
+        }
+        let pod_name = match pod_name{
+            Some(pod_name) => pod_name,
+            None => return Ok(None),
+        };
+        let ca = match &ctx.ca{
+            Some(ca) => ca.clone(),
+            None => return Err(ReconcileError::CrpdUnreachable(anyhow::anyhow!("no CA to connect to {}", pod_name))),
+        };
+        match junos::client::Client::new(
+            address.clone(),
+            ctx.config.jet.port,
+            std::time::Duration::from_secs(ctx.config.jet.timeout_seconds),
+            pod_name,
+            ctx.key.clone().unwrap_or_default(),
+            ca,
+            ctx.cert.clone().unwrap_or_default()).await{
+            Ok(client) => Ok(Some(client)),
+            Err(e) => Err(ReconcileError::CrpdUnreachable(e)),
+        }
+    }
+    // cleanup removes the neighbors of a deleted BgpRouter from every managed
+    // cRPD peering with it and, for a managed router, its own groups before
+    // releasing the finalizer
+    async fn cleanup(bgp_router: BgpRouter, ctx: Arc<Context>) -> Result<Action, ReconcileError> {
+        if !controllers::has_finalizer(&bgp_router, controllers::JUNOS_FINALIZER){
+            return Ok(Action::await_change())
+        }
+        info!("junos config controller cleans up bgprouter {:?}", bgp_router.meta().name.as_ref().unwrap().clone());
+        let bgp_router_name = bgp_router.meta().name.as_ref().unwrap().clone();
+        let namespace = bgp_router.meta().namespace.as_ref().unwrap().clone();
+
+        // the peers still list the deleted router, the BgpRouterController
+        // only removes it once this finalizer is gone
+        let peers = cache::list::<BgpRouter>(&ctx.cache.bgp_routers, &namespace, None)?;
+        for peer in &peers{
+            if !is_managed(peer) || peer.meta().deletion_timestamp.is_some(){
+                continue;
+            }
+            let mut neighbors = Vec::new();
+            for bgp_peer_reference in peer.status.iter().flat_map(|status| status.bgp_peer_references.iter().flatten()){
+                if bgp_peer_reference.peer_reference.name.as_ref() != Some(&bgp_router_name){
+                    continue;
+                }
+                let group = match &bgp_peer_reference.bgp_router_group{
+                    Some(group) => group.clone(),
+                    None => "default".to_string(),
+                };
+                neighbors.push((group, bgp_peer_reference.session_attributes.peer_address.clone()));
+            }
+            if neighbors.is_empty(){
+                continue;
+            }
+            match JunosConfigurationController::connect(peer, &ctx).await{
+                Ok(Some(mut client)) => {
+                    let paths = neighbors.iter().map(|(group, neighbor)| format!("protocols bgp group {} neighbor {}", group, neighbor)).collect();
+                    let addresses = neighbors.iter().map(|(_, neighbor)| neighbor.clone()).collect::<Vec<String>>().join(", ");
+                    match client.delete(paths).await{
+                        Ok(_) => {
+                            controllers::publish_event(peer, &ctx, EventType::Normal, "CommitSucceeded", "Commit",
+                                format!("removed neighbor {}", addresses)).await;
+                        },
+                        Err(e) => {
+                            controllers::publish_event(peer, &ctx, EventType::Warning, "CommitFailed", "Commit",
+                                format!("failed to remove neighbor {}: {}", addresses, e)).await;
+                            return Err(ReconcileError::CommitRejected(e));
+                        },
+                    }
+                },
+                Ok(None) => {},
+                Err(e) => return Err(e),
             }
         }
+
+        // the cRPD of the deleted router is usually going away as well,
+        // so failing to reach it must not block the finalizer
+        let mut groups = Vec::new();
+        for (group, _) in expected_neighbors(&bgp_router){
+            if !groups.contains(&group){
+                groups.push(group);
+            }
+        }
+        if is_managed(&bgp_router) && !groups.is_empty(){
+            match JunosConfigurationController::connect(&bgp_router, &ctx).await{
+                Ok(Some(mut client)) => {
+                    let paths = groups.iter().map(|group| format!("protocols bgp group {}", group)).collect();
+                    if let Err(e) = client.delete(paths).await{
+                        warn!("failed to remove bgp groups from {:?}: {:?}", bgp_router.meta().name, e);
+                    }
+                },
+                Ok(None) => {},
+                Err(e) => {
+                    warn!("failed to connect to {:?}: {:?}", bgp_router.meta().name, e);
+                },
+            }
+        }
+
+        controllers::remove_finalizer(&bgp_router, controllers::JUNOS_FINALIZER, ctx.client.clone()).await?;
         Ok(Action::await_change())
     }
     fn error_policy(g: Arc<BgpRouter>, error: &ReconcileError, ctx: Arc<Context>) -> Action {
         warn!("reconcile failed: {:?}", error);
-        Action::requeue(Duration::from_secs(5))
+        metrics::metrics().reconcile_failure("junos_configuration");
+        let action = controllers::error_action("junos_configuration", g.as_ref(), error, &ctx);
+        let note = error.to_string();
+        tokio::spawn(async move {
+            controllers::publish_event(g.as_ref(), &ctx, EventType::Warning, "ReconcileFailed", "Reconcile", note).await
+        });
+        action
+    }
+}
+
+// is_managed tells whether the operator configures the cRPD of the BgpRouter
+fn is_managed(bgp_router: &BgpRouter) -> bool{
+    match &bgp_router.meta().labels{
+        Some(labels) => labels.get("cnm.juniper.net/bgpRouterManaged") == Some(&"true".to_string())
+            && matches!(bgp_router.spec.router_type, BgpRouterType::Crpd),
+        None => false,
+    }
+}
+
+// expected_neighbors returns the (group, neighbor address) pairs the peer
+// references of the BgpRouter configure on its cRPD
+fn expected_neighbors(bgp_router: &BgpRouter) -> Vec<(String, String)>{
+    let mut neighbors = Vec::new();
+    for bgp_peer_reference in bgp_router.status.iter().flat_map(|status| status.bgp_peer_references.iter().flatten()){
+        let group = match &bgp_peer_reference.bgp_router_group{
+            Some(group) => group.clone(),
+            None => "default".to_string(),
+        };
+        neighbors.push((group, bgp_peer_reference.session_attributes.peer_address.clone()));
+    }
+    neighbors
+}
+
+// configured_neighbors reads the (group, neighbor address) pairs of the
+// protocols bgp stanza from the json output of get-configuration
+fn configured_neighbors(config: &str) -> anyhow::Result<Vec<(String, String)>>{
+    if config.trim().is_empty(){
+        return Ok(Vec::new());
     }
+    let config: serde_json::Value = serde_json::from_str(config)?;
+    let groups = match config.pointer("/configuration/protocols/bgp/group").and_then(|groups| groups.as_array()){
+        Some(groups) => groups,
+        None => return Ok(Vec::new()),
+    };
+    let mut neighbors = Vec::new();
+    for group in groups{
+        let group_name = match group.get("name").and_then(|name| name.as_str()){
+            Some(group_name) => group_name,
+            None => continue,
+        };
+        for neighbor in group.get("neighbor").and_then(|neighbors| neighbors.as_array()).into_iter().flatten(){
+            if let Some(neighbor_name) = neighbor.get("name").and_then(|name| name.as_str()){
+                neighbors.push((group_name.to_string(), neighbor_name.to_string()));
+            }
+        }
+    }
+    Ok(neighbors)
+}
+
+// missing_neighbors returns the expected neighbors which are not configured
+fn missing_neighbors(expected: &[(String, String)], configured: &[(String, String)]) -> Vec<(String, String)>{
+    expected.iter().filter(|neighbor| !configured.contains(neighbor)).cloned().collect()
 }
 
 #[async_trait]
 impl Controller for JunosConfigurationController{
     async fn run(&self) -> anyhow::Result<()>{
         let reconcile = |g: Arc<BgpRouter>, ctx: Arc<Context>| {
-            async move { JunosConfigurationController::reconcile(g, ctx).await }
+            async move {
+                let _measurer = metrics::metrics().reconcile_start("junos_configuration");
+                let key = controllers::backoff_key("junos_configuration", g.as_ref());
+                // a failed object is only reconciled again once its backoff
+                // passed or its spec changed
+                if let Some(pending) = ctx.backoff.pending(&key, g.meta().generation){
+                    return Ok(Action::requeue(pending));
+                }
+                let res = JunosConfigurationController::reconcile(g, ctx.clone()).await;
+                if res.is_ok(){
+                    ctx.backoff.reset(&key);
+                }
+                res
+            }
         };
         let error_policy = |g: Arc<BgpRouter>, error: &ReconcileError, ctx: Arc<Context>| {
             JunosConfigurationController::error_policy(g, error, ctx)
         };
         
         let (ca, kp) = match controllers::get::<core_v1::Secret>(self.context.namespace.as_ref().unwrap().clone(), 
-        "cnm-ca".to_string(), self.context.client.clone()).await{
+        self.context.config.ca_secret.clone(), self.context.client.clone()).await{
             Ok(ca_secret) => {
                 match ca_secret {
                     Some((secret, _)) => {
@@ -182,14 +391,14 @@ impl Controller for JunosConfigurationController{
             }
         }
 
-        match controllers::create_or_update(junos_controller_secret, self.context.client.clone()).await{
+        match controllers::apply(junos_controller_secret, FIELD_MANAGER, true, self.context.client.clone()).await{
             Ok(_) => {},
             Err(e) => {
                 return Err(e.into());
             }
         }
 
-        let mut new_context = Context::new(self.context.client.clone());
+        let mut new_context = Context::new(self.context.client.clone(), self.context.config.clone(), self.context.cache.clone());
         new_context.ca = Some(ca.clone());
         new_context.cert = Some(cert.clone());
         new_context.key = Some(key.clone());
@@ -198,12 +407,12 @@ impl Controller for JunosConfigurationController{
         new_context.address = Some(self.context.address.as_ref().unwrap().clone());
 
 
-        let mut config = Config::default();
-        config.label_selector = Some("
-            cnm.juniper.net/bgpRouterManaged=true,
-            cnm.juniper.net/bgpRouterType=Crpd
-        ".to_string());
-        runtime_controller::new(self.resource.clone(), config)
+        // unmanaged routers are watched for their deletion, they can be
+        // neighbors of managed ones
+        let controller = controllers::new_controller::<BgpRouter>(&self.context, Config::default());
+        health::track("junos_configuration", controller.store());
+        controller
+            .graceful_shutdown_on(health::health().shutdown_requested())
             .run(reconcile, error_policy, Arc::new(new_context))
             .for_each(|res| async move {
                 match res {
@@ -212,6 +421,135 @@ impl Controller for JunosConfigurationController{
                 }
             })
             .await;
+        health::health().stopped("junos_configuration");
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::controllers::test_utils::{self, ApiServer};
+    use crate::resources::bgp_router::{BgpPeeringReference, BgpSessionAttributes};
+
+    fn deleted_bgp_router(name: &str, address: &str) -> BgpRouter{
+        let mut bgp_router = test_utils::bgp_router(name, Some(address), Some("group1"));
+        bgp_router.metadata.deletion_timestamp = Some(meta_v1::Time(k8s_openapi::chrono::Utc::now()));
+        bgp_router.metadata.finalizers = Some(vec![controllers::JUNOS_FINALIZER.to_string()]);
+        bgp_router
+    }
+
+    // managed_peer returns a managed BgpRouter of a cRPD pod peering with the named router
+    fn managed_peer(name: &str, peer: &str, peer_address: &str) -> BgpRouter{
+        let mut bgp_router = test_utils::bgp_router(name, Some("10.0.0.1"), Some("group1"));
+        bgp_router.metadata.labels.as_mut().unwrap().insert("cnm.juniper.net/bgpRouterManaged".to_string(), "true".to_string());
+        bgp_router.metadata.owner_references = Some(vec![meta_v1::OwnerReference{
+            kind: "Pod".to_string(),
+            name: "crpd1-0".to_string(),
+            ..Default::default()
+        }]);
+        bgp_router.status = Some(BgpRouterStatus{
+            bgp_peer_references: Some(vec![BgpPeeringReference{
+                peer_reference: core_v1::ObjectReference{
+                    name: Some(peer.to_string()),
+                    ..Default::default()
+                },
+                bgp_router_group: Some("group1".to_string()),
+                session_attributes: BgpSessionAttributes{
+                    peer_address: peer_address.to_string(),
+                    ..Default::default()
+                },
+            }]),
+            ..Default::default()
+        });
+        bgp_router
+    }
+
+    #[tokio::test]
+    async fn reconcile_leaves_unpeered_unmanaged_bgp_router_alone(){
+        let server = ApiServer::new();
+        let bgp_router = test_utils::bgp_router("external1", Some("10.0.0.9"), None);
+        server.add(&bgp_router);
+
+        let action = JunosConfigurationController::reconcile(Arc::new(bgp_router), server.context()).await.unwrap();
+        assert_eq!(action, Action::await_change());
+        assert!(!controllers::has_finalizer(&server.get::<BgpRouter>("external1").unwrap(), controllers::JUNOS_FINALIZER));
+    }
+
+    #[tokio::test]
+    async fn reconcile_adds_finalizer_to_peers_of_managed_bgp_router(){
+        let server = ApiServer::new();
+        server.add(&test_utils::bgp_router("external1", Some("10.0.0.9"), None));
+        server.add(&test_utils::bgp_router("external2", Some("10.0.0.8"), None));
+        let managed = managed_peer("router1", "external1", "10.0.0.9");
+        server.add(&managed);
+
+        // the test context can't open a JET session, the finalizers are
+        // added before connecting
+        let res = JunosConfigurationController::reconcile(Arc::new(managed), server.context()).await;
+        assert!(matches!(res, Err(ReconcileError::CrpdUnreachable(_))));
+        assert!(controllers::has_finalizer(&server.get::<BgpRouter>("router1").unwrap(), controllers::JUNOS_FINALIZER));
+        assert!(controllers::has_finalizer(&server.get::<BgpRouter>("external1").unwrap(), controllers::JUNOS_FINALIZER));
+        assert!(!controllers::has_finalizer(&server.get::<BgpRouter>("external2").unwrap(), controllers::JUNOS_FINALIZER));
+    }
+
+    #[tokio::test]
+    async fn reconcile_releases_unreferenced_bgp_router(){
+        let server = ApiServer::new();
+        let bgp_router = deleted_bgp_router("external1", "10.0.0.9");
+        server.add(&bgp_router);
+        server.add(&managed_peer("router1", "other", "10.0.0.8"));
+
+        JunosConfigurationController::reconcile(Arc::new(bgp_router), server.context()).await.unwrap();
+        assert!(!controllers::has_finalizer(&server.get::<BgpRouter>("external1").unwrap(), controllers::JUNOS_FINALIZER));
+    }
+
+    #[tokio::test]
+    async fn reconcile_removes_unmanaged_neighbor_from_managed_peers(){
+        let server = ApiServer::new();
+        let bgp_router = deleted_bgp_router("external1", "10.0.0.9");
+        server.add(&bgp_router);
+        server.add(&managed_peer("router1", "external1", "10.0.0.9"));
+
+        // the test context can't open a JET session, the neighbor stays and
+        // so does the finalizer
+        let res = JunosConfigurationController::reconcile(Arc::new(bgp_router), server.context()).await;
+        assert!(matches!(res, Err(ReconcileError::CrpdUnreachable(_))));
+        assert!(controllers::has_finalizer(&server.get::<BgpRouter>("external1").unwrap(), controllers::JUNOS_FINALIZER));
+    }
+
+    #[test]
+    fn configured_neighbors_reads_bgp_groups(){
+        let config = r#"{
+            "configuration": {
+                "system": {"host-name": "crpd1-0"},
+                "protocols": {
+                    "bgp": {
+                        "group": [
+                            {"name": "group1", "neighbor": [{"name": "10.0.0.8"}, {"name": "10.0.0.9"}]},
+                            {"name": "group2"}
+                        ]
+                    }
+                }
+            }
+        }"#;
+        assert_eq!(configured_neighbors(config).unwrap(), vec![
+            ("group1".to_string(), "10.0.0.8".to_string()),
+            ("group1".to_string(), "10.0.0.9".to_string()),
+        ]);
+        assert!(configured_neighbors(r#"{"configuration": {}}"#).unwrap().is_empty());
+        assert!(configured_neighbors("").unwrap().is_empty());
+        assert!(configured_neighbors("<configuration/>").is_err());
+    }
+
+    #[test]
+    fn missing_neighbors_compares_groups_and_addresses(){
+        let bgp_router = managed_peer("router1", "external1", "10.0.0.9");
+        let expected = expected_neighbors(&bgp_router);
+        assert_eq!(expected, vec![("group1".to_string(), "10.0.0.9".to_string())]);
+
+        assert!(missing_neighbors(&expected, &expected).is_empty());
+        assert_eq!(missing_neighbors(&expected, &[("group2".to_string(), "10.0.0.9".to_string())]), expected);
+        assert_eq!(missing_neighbors(&expected, &[]), expected);
+    }
+}
@@ -0,0 +1,225 @@
+use crate::controllers::controllers::{self, Context, ReconcileError};
+use crate::controllers::crpd::junos::{self, op};
+use crate::resources::crpd::crpd::{Crpd, Instance, LicenseStatus};
+use kube::Resource;
+use k8s_openapi::api::core::v1 as core_v1;
+use tracing::*;
+
+// Licenses are installed by the CrpdController over JET: the key from
+// spec.licenseSecretRef is committed on every instance which doesn't have it
+// yet and the license summary of the instance is reported in its Instance
+// status. The JET session is the one the operational state is read with,
+// see instance.rs.
+
+// License is the license of a Crpd to install on its instances
+pub enum License{
+    // the Crpd has no licenseSecretRef
+    None,
+    // the key could not be read from the secret
+    Unavailable(String),
+    Key(String),
+}
+
+// license reads the license key of the Crpd from its secret
+pub async fn license(crpd: &Crpd, ctx: &Context) -> Result<License, ReconcileError>{
+    let secret_ref = match &crpd.spec.license_secret_ref{
+        Some(secret_ref) => secret_ref,
+        None => return Ok(License::None),
+    };
+    let namespace = crpd.meta().namespace.as_ref().unwrap().clone();
+    let secret = match controllers::get::<core_v1::Secret>(namespace.clone(), secret_ref.name.clone().unwrap_or_default(), ctx.client.clone()).await?{
+        Some((secret, _)) => secret,
+        None => return Ok(License::Unavailable(format!("license secret {} not found", secret_ref.name.clone().unwrap_or_default()))),
+    };
+    let key = match secret.data.as_ref().and_then(|data| data.get(&secret_ref.key)){
+        Some(key) => String::from_utf8_lossy(&key.0).to_string(),
+        None => return Ok(License::Unavailable(format!("key {} not found in license secret {}", secret_ref.key, secret_ref.name.clone().unwrap_or_default()))),
+    };
+    // the key is committed as a quoted word
+    if key.contains(['"', '\\']){
+        return Ok(License::Unavailable(format!("key {} of license secret {} must not contain quotes or backslashes", secret_ref.key, secret_ref.name.clone().unwrap_or_default())));
+    }
+    Ok(License::Key(key))
+}
+
+impl License{
+    // install installs the license on the instance behind client and
+    // returns the license state of the instance
+    pub async fn install(&self, instance: &Instance, client: &mut junos::client::Client) -> Option<LicenseStatus>{
+        let key = match self{
+            License::Key(key) => key,
+            _ => return self.not_installed(String::new()),
+        };
+        match install(client, key).await{
+            Ok(license) => Some(license),
+            Err(e) => {
+                warn!("failed to install license on {}: {:?}", instance.name, e);
+                self.not_installed(e.to_string())
+            }
+        }
+    }
+
+    // not_installed is the license state of an instance the license could
+    // not be installed on, the reason of an unavailable key wins
+    pub fn not_installed(&self, message: String) -> Option<LicenseStatus>{
+        let message = match self{
+            License::None => return None,
+            License::Unavailable(message) => message.clone(),
+            License::Key(_) => message,
+        };
+        Some(LicenseStatus{
+            installed: false,
+            message: Some(message),
+            ..Default::default()
+        })
+    }
+}
+
+async fn install(client: &mut junos::client::Client, key: &str) -> anyhow::Result<LicenseStatus>{
+    let installed = match client.license_keys().await?{
+        Some(keys) => has_key(&keys, key)?,
+        None => false,
+    };
+    if !installed{
+        client.add_license(key).await?;
+    }
+    match client.license_summary().await?{
+        Some(summary) => parse_license_summary(&summary),
+        None => Err(anyhow::anyhow!("empty license summary")),
+    }
+}
+
+// has_key checks whether the output of get-license-key-information holds
+// the key. Junos breaks a key into lines, whitespace is ignored.
+pub fn has_key(keys: &str, key: &str) -> anyhow::Result<bool>{
+    let keys: serde_json::Value = serde_json::from_str(keys)?;
+    let key: String = key.split_whitespace().collect();
+    if key.is_empty(){
+        return Ok(false);
+    }
+    Ok(op::values(&keys, "license-key").iter().any(|license_key| {
+        let mut text = String::new();
+        leaves(license_key, &mut text);
+        text.contains(&key)
+    }))
+}
+
+// leaves appends the string leaves of value without whitespace
+fn leaves(value: &serde_json::Value, text: &mut String){
+    match value{
+        serde_json::Value::String(leaf) => text.extend(leaf.split_whitespace()),
+        serde_json::Value::Array(items) => items.iter().for_each(|item| leaves(item, text)),
+        serde_json::Value::Object(object) => object.values().for_each(|item| leaves(item, text)),
+        _ => {},
+    }
+}
+
+// parse_license_summary reads the output of get-license-summary-information
+pub fn parse_license_summary(summary: &str) -> anyhow::Result<LicenseStatus>{
+    let summary: serde_json::Value = serde_json::from_str(summary)?;
+    let mut license = LicenseStatus::default();
+    for feature in op::values(&summary, "feature-summary"){
+        let licensed = op::data(feature, "licensed")
+            .and_then(|licensed| licensed.parse::<i64>().ok())
+            .unwrap_or(0);
+        if licensed == 0{
+            continue;
+        }
+        if let Some(name) = op::data(feature, "name"){
+            license.features.push(name);
+        }
+        if let Some(end_date) = op::data(feature, "end-date"){
+            if license.expiry.as_ref().map(|expiry| &end_date < expiry).unwrap_or(true){
+                license.expiry = Some(end_date);
+            }
+        }
+    }
+    license.installed = !license.features.is_empty();
+    Ok(license)
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::controllers::test_utils::{self, ApiServer, NAMESPACE};
+    use k8s_openapi::ByteString;
+    use std::collections::BTreeMap;
+
+    #[tokio::test]
+    async fn license_rejects_keys_with_quotes(){
+        let server = ApiServer::new();
+        let mut crpd = test_utils::crpd("crpd1", 1);
+        crpd.spec.license_secret_ref = Some(core_v1::SecretKeySelector{
+            name: Some("license".to_string()),
+            key: "key".to_string(),
+            optional: None,
+        });
+        server.add(&core_v1::Secret{
+            metadata: kube::api::ObjectMeta{
+                name: Some("license".to_string()),
+                namespace: Some(NAMESPACE.to_string()),
+                ..Default::default()
+            },
+            data: Some(BTreeMap::from([("key".to_string(), ByteString(b"E123456789\"; delete system".to_vec()))])),
+            ..Default::default()
+        });
+
+        let license = license(&crpd, &server.context()).await.unwrap();
+        assert!(matches!(license, License::Unavailable(_)));
+        let status = license.not_installed("unreachable".to_string()).unwrap();
+        assert!(!status.installed);
+        assert!(status.message.unwrap().contains("must not contain quotes"));
+    }
+
+    #[test]
+    fn parse_license_summary_reports_licensed_features(){
+        let summary = r#"{
+            "license-summary-information": [{
+                "license-usage-summary": [{
+                    "feature-summary": [
+                        {
+                            "name": [{"data": "bgp"}],
+                            "licensed": [{"data": "1"}],
+                            "end-date": [{"data": "2025-06-30"}]
+                        },
+                        {
+                            "name": [{"data": "isis"}],
+                            "licensed": [{"data": "1"}],
+                            "end-date": [{"data": "2025-03-31"}]
+                        },
+                        {
+                            "name": [{"data": "ospf"}],
+                            "licensed": [{"data": "0"}]
+                        }
+                    ]
+                }]
+            }]
+        }"#;
+        let license = parse_license_summary(summary).unwrap();
+        assert!(license.installed);
+        assert_eq!(license.features, vec!["bgp", "isis"]);
+        assert_eq!(license.expiry, Some("2025-03-31".to_string()));
+    }
+
+    #[test]
+    fn has_key_ignores_line_breaks(){
+        let keys = r#"{
+            "license-key-information": [{
+                "license-key": [{
+                    "key-data": [{"data": "E123456789 aeaqic aiagca\n  cmbqgi ybgkgi"}]
+                }]
+            }]
+        }"#;
+        assert!(has_key(keys, "E123456789 aeaqic aiagca cmbqgi ybgkgi").unwrap());
+        assert!(!has_key(keys, "E987654321 aeaqic").unwrap());
+        assert!(!has_key(r#"{"license-key-information": [{}]}"#, "E123456789").unwrap());
+    }
+
+    #[test]
+    fn parse_license_summary_without_licenses(){
+        let license = parse_license_summary(r#"{"license-summary-information": [{}]}"#).unwrap();
+        assert!(!license.installed);
+        assert!(license.features.is_empty());
+        assert_eq!(license.expiry, None);
+    }
+}
@@ -1,4 +1,7 @@
 pub mod crpd;
 pub mod bgp_router_group;
 pub mod junos_configuration;
+pub mod upgrade;
+pub mod license;
+pub mod instance;
 pub mod junos;
\ No newline at end of file
@@ -0,0 +1,325 @@
+use crate::controllers::controllers::{self, Context, ReconcileError};
+use crate::controllers::cache;
+use crate::resources::crpd::crpd::{Crpd, Instance, UpgradeStatus};
+use crate::resources::bgp_router::BgpRouter;
+use kube::Resource;
+use kube::runtime::events::EventType;
+use k8s_openapi::api::apps::v1 as apps_v1;
+use k8s_openapi::api::core::v1 as core_v1;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1 as meta_v1;
+use tracing::*;
+
+// Image upgrades are driven by the CrpdController instead of the StatefulSet.
+// The StatefulSet runs with the OnDelete strategy and the instances are
+// replaced one at a time: the next one only once the replaced instance is
+// ready, its BgpRouters are back and it established at least as many BGP
+// sessions as before it was replaced. crpd-init enables graceful restart,
+// so the peers keep the routes of an instance while it is replaced.
+//
+// In DaemonSet mode the pods are rolled by the DaemonSet controller, one
+// node at a time and without waiting for the BGP sessions.
+
+const DEFAULT_UPGRADE_TIMEOUT_SECONDS: i64 = 600;
+const REVISION_LABEL: &str = "controller-revision-hash";
+
+pub enum Upgrade{
+    // every instance runs the current revision
+    Complete,
+    InProgress(String),
+    // the replaced instance didn't come back in time, no further instance
+    // is replaced until it does
+    Failed(String),
+}
+
+// upgrade advances the upgrade of the Crpd's instances by at most one step
+// and records it in the status
+pub async fn upgrade(crpd: &mut Crpd, sts: &apps_v1::StatefulSet, pods: &[core_v1::Pod], instances: &[Instance], ctx: &Context) -> Result<Upgrade, ReconcileError>{
+    let revision = match sts.status.as_ref().and_then(|status| status.update_revision.clone()){
+        Some(revision) => revision,
+        None => return Ok(Upgrade::Complete),
+    };
+    let timeout = crpd.spec.upgrade_timeout_seconds.unwrap_or(DEFAULT_UPGRADE_TIMEOUT_SECONDS);
+    // a new revision, e.g. a rollback, starts over
+    let mut upgrade = match crpd.status.as_ref().and_then(|status| status.upgrade.clone()){
+        Some(upgrade) if upgrade.revision == revision => upgrade,
+        _ => UpgradeStatus{
+            revision: revision.clone(),
+            ..Default::default()
+        },
+    };
+
+    if let Some(instance) = upgrade.instance.clone(){
+        let back = match pods.iter().find(|pod| pod.meta().name.as_ref() == Some(&instance)){
+            Some(pod) => pod_revision(pod) == Some(&revision) && instance_ready(pod, instances, &upgrade, ctx)?,
+            None => false,
+        };
+        if !back{
+            let elapsed = match &upgrade.started{
+                Some(started) => (k8s_openapi::chrono::Utc::now() - started.0).num_seconds(),
+                None => 0,
+            };
+            crpd.status.get_or_insert_with(Default::default).upgrade = Some(upgrade);
+            if elapsed > timeout{
+                return Ok(Upgrade::Failed(format!("instance {} did not come back within {}s", instance, timeout)));
+            }
+            return Ok(Upgrade::InProgress(format!("waiting for instance {}", instance)));
+        }
+        controllers::publish_event(crpd, ctx, EventType::Normal, "InstanceUpgraded", "UpgradeInstance",
+            format!("instance {} runs revision {}", instance, revision)).await;
+        upgrade.instance = None;
+        upgrade.started = None;
+        upgrade.bgp_routers = 0;
+        upgrade.bgp_sessions = 0;
+    }
+
+    // like the StatefulSet controller, the highest ordinal is replaced first
+    let next = pods.iter()
+        .filter(|pod| pod_revision(pod) != Some(&revision) && pod.meta().deletion_timestamp.is_none())
+        .max_by_key(|pod| ordinal(pod));
+    let pod = match next{
+        Some(pod) => pod,
+        None => {
+            crpd.status.get_or_insert_with(Default::default).upgrade = None;
+            return Ok(Upgrade::Complete);
+        }
+    };
+    let namespace = pod.meta().namespace.as_ref().unwrap().clone();
+    let name = pod.meta().name.as_ref().unwrap().clone();
+    let bgp_routers = owned_bgp_routers(pod, &ctx.cache.bgp_routers)?.len() as i32;
+    let bgp_sessions = established_bgp_sessions(&name, instances).unwrap_or(0);
+    info!("upgrading instance {} to revision {}", name, revision);
+    controllers::delete::<core_v1::Pod>(namespace, name.clone(), ctx.client.clone()).await?;
+    controllers::publish_event(crpd, ctx, EventType::Normal, "InstanceUpgradeStarted", "UpgradeInstance",
+        format!("replacing instance {} with revision {}", name, revision)).await;
+    upgrade.instance = Some(name.clone());
+    upgrade.started = Some(meta_v1::Time(k8s_openapi::chrono::Utc::now()));
+    upgrade.bgp_routers = bgp_routers;
+    upgrade.bgp_sessions = bgp_sessions;
+    crpd.status.get_or_insert_with(Default::default).upgrade = Some(upgrade);
+    Ok(Upgrade::InProgress(format!("replacing instance {}", name)))
+}
+
+fn pod_revision(pod: &core_v1::Pod) -> Option<&String>{
+    pod.meta().labels.as_ref().and_then(|labels| labels.get(REVISION_LABEL))
+}
+
+fn ordinal(pod: &core_v1::Pod) -> i64{
+    pod.meta().name.as_ref()
+        .and_then(|name| name.rsplit('-').next())
+        .and_then(|ordinal| ordinal.parse().ok())
+        .unwrap_or(-1)
+}
+
+// instance_ready is true once the pod is ready, at least as many
+// BgpRouters as before the upgrade are owned by it and the instance reports
+// at least as many established BGP sessions as before it was replaced
+fn instance_ready(pod: &core_v1::Pod, instances: &[Instance], upgrade: &UpgradeStatus, ctx: &Context) -> Result<bool, ReconcileError>{
+    let pod_ready = pod.status.as_ref()
+        .and_then(|status| status.conditions.as_ref())
+        .map(|conditions| conditions.iter().any(|condition| condition.type_ == "Ready" && condition.status == "True"))
+        .unwrap_or(false);
+    if !pod_ready{
+        return Ok(false);
+    }
+    let owned = owned_bgp_routers(pod, &ctx.cache.bgp_routers)?;
+    if (owned.len() as i32) < upgrade.bgp_routers{
+        return Ok(false);
+    }
+    // the sessions are read over JET by this reconcile, an instance which
+    // can't be read isn't back
+    match established_bgp_sessions(pod.meta().name.as_ref().unwrap(), instances){
+        Some(established) => Ok(established >= upgrade.bgp_sessions),
+        None => Ok(false),
+    }
+}
+
+fn established_bgp_sessions(name: &str, instances: &[Instance]) -> Option<i32>{
+    instances.iter()
+        .find(|instance| instance.name == name)
+        .and_then(|instance| instance.operational.as_ref())
+        .and_then(|operational| operational.established_bgp_sessions)
+}
+
+fn owned_bgp_routers(pod: &core_v1::Pod, store: &kube::runtime::reflector::Store<BgpRouter>) -> Result<Vec<BgpRouter>, ReconcileError>{
+    let bgp_routers = cache::list(store, pod.meta().namespace.as_ref().unwrap(), None)?;
+    Ok(bgp_routers.into_iter()
+        .filter(|bgp_router| {
+            bgp_router.meta().owner_references.iter().flatten().any(|owner| {
+                owner.kind == "Pod" && Some(&owner.uid) == pod.meta().uid.as_ref()
+            })
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::controllers::test_utils::{self, ApiServer};
+    use crate::resources::crpd::crpd::{CrpdStatus, OperationalStatus};
+    use k8s_openapi::chrono::{Duration, Utc};
+
+    fn pod(crpd: &Crpd, name: &str, revision: &str) -> core_v1::Pod{
+        let mut pod = test_utils::pod(crpd, name, Some("10.0.0.1"));
+        pod.metadata.labels.as_mut().unwrap().insert(REVISION_LABEL.to_string(), revision.to_string());
+        pod.status.as_mut().unwrap().conditions = Some(vec![core_v1::PodCondition{
+            type_: "Ready".to_string(),
+            status: "True".to_string(),
+            ..Default::default()
+        }]);
+        pod
+    }
+
+    fn stateful_set(crpd: &Crpd, revision: &str) -> apps_v1::StatefulSet{
+        let mut sts = test_utils::stateful_set(crpd, 2);
+        sts.status.as_mut().unwrap().update_revision = Some(revision.to_string());
+        sts
+    }
+
+    fn instance(name: &str, established_bgp_sessions: i32) -> Instance{
+        Instance{
+            name: name.to_string(),
+            operational: Some(OperationalStatus{
+                authenticated: true,
+                established_bgp_sessions: Some(established_bgp_sessions),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn owned_bgp_router(name: &str, pod: &str) -> BgpRouter{
+        let mut bgp_router = test_utils::bgp_router(name, Some("10.0.0.1"), Some("group1"));
+        bgp_router.metadata.owner_references = Some(vec![meta_v1::OwnerReference{
+            kind: "Pod".to_string(),
+            name: pod.to_string(),
+            uid: format!("uid-{}", pod),
+            ..Default::default()
+        }]);
+        bgp_router
+    }
+
+    fn upgrading(crpd: &mut Crpd, instance: &str, started: meta_v1::Time){
+        crpd.status = Some(CrpdStatus{
+            upgrade: Some(UpgradeStatus{
+                revision: "rev2".to_string(),
+                instance: Some(instance.to_string()),
+                started: Some(started),
+                bgp_routers: 1,
+                bgp_sessions: 2,
+            }),
+            ..Default::default()
+        });
+    }
+
+    #[tokio::test]
+    async fn upgrade_replaces_highest_outdated_instance(){
+        let server = ApiServer::new();
+        let mut crpd = test_utils::crpd("crpd1", 2);
+        let pods = vec![pod(&crpd, "crpd1-0", "rev1"), pod(&crpd, "crpd1-1", "rev1")];
+        for pod in &pods{
+            server.add(pod);
+        }
+
+        let instances = vec![instance("crpd1-0", 2), instance("crpd1-1", 3)];
+
+        let sts = stateful_set(&crpd, "rev2");
+        let res = upgrade(&mut crpd, &sts, &pods, &instances, &server.context()).await.unwrap();
+        assert!(matches!(res, Upgrade::InProgress(_)));
+        assert!(server.get::<core_v1::Pod>("crpd1-0").is_some());
+        assert!(server.get::<core_v1::Pod>("crpd1-1").is_none());
+        let upgrade = crpd.status.unwrap().upgrade.unwrap();
+        assert_eq!(upgrade.instance, Some("crpd1-1".to_string()));
+        assert_eq!(upgrade.bgp_sessions, 3);
+        assert_eq!(server.event_reasons(), vec!["InstanceUpgradeStarted"]);
+    }
+
+    #[tokio::test]
+    async fn upgrade_waits_for_bgp_routers_of_replaced_instance(){
+        let server = ApiServer::new();
+        let mut crpd = test_utils::crpd("crpd1", 2);
+        upgrading(&mut crpd, "crpd1-1", meta_v1::Time(Utc::now()));
+        let pods = vec![pod(&crpd, "crpd1-0", "rev1"), pod(&crpd, "crpd1-1", "rev2")];
+        for pod in &pods{
+            server.add(pod);
+        }
+        let instances = vec![instance("crpd1-0", 2), instance("crpd1-1", 2)];
+
+        let sts = stateful_set(&crpd, "rev2");
+        let res = upgrade(&mut crpd, &sts, &pods, &instances, &server.context()).await.unwrap();
+        assert!(matches!(res, Upgrade::InProgress(_)));
+        assert!(server.get::<core_v1::Pod>("crpd1-0").is_some());
+    }
+
+    #[tokio::test]
+    async fn upgrade_waits_for_bgp_sessions_of_replaced_instance(){
+        let server = ApiServer::new();
+        let mut crpd = test_utils::crpd("crpd1", 2);
+        upgrading(&mut crpd, "crpd1-1", meta_v1::Time(Utc::now()));
+        let pods = vec![pod(&crpd, "crpd1-0", "rev1"), pod(&crpd, "crpd1-1", "rev2")];
+        for pod in &pods{
+            server.add(pod);
+        }
+        server.add(&owned_bgp_router("crpd1-1-group1", "crpd1-1"));
+
+        // one of the two sessions is still down or the instance can't be read
+        let unreadable = Instance{
+            name: "crpd1-1".to_string(),
+            ..Default::default()
+        };
+        for instances in [vec![instance("crpd1-0", 2), instance("crpd1-1", 1)], vec![unreadable]]{
+            let sts = stateful_set(&crpd, "rev2");
+            let res = upgrade(&mut crpd, &sts, &pods, &instances, &server.context()).await.unwrap();
+            assert!(matches!(res, Upgrade::InProgress(_)));
+            assert!(server.get::<core_v1::Pod>("crpd1-0").is_some());
+        }
+    }
+
+    #[tokio::test]
+    async fn upgrade_continues_once_replaced_instance_is_back(){
+        let server = ApiServer::new();
+        let mut crpd = test_utils::crpd("crpd1", 2);
+        upgrading(&mut crpd, "crpd1-1", meta_v1::Time(Utc::now()));
+        let pods = vec![pod(&crpd, "crpd1-0", "rev1"), pod(&crpd, "crpd1-1", "rev2")];
+        for pod in &pods{
+            server.add(pod);
+        }
+        server.add(&owned_bgp_router("crpd1-1-group1", "crpd1-1"));
+        let instances = vec![instance("crpd1-0", 2), instance("crpd1-1", 2)];
+
+        let sts = stateful_set(&crpd, "rev2");
+        let res = upgrade(&mut crpd, &sts, &pods, &instances, &server.context()).await.unwrap();
+        assert!(matches!(res, Upgrade::InProgress(_)));
+        assert!(server.get::<core_v1::Pod>("crpd1-0").is_none());
+        assert_eq!(server.event_reasons().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn upgrade_halts_when_replaced_instance_does_not_come_back(){
+        let server = ApiServer::new();
+        let mut crpd = test_utils::crpd("crpd1", 2);
+        upgrading(&mut crpd, "crpd1-1", meta_v1::Time(Utc::now() - Duration::seconds(DEFAULT_UPGRADE_TIMEOUT_SECONDS + 1)));
+        let pods = vec![pod(&crpd, "crpd1-0", "rev1")];
+        server.add(&pods[0]);
+
+        let sts = stateful_set(&crpd, "rev2");
+        let res = upgrade(&mut crpd, &sts, &pods, &[], &server.context()).await.unwrap();
+        assert!(matches!(res, Upgrade::Failed(_)));
+        assert!(server.get::<core_v1::Pod>("crpd1-0").is_some());
+        assert!(crpd.status.unwrap().upgrade.is_some());
+    }
+
+    #[tokio::test]
+    async fn upgrade_is_complete_when_all_instances_are_current(){
+        let server = ApiServer::new();
+        let mut crpd = test_utils::crpd("crpd1", 2);
+        upgrading(&mut crpd, "crpd1-1", meta_v1::Time(Utc::now()));
+        crpd.status.as_mut().unwrap().upgrade.as_mut().unwrap().bgp_routers = 0;
+        let pods = vec![pod(&crpd, "crpd1-0", "rev2"), pod(&crpd, "crpd1-1", "rev2")];
+        let instances = vec![instance("crpd1-0", 2), instance("crpd1-1", 2)];
+
+        let sts = stateful_set(&crpd, "rev2");
+        let res = upgrade(&mut crpd, &sts, &pods, &instances, &server.context()).await.unwrap();
+        assert!(matches!(res, Upgrade::Complete));
+        assert!(crpd.status.unwrap().upgrade.is_none());
+    }
+}
@@ -1,4 +1,8 @@
 pub mod controllers;
+pub mod cache;
+pub mod registry;
 pub mod routing_instance;
 pub mod bgp_router;
-pub mod crpd;
\ No newline at end of file
+pub mod crpd;
+#[cfg(test)]
+pub mod test_utils;
@@ -0,0 +1,28 @@
+use crate::config::config::ControllerKind;
+use crate::controllers::controllers::{Controller, Context};
+use crate::controllers::crpd::crpd::CrpdController;
+use crate::controllers::crpd::bgp_router_group::BgpRouterGroupController;
+use crate::controllers::crpd::junos_configuration::JunosConfigurationController;
+use crate::controllers::bgp_router::BgpRouterController;
+use crate::controllers::routing_instance::RoutingInstanceController;
+use std::sync::Arc;
+use tracing::*;
+
+// controllers returns the enabled controllers which run under the lease.
+// The admission webhook is served by every replica and not part of it.
+pub fn controllers(ctx: Arc<Context>) -> Vec<Box<dyn Controller>>{
+    let mut controller_list: Vec<Box<dyn Controller>> = Vec::new();
+    for kind in &ctx.config.controllers{
+        let controller: Box<dyn Controller> = match kind{
+            ControllerKind::Crpd => Box::new(CrpdController::new(ctx.clone())),
+            ControllerKind::BgpRouter => Box::new(BgpRouterController::new(ctx.clone())),
+            ControllerKind::BgpRouterGroup => Box::new(BgpRouterGroupController::new(ctx.clone())),
+            ControllerKind::JunosConfiguration => Box::new(JunosConfigurationController::new(ctx.clone())),
+            ControllerKind::RoutingInstance => Box::new(RoutingInstanceController::new(ctx.clone())),
+            ControllerKind::Admission => continue,
+        };
+        info!("enabling {:?} controller", kind);
+        controller_list.push(controller);
+    }
+    controller_list
+}
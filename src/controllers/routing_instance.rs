@@ -1,11 +1,13 @@
 use crate::controllers::controllers::{Controller, Context, ReconcileError};
-use crate::controllers::controllers;
+use crate::metrics::metrics;
+use crate::health::health;
+use kube::runtime::events::EventType;
+use crate::controllers::{controllers, cache};
 use crate::resources::routing_instance::RoutingInstance;
 use kube::Resource;
 use async_trait::async_trait;
 use futures::StreamExt;
 use kube::{
-    api::Api,
     client::Client,
     runtime::{
         controller::{Action, Controller as runtime_controller},
@@ -13,47 +15,35 @@ use kube::{
     },
 };
 use std::sync::Arc;
-use tokio::time::Duration;
 use tracing::*;
 
 pub struct RoutingInstanceController{
     context: Arc<Context>,
-    resource: Api<RoutingInstance>,
 }
 
 impl RoutingInstanceController{
-    pub fn new(client: Client) -> Self{
-        let resource = Api::all(client.clone());
-        let context = Arc::new(Context::new(client.clone()));
-        RoutingInstanceController{context, resource}
+    pub fn new(context: Arc<Context>) -> Self{
+        let context = context.clone();
+        RoutingInstanceController{context}
     }
     async fn reconcile(g: Arc<RoutingInstance>, ctx: Arc<Context>) ->  Result<Action, ReconcileError> {
         info!("reconciling RoutingInstance {:?}", g.meta().name.as_ref().unwrap().clone());
-        match controllers::get::<RoutingInstance>(
-            g.meta().namespace.as_ref().unwrap().clone(),
-            g.meta().name.as_ref().unwrap().clone(),
-            ctx.client.clone())
-            .await{
-            Ok(res) => {
-                match res{
-                    Some((mut routing_instance, _api)) => {
-                        let match_labels = if let Some(match_labels) = &routing_instance.spec.selector.match_labels{
-                            match_labels
-                        } else {
-                            return Ok(Action::await_change())
-                        };
-                    }
-                    None => {}
-                }
-            },
-            Err(e) => return Err(e)
-            
+        let routing_instance = g.as_ref();
+        let crpd_list = cache::list(&ctx.cache.crpds, routing_instance.meta().namespace.as_ref().unwrap(), Some(&routing_instance.spec.selector))?;
+        for crpd in &crpd_list{
+            info!("RoutingInstance {:?} selects Crpd {:?}", routing_instance.meta().name, crpd.meta().name);
         }
         Ok(Action::await_change())
     }
     fn error_policy(g: Arc<RoutingInstance>, error: &ReconcileError, ctx: Arc<Context>) -> Action {
         warn!("reconcile failed: {:?}", error);
-        Action::requeue(Duration::from_secs(5 * 60))
+        metrics::metrics().reconcile_failure("routing_instance");
+        let action = controllers::error_action("routing_instance", g.as_ref(), error, &ctx);
+        let note = error.to_string();
+        tokio::spawn(async move {
+            controllers::publish_event(g.as_ref(), &ctx, EventType::Warning, "ReconcileFailed", "Reconcile", note).await
+        });
+        action
     }
 }
 
@@ -61,12 +51,28 @@ impl RoutingInstanceController{
 impl Controller for RoutingInstanceController{
     async fn run(&self) -> anyhow::Result<()>{
         let reconcile = |g: Arc<RoutingInstance>, ctx: Arc<Context>| {
-            async move { RoutingInstanceController::reconcile(g, ctx).await }
+            async move {
+                let _measurer = metrics::metrics().reconcile_start("routing_instance");
+                let key = controllers::backoff_key("routing_instance", g.as_ref());
+                // a failed object is only reconciled again once its backoff
+                // passed or its spec changed
+                if let Some(pending) = ctx.backoff.pending(&key, g.meta().generation){
+                    return Ok(Action::requeue(pending));
+                }
+                let res = RoutingInstanceController::reconcile(g, ctx.clone()).await;
+                if res.is_ok(){
+                    ctx.backoff.reset(&key);
+                }
+                res
+            }
         };
         let error_policy = |g: Arc<RoutingInstance>, error: &ReconcileError, ctx: Arc<Context>| {
             RoutingInstanceController::error_policy(g, error, ctx)
         };
-        runtime_controller::new(self.resource.clone(), Config::default())
+        let controller = controllers::new_controller::<RoutingInstance>(&self.context, Config::default());
+        health::track("routing_instance", controller.store());
+        controller
+            .graceful_shutdown_on(health::health().shutdown_requested())
             .run(reconcile, error_policy, self.context.clone())
             .for_each(|res| async move {
                 match res {
@@ -75,6 +81,7 @@ impl Controller for RoutingInstanceController{
                 }
             })
             .await;
+        health::health().stopped("routing_instance");
         Ok(())
     }
 }
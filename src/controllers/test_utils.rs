@@ -0,0 +1,505 @@
+use crate::controllers::controllers::Context;
+use crate::controllers::cache::Cache;
+use crate::config::config::OperatorConfig;
+use crate::resources::crpd::crpd::{Crpd, CrpdMode, CrpdSpec, CrpdStatus, Instance};
+use crate::resources::bgp_router::{BgpRouter, BgpRouterSpec, BgpRouterType, AddressFamily};
+use crate::resources::bgp_router_group::{BgpRouterGroup, BgpRouterGroupSpec};
+use http::{Method, Request, Response, StatusCode};
+use hyper::Body;
+use k8s_openapi::api::apps::v1 as apps_v1;
+use k8s_openapi::api::core::v1 as core_v1;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1 as meta_v1;
+use kube::{Client, Resource};
+use kube::runtime::{reflector, watcher};
+use kube::runtime::reflector::Store;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+use tower_test::mock;
+
+pub const NAMESPACE: &str = "default";
+
+// Failure answers requests with the method on paths starting with the prefix
+// with a status code and reason
+type Failure = (Method, String, StatusCode, &'static str);
+
+// ApiServer is an in-memory stand-in for the kubernetes api of a single
+// cluster. Objects are kept as json by their api path, writes are merged
+// into the stored object like a merge patch.
+#[derive(Clone, Default)]
+pub struct ApiServer{
+    objects: Arc<Mutex<BTreeMap<String, serde_json::Value>>>,
+    failures: Arc<Mutex<Vec<Failure>>>,
+    requests: Arc<Mutex<Vec<(Method, String)>>>,
+    delete_bodies: Arc<Mutex<Vec<serde_json::Value>>>,
+    resource_version: Arc<Mutex<u64>>,
+}
+
+impl ApiServer{
+    pub fn new() -> Self{
+        Self::default()
+    }
+    // client returns a kube::Client whose requests are served by this ApiServer
+    pub fn client(&self) -> Client{
+        let (service, mut handle) = mock::pair::<Request<Body>, Response<Body>>();
+        let server = self.clone();
+        tokio::spawn(async move {
+            while let Some((request, send)) = handle.next_request().await{
+                let response = server.handle(request).await;
+                send.send_response(response);
+            }
+        });
+        Client::new(service, NAMESPACE)
+    }
+    // context returns a Context whose caches hold a snapshot of the objects
+    // added so far
+    pub fn context(&self) -> Arc<Context>{
+        self.context_with_config(OperatorConfig::default())
+    }
+    pub fn context_with_config(&self, config: OperatorConfig) -> Arc<Context>{
+        let cache = Cache{
+            crpds: self.store(),
+            bgp_routers: self.store(),
+            bgp_router_groups: self.store(),
+            pods: self.store(),
+            stateful_sets: self.store(),
+            daemon_sets: self.store(),
+            applied_crpds: broadcast::channel(1).0,
+            applied_stateful_sets: broadcast::channel(1).0,
+            applied_daemon_sets: broadcast::channel(1).0,
+        };
+        Arc::new(Context::new(self.client(), Arc::new(config), Arc::new(cache)))
+    }
+    fn store<T>(&self) -> Store<T>
+    where
+    T: Resource<DynamicType = ()> + DeserializeOwned + Clone,
+    {
+        let (reader, mut writer) = reflector::store();
+        writer.apply_watcher_event(&watcher::Event::Restarted(self.list::<T>()));
+        reader
+    }
+    pub fn add<T>(&self, t: &T)
+    where
+    T: Resource<DynamicType = ()> + Serialize,
+    {
+        let path = object_path(t);
+        let mut object = serde_json::to_value(t).unwrap();
+        let resource_version = self.next_resource_version();
+        object["metadata"]["resourceVersion"] = serde_json::json!(resource_version);
+        self.objects.lock().unwrap().insert(path, object);
+    }
+    pub fn get<T>(&self, name: &str) -> Option<T>
+    where
+    T: Resource<DynamicType = ()> + DeserializeOwned,
+    {
+        let path = format!("{}/{}", T::url_path(&(), Some(NAMESPACE)), name);
+        self.objects.lock().unwrap().get(&path).map(|object| serde_json::from_value(object.clone()).unwrap())
+    }
+    pub fn list<T>(&self) -> Vec<T>
+    where
+    T: Resource<DynamicType = ()> + DeserializeOwned,
+    {
+        let collection = T::url_path(&(), Some(NAMESPACE));
+        self.objects.lock().unwrap().iter()
+            .filter(|(path, _)| parent(path) == collection)
+            .map(|(_, object)| serde_json::from_value(object.clone()).unwrap())
+            .collect()
+    }
+    // fail makes every request with the given method on a path starting
+    // with prefix answer with an internal server error
+    pub fn fail(&self, method: Method, prefix: &str){
+        self.failures.lock().unwrap().push((method, prefix.to_string(), StatusCode::INTERNAL_SERVER_ERROR, "InternalError"));
+    }
+    // conflict makes those requests answer with a conflict, like an apply
+    // of fields owned by another field manager
+    pub fn conflict(&self, method: Method, prefix: &str){
+        self.failures.lock().unwrap().push((method, prefix.to_string(), StatusCode::CONFLICT, "Conflict"));
+    }
+    pub fn requests(&self, method: Method) -> Vec<String>{
+        self.requests.lock().unwrap().iter()
+            .filter(|(m, _)| *m == method)
+            .map(|(_, path)| path.clone())
+            .collect()
+    }
+    // delete_bodies returns the delete options sent so far
+    pub fn delete_bodies(&self) -> Vec<serde_json::Value>{
+        self.delete_bodies.lock().unwrap().clone()
+    }
+    // event_reasons returns the reasons of all events published so far
+    pub fn event_reasons(&self) -> Vec<String>{
+        self.objects.lock().unwrap().iter()
+            .filter(|(path, _)| path.contains("/events/"))
+            .filter_map(|(_, event)| event["reason"].as_str().map(|reason| reason.to_string()))
+            .collect()
+    }
+    fn next_resource_version(&self) -> String{
+        let mut resource_version = self.resource_version.lock().unwrap();
+        *resource_version += 1;
+        resource_version.to_string()
+    }
+    async fn handle(&self, request: Request<Body>) -> Response<Body>{
+        let method = request.method().clone();
+        let path = request.uri().path().to_string();
+        let query = request.uri().query().unwrap_or("").to_string();
+        let content_type = request.headers().get(http::header::CONTENT_TYPE)
+            .map(|content_type| content_type.to_str().unwrap().to_string())
+            .unwrap_or_default();
+        let body = hyper::body::to_bytes(request.into_body()).await.unwrap();
+        self.requests.lock().unwrap().push((method.clone(), path.clone()));
+        let failure = self.failures.lock().unwrap().iter()
+            .find(|(m, prefix, _, _)| *m == method && path.starts_with(prefix.as_str()))
+            .map(|(_, _, code, reason)| (*code, *reason));
+        if let Some((code, reason)) = failure{
+            return status(code, reason, &path);
+        }
+        let (path, subresource) = match path.strip_suffix("/status"){
+            Some(path) => (path.to_string(), true),
+            None => (path, false),
+        };
+        let body: serde_json::Value = if body.is_empty(){
+            serde_json::Value::Null
+        } else {
+            serde_json::from_slice(&body).unwrap()
+        };
+        let mut objects = self.objects.lock().unwrap();
+        match method{
+            Method::GET => {
+                if let Some(object) = objects.get(&path){
+                    return ok(StatusCode::OK, object);
+                }
+                if !is_collection(&path){
+                    return status(StatusCode::NOT_FOUND, "NotFound", &path);
+                }
+                let requirements = label_requirements(&query);
+                let items: Vec<serde_json::Value> = objects.iter()
+                    .filter(|(object_path, _)| parent(object_path) == path)
+                    .filter(|(_, object)| requirements.iter().all(|requirement| requirement.matches(&object["metadata"]["labels"])))
+                    .map(|(_, object)| object.clone())
+                    .collect();
+                ok(StatusCode::OK, &serde_json::json!({
+                    "apiVersion": "v1",
+                    "kind": "List",
+                    "metadata": {"resourceVersion": ""},
+                    "items": items,
+                }))
+            },
+            Method::POST => {
+                let mut object = body;
+                let name = match object["metadata"]["name"].as_str(){
+                    Some(name) => name.to_string(),
+                    None => format!("{}{}", object["metadata"]["generateName"].as_str().unwrap_or_default(), self.next_resource_version()),
+                };
+                object["metadata"]["name"] = serde_json::json!(name);
+                let object_path = format!("{}/{}", path, name);
+                if objects.contains_key(&object_path){
+                    return status(StatusCode::CONFLICT, "AlreadyExists", &object_path);
+                }
+                object["metadata"]["uid"] = serde_json::json!(format!("uid-{}", name));
+                object["metadata"]["resourceVersion"] = serde_json::json!(self.next_resource_version());
+                objects.insert(object_path, object.clone());
+                ok(StatusCode::CREATED, &object)
+            },
+            Method::PATCH => {
+                if !objects.contains_key(&path){
+                    // only server side apply creates missing objects
+                    if !content_type.starts_with("application/apply-patch"){
+                        return status(StatusCode::NOT_FOUND, "NotFound", &path);
+                    }
+                    let name = path.rsplit('/').next().unwrap().to_string();
+                    objects.insert(path.clone(), serde_json::json!({
+                        "metadata": {"uid": format!("uid-{}", name)},
+                    }));
+                }
+                let object = objects.get_mut(&path).unwrap();
+                json_patch::merge(object, &body);
+                object["metadata"]["resourceVersion"] = serde_json::json!(self.next_resource_version());
+                ok(StatusCode::OK, object)
+            },
+            Method::PUT => {
+                let object = match objects.get_mut(&path){
+                    Some(object) => object,
+                    None => return status(StatusCode::NOT_FOUND, "NotFound", &path),
+                };
+                if subresource{
+                    object["status"] = body["status"].clone();
+                } else {
+                    let uid = object["metadata"]["uid"].clone();
+                    *object = body;
+                    object["metadata"]["uid"] = uid;
+                }
+                object["metadata"]["resourceVersion"] = serde_json::json!(self.next_resource_version());
+                ok(StatusCode::OK, object)
+            },
+            Method::DELETE => {
+                self.delete_bodies.lock().unwrap().push(body);
+                match objects.remove(&path){
+                    Some(object) => ok(StatusCode::OK, &object),
+                    None => status(StatusCode::NOT_FOUND, "NotFound", &path),
+                }
+            },
+            _ => status(StatusCode::METHOD_NOT_ALLOWED, "MethodNotAllowed", &path),
+        }
+    }
+}
+
+fn object_path<T: Resource<DynamicType = ()>>(t: &T) -> String{
+    format!("{}/{}", T::url_path(&(), t.meta().namespace.as_deref()), t.meta().name.as_ref().unwrap())
+}
+
+fn parent(path: &str) -> &str{
+    match path.rsplit_once('/'){
+        Some((parent, _)) => parent,
+        None => "",
+    }
+}
+
+// collections are the paths directly below a namespace,
+// e.g. /api/v1/namespaces/default/pods
+fn is_collection(path: &str) -> bool{
+    let segments: Vec<&str> = path.split('/').collect();
+    match segments.iter().position(|segment| *segment == "namespaces"){
+        Some(idx) => segments.len() == idx + 3,
+        None => false,
+    }
+}
+
+fn ok(code: StatusCode, object: &serde_json::Value) -> Response<Body>{
+    Response::builder()
+        .status(code)
+        .body(Body::from(serde_json::to_vec(object).unwrap()))
+        .unwrap()
+}
+
+fn status(code: StatusCode, reason: &str, path: &str) -> Response<Body>{
+    ok(code, &serde_json::json!({
+        "apiVersion": "v1",
+        "kind": "Status",
+        "metadata": {},
+        "status": "Failure",
+        "message": format!("{} {}", reason, path),
+        "reason": reason,
+        "code": code.as_u16(),
+    }))
+}
+
+enum LabelRequirement{
+    Equals(String, String),
+    In(String, Vec<String>),
+    NotIn(String, Vec<String>),
+    Exists(String),
+    DoesNotExist(String),
+}
+
+impl LabelRequirement{
+    fn matches(&self, labels: &serde_json::Value) -> bool{
+        match self{
+            LabelRequirement::Equals(key, value) => labels[key].as_str() == Some(value.as_str()),
+            LabelRequirement::In(key, values) => labels[key].as_str().map(|value| values.iter().any(|v| v == value)).unwrap_or(false),
+            LabelRequirement::NotIn(key, values) => !labels[key].as_str().map(|value| values.iter().any(|v| v == value)).unwrap_or(false),
+            LabelRequirement::Exists(key) => !labels[key].is_null(),
+            LabelRequirement::DoesNotExist(key) => labels[key].is_null(),
+        }
+    }
+}
+
+// label_requirements parses the labelSelector of a list request in the form
+// label_selector writes it
+fn label_requirements(query: &str) -> Vec<LabelRequirement>{
+    let selector = query.split('&')
+        .find_map(|param| param.strip_prefix("labelSelector="))
+        .map(decode)
+        .unwrap_or_default();
+    // commas inside the value set of in and notin don't separate requirements
+    let mut requirements = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, c) in selector.char_indices(){
+        match c{
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                requirements.push(&selector[start..i]);
+                start = i + 1;
+            },
+            _ => {},
+        }
+    }
+    requirements.push(&selector[start..]);
+    requirements.into_iter()
+        .filter(|requirement| !requirement.is_empty())
+        .map(|requirement| {
+            let set = |values: &str| values.trim().trim_start_matches('(').trim_end_matches(')')
+                .split(',').map(|value| value.trim().to_string()).collect::<Vec<String>>();
+            if let Some((key, values)) = requirement.split_once(" notin "){
+                return LabelRequirement::NotIn(key.trim().to_string(), set(values));
+            }
+            if let Some((key, values)) = requirement.split_once(" in "){
+                return LabelRequirement::In(key.trim().to_string(), set(values));
+            }
+            match requirement.split_once('='){
+                Some((key, value)) => LabelRequirement::Equals(key.to_string(), value.to_string()),
+                None => match requirement.strip_prefix('!'){
+                    Some(key) => LabelRequirement::DoesNotExist(key.to_string()),
+                    None => LabelRequirement::Exists(requirement.to_string()),
+                },
+            }
+        })
+        .collect()
+}
+
+fn decode(s: &str) -> String{
+    let mut bytes = Vec::new();
+    let mut chars = s.bytes();
+    while let Some(c) = chars.next(){
+        match c{
+            b'+' => bytes.push(b' '),
+            b'%' => {
+                let hex = [chars.next().unwrap(), chars.next().unwrap()];
+                bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).unwrap(), 16).unwrap());
+            },
+            _ => bytes.push(c),
+        }
+    }
+    String::from_utf8(bytes).unwrap()
+}
+
+fn metadata(name: &str, labels: BTreeMap<String, String>) -> meta_v1::ObjectMeta{
+    meta_v1::ObjectMeta{
+        name: Some(name.to_string()),
+        namespace: Some(NAMESPACE.to_string()),
+        uid: Some(format!("uid-{}", name)),
+        generation: Some(1),
+        labels: Some(labels),
+        ..Default::default()
+    }
+}
+
+pub fn crpd(name: &str, replicas: i32) -> Crpd{
+    Crpd{
+        metadata: metadata(name, BTreeMap::from([("app".to_string(), "crpd".to_string())])),
+        spec: CrpdSpec{
+            mode: CrpdMode::StatefulSet,
+            replicas,
+            image: "crpd:latest".to_string(),
+            init_image: "crpd-init:latest".to_string(),
+            resources: None,
+            node_selector: None,
+            affinity: None,
+            topology_spread_constraints: None,
+            max_unavailable: None,
+            tolerations: None,
+            env: None,
+            volumes: None,
+            volume_mounts: None,
+            image_pull_secrets: None,
+            priority_class_name: None,
+            annotations: None,
+            config_storage: None,
+            upgrade_timeout_seconds: None,
+            license_secret_ref: None,
+        },
+        status: None,
+    }
+}
+
+// crpd_with_instances returns a Crpd whose status lists the given
+// (pod name, address) pairs as instances
+pub fn crpd_with_instances(name: &str, instances: Vec<(&str, &str)>) -> Crpd{
+    let mut crpd = crpd(name, instances.len() as i32);
+    crpd.status = Some(CrpdStatus{
+        instances: Some(instances.into_iter().map(|(pod, address)| Instance{
+            name: pod.to_string(),
+            address: address.to_string(),
+            addresses: Vec::new(),
+            uuid: format!("uid-{}", pod),
+            license: None,
+            operational: None,
+        }).collect()),
+        ..Default::default()
+    });
+    crpd
+}
+
+pub fn pod(crpd: &Crpd, name: &str, pod_ip: Option<&str>) -> core_v1::Pod{
+    let labels = BTreeMap::from([
+        ("app".to_string(), "crpd".to_string()),
+        ("crpd".to_string(), crpd.meta().name.as_ref().unwrap().clone()),
+    ]);
+    core_v1::Pod{
+        metadata: metadata(name, labels),
+        status: Some(core_v1::PodStatus{
+            pod_ip: pod_ip.map(|pod_ip| pod_ip.to_string()),
+            ..Default::default()
+        }),
+        ..Default::default()
+    }
+}
+
+pub fn stateful_set(crpd: &Crpd, ready_replicas: i32) -> apps_v1::StatefulSet{
+    let mut sts = apps_v1::StatefulSet::from(crpd.clone());
+    sts.status = Some(apps_v1::StatefulSetStatus{
+        replicas: crpd.spec.replicas,
+        ready_replicas: Some(ready_replicas),
+        ..Default::default()
+    });
+    sts
+}
+
+pub fn daemon_set(crpd: &Crpd, number_ready: i32, desired_number_scheduled: i32) -> apps_v1::DaemonSet{
+    let mut ds = apps_v1::DaemonSet::from(crpd.clone());
+    ds.status = Some(apps_v1::DaemonSetStatus{
+        desired_number_scheduled,
+        number_ready,
+        ..Default::default()
+    });
+    ds
+}
+
+pub fn bgp_router_spec(address: Option<&str>) -> BgpRouterSpec{
+    BgpRouterSpec{
+        autonomous_system_number: 64512,
+        router_id: address.map(|address| address.to_string()),
+        address: address.map(|address| address.to_string()),
+        addresses: None,
+        ip_families: None,
+        address_families: vec![AddressFamily::Inet],
+        router_type: BgpRouterType::Crpd,
+        managed: false,
+        bgp_peer_references: None,
+    }
+}
+
+pub fn bgp_router(name: &str, address: Option<&str>, bgp_router_group: Option<&str>) -> BgpRouter{
+    let mut labels = BTreeMap::new();
+    if let Some(bgp_router_group) = bgp_router_group{
+        labels.insert("cnm.juniper.net/bgpRouterGroup".to_string(), bgp_router_group.to_string());
+    }
+    BgpRouter{
+        metadata: metadata(name, labels),
+        spec: bgp_router_spec(address),
+        status: None,
+    }
+}
+
+pub fn bgp_router_group(name: &str, discover: bool) -> BgpRouterGroup{
+    BgpRouterGroup{
+        metadata: metadata(name, BTreeMap::new()),
+        spec: BgpRouterGroupSpec{
+            discover,
+            bgp_router_template: bgp_router_spec(None),
+            selector: meta_v1::LabelSelector{
+                match_labels: Some(BTreeMap::from([("app".to_string(), "crpd".to_string())])),
+                ..Default::default()
+            },
+        },
+        status: None,
+    }
+}
+
+pub fn condition_status<'a>(conditions: &'a Option<Vec<meta_v1::Condition>>, type_: &str) -> Option<&'a str>{
+    conditions.as_ref()?.iter()
+        .find(|condition| condition.type_ == type_)
+        .map(|condition| condition.status.as_str())
+}
@@ -28,12 +28,16 @@ pub async fn main() -> anyhow::Result<()> {
         Ok(pod_namespace) => pod_namespace,
         Err(e) => return Err(e.into())
     };
+    // the operator passes its settings, the defaults match an unconfigured operator
+    let ca_namespace = std::env::var("CNM_NAMESPACE").unwrap_or(pod_namespace.clone());
+    let ca_secret = std::env::var("CNM_CA_SECRET").unwrap_or("cnm-ca".to_string());
+    let jet_port = std::env::var("CNM_JET_PORT").unwrap_or("50052".to_string());
 
     let client = Client::try_default().await?;
 
     let (ca, kp) = match controllers::get::<core_v1::Secret>(
-        pod_namespace.clone(), 
-        "cnm-ca".to_string(),
+        ca_namespace,
+        ca_secret,
         client.clone()).await{
         Ok(ca_secret) => {
             match ca_secret {
@@ -92,7 +96,7 @@ pub async fn main() -> anyhow::Result<()> {
         ..Default::default()
     };
     
-    controllers::create_or_update(secret, client).await?;
+    controllers::apply(secret, "crpd-init", true, client).await?;
     //write the cert to a file
     let mut cert_file = std::fs::File::create("/etc/certs/tls.crt")?;
     cert_file.write_all(&signed_cert.as_bytes())?;
@@ -105,7 +109,14 @@ pub async fn main() -> anyhow::Result<()> {
     
     let single_line_cert = read_file("/etc/certs/tls.pem")?;
     if let Ok(passwpord) = gen_password("Juniper123") {
-        write_config(&generate_config(&passwpord, &single_line_cert))?;
+        let mut config = generate_config(&jet_port, &passwpord, &single_line_cert);
+        // on a persistent config volume the committed config survives a
+        // restart, the base stanzas and the new certificate are merged into it
+        if std::path::Path::new(CONFIG_FILE).exists(){
+            tracing::info!("merging base config into existing config {}", CONFIG_FILE);
+            config = merge_config(&read_config()?, &config);
+        }
+        write_config(&config)?;
         gzip_config()?;
     } else {
         return Err(anyhow::anyhow!("Failed to generate password"));
@@ -119,6 +130,8 @@ fn gen_password(pwd: &str) -> anyhow::Result<String>{
     Ok(unix::crypt(pwd, h.as_str())?)
 }
 
+const CONFIG_FILE: &str = "/config/juniper.conf.gz";
+
 const BASE_CONFIG: &str = r#"
 system {
     root-authentication {
@@ -133,7 +146,7 @@ system {
             request-response {
                 grpc {
                     ssl {
-                        port 50052;
+                        port JET_PORT;
                         local-certificate grpc;
                     }
                     skip-authentication;
@@ -149,6 +162,9 @@ system {
         }
     }
 }
+routing-options {
+    graceful-restart;
+}
 security {
     certificates {
         local {
@@ -170,12 +186,115 @@ fn read_file(path: &str) -> Result<String, std::io::Error> {
 
 // generate_config generates a configuration for the device. It replaces PASSWORD and KEY with the
 // password and key that are passed in.
-fn generate_config(password: &str, key: &str) -> String {
+fn generate_config(jet_port: &str, password: &str, key: &str) -> String {
     BASE_CONFIG
+        .replace("JET_PORT", jet_port)
         .replace("PASSWORD", password)
         .replace("KEY", key)
 }
 
+// Statement is a line of a Junos config in curly brace format: a block with
+// its statements, a leaf ending in ';' or a comment
+#[derive(Debug, PartialEq)]
+enum Statement{
+    Block(String, Vec<Statement>),
+    Leaf(String),
+    Comment(String),
+}
+
+// parse_config reads a Junos config in curly brace format, as saved by
+// the device with one statement per line
+fn parse_config(config: &str) -> Vec<Statement>{
+    let mut stack: Vec<(String, Vec<Statement>)> = vec![(String::new(), Vec::new())];
+    for line in config.lines().map(str::trim).filter(|line| !line.is_empty()){
+        if line.starts_with('#') || line.starts_with("/*"){
+            stack.last_mut().unwrap().1.push(Statement::Comment(line.to_string()));
+        } else if let Some(header) = line.strip_suffix('{'){
+            stack.push((header.trim().to_string(), Vec::new()));
+        } else if line == "}"{
+            if stack.len() > 1{
+                let (header, statements) = stack.pop().unwrap();
+                stack.last_mut().unwrap().1.push(Statement::Block(header, statements));
+            }
+        } else {
+            stack.last_mut().unwrap().1.push(Statement::Leaf(line.to_string()));
+        }
+    }
+    // close blocks of a truncated config
+    while stack.len() > 1{
+        let (header, statements) = stack.pop().unwrap();
+        stack.last_mut().unwrap().1.push(Statement::Block(header, statements));
+    }
+    stack.pop().unwrap().1
+}
+
+// leaf_key identifies a leaf by its first word, a leaf which only holds a
+// quoted value like a certificate is identified by being quoted
+fn leaf_key(leaf: &str) -> &str{
+    if leaf.starts_with('"'){
+        return "\"";
+    }
+    leaf.split(|c: char| c.is_whitespace() || c == ';').next().unwrap_or_default()
+}
+
+// merge_statements merges base into statements: blocks are merged by their
+// header, a leaf of base replaces the leaf with the same key
+fn merge_statements(statements: &mut Vec<Statement>, base: Vec<Statement>){
+    for statement in base{
+        match statement{
+            Statement::Block(header, base_statements) => {
+                let existing = statements.iter_mut().find(|existing| matches!(existing, Statement::Block(existing_header, _) if *existing_header == header));
+                match existing{
+                    Some(Statement::Block(_, existing_statements)) => merge_statements(existing_statements, base_statements),
+                    _ => statements.push(Statement::Block(header, base_statements)),
+                }
+            },
+            Statement::Leaf(leaf) => {
+                let existing = statements.iter_mut().find(|existing| matches!(existing, Statement::Leaf(existing_leaf) if leaf_key(existing_leaf) == leaf_key(&leaf)));
+                match existing{
+                    Some(existing) => *existing = Statement::Leaf(leaf),
+                    None => statements.push(Statement::Leaf(leaf)),
+                }
+            },
+            Statement::Comment(_) => {},
+        }
+    }
+}
+
+fn format_statements(statements: &[Statement], indent: usize, config: &mut String){
+    for statement in statements{
+        match statement{
+            Statement::Block(header, statements) => {
+                config.push_str(&format!("{}{} {{\n", " ".repeat(indent), header));
+                format_statements(statements, indent + 4, config);
+                config.push_str(&format!("{}}}\n", " ".repeat(indent)));
+            },
+            Statement::Leaf(line) | Statement::Comment(line) => {
+                config.push_str(&format!("{}{}\n", " ".repeat(indent), line));
+            },
+        }
+    }
+}
+
+// merge_config merges the base config into the existing config. The
+// statements of the base config win, everything else of the existing
+// config is kept.
+fn merge_config(existing: &str, base: &str) -> String{
+    let mut statements = parse_config(existing);
+    merge_statements(&mut statements, parse_config(base));
+    let mut config = String::new();
+    format_statements(&statements, 0, &mut config);
+    config
+}
+
+// read_config reads the gzipped configuration of the device
+fn read_config() -> Result<String, std::io::Error> {
+    let file = std::fs::File::open(CONFIG_FILE)?;
+    let mut contents = String::new();
+    flate2::read::GzDecoder::new(file).read_to_string(&mut contents)?;
+    Ok(contents)
+}
+
 // write_config writes the configuration to the device
 fn write_config(config: &str) -> Result<(), std::io::Error> {
     let mut file = std::fs::File::create("/tmp/juniper.conf")?;
@@ -191,7 +310,69 @@ fn gzip_config() -> Result<(), std::io::Error> {
     let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
     encoder.write_all(&contents)?;
     let compressed_contents = encoder.finish()?;
-    let mut file = std::fs::File::create("/config/juniper.conf.gz")?;
+    let mut file = std::fs::File::create(CONFIG_FILE)?;
     file.write_all(&compressed_contents)?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn merge_config_keeps_committed_config(){
+        let existing = r#"## Last changed: 2024-01-01 10:00:00 UTC
+version 23.2R1.13;
+system {
+    root-authentication {
+        encrypted-password "$6$old";
+    }
+    services {
+        extension-service {
+            request-response {
+                grpc {
+                    ssl {
+                        port 50052;
+                        local-certificate grpc;
+                    }
+                    skip-authentication;
+                }
+            }
+        }
+    }
+}
+protocols {
+    bgp {
+        group g1 {
+            neighbor 10.0.0.2;
+        }
+    }
+}
+security {
+    certificates {
+        local {
+            grpc {
+                "old-cert";
+            }
+        }
+    }
+}
+"#;
+        let merged = merge_config(existing, &generate_config("50053", "$6$new", "new-cert"));
+        assert!(merged.starts_with("## Last changed: 2024-01-01 10:00:00 UTC\nversion 23.2R1.13;\n"));
+        assert!(merged.contains("neighbor 10.0.0.2;"));
+        assert!(merged.contains("graceful-restart;"));
+        assert!(merged.contains("port 50053;"));
+        assert!(merged.contains("encrypted-password \"$6$new\";"));
+        assert!(merged.contains("\"new-cert\";"));
+        assert!(!merged.contains("old"));
+        assert_eq!(merged.matches("extension-service {").count(), 1);
+        assert_eq!(merged.matches("port ").count(), 2);
+    }
+
+    #[test]
+    fn merge_config_replaces_leaves_by_first_word(){
+        let merged = merge_config("a {\n    b 1;\n    c;\n}\n", "a {\n    b 2;\n    d;\n}\n");
+        assert_eq!(merged, "a {\n    b 2;\n    c;\n    d;\n}\n");
+    }
+}
@@ -0,0 +1,128 @@
+use std::collections::BTreeMap;
+use std::sync::{Mutex, OnceLock};
+use tokio::sync::watch;
+use tracing::*;
+use warp::{Filter, Reply};
+use kube::runtime::reflector::Store;
+
+#[derive(Clone, Debug, Default)]
+struct ControllerHealth{
+    running: bool,
+    synced: bool,
+}
+
+pub struct Health{
+    controllers: Mutex<BTreeMap<String, ControllerHealth>>,
+    shutdown: watch::Sender<bool>,
+}
+
+impl Health{
+    fn new() -> Self{
+        let (shutdown, _) = watch::channel(false);
+        Health{
+            controllers: Mutex::new(BTreeMap::new()),
+            shutdown,
+        }
+    }
+
+    pub fn register(&self, controller: &str){
+        self.controllers.lock().unwrap().insert(controller.to_string(), ControllerHealth{
+            running: true,
+            synced: false,
+        });
+    }
+
+    pub fn synced(&self, controller: &str){
+        info!("{} controller cache synced", controller);
+        if let Some(health) = self.controllers.lock().unwrap().get_mut(controller){
+            health.synced = true;
+        }
+    }
+
+    pub fn stopped(&self, controller: &str){
+        info!("{} controller stopped", controller);
+        if let Some(health) = self.controllers.lock().unwrap().get_mut(controller){
+            health.running = false;
+        }
+    }
+
+    // a replica which is not leading has no controllers registered and is healthy
+    pub fn is_healthy(&self) -> bool{
+        self.controllers.lock().unwrap().values().all(|health| health.running)
+    }
+
+    pub fn is_ready(&self) -> bool{
+        self.controllers.lock().unwrap().values().all(|health| health.running && health.synced)
+    }
+
+    pub fn shutdown(&self){
+        info!("shutdown requested");
+        self.shutdown.send_replace(true);
+    }
+
+    pub fn is_shutting_down(&self) -> bool{
+        *self.shutdown.borrow()
+    }
+
+    // shutdown_requested resolves once shutdown has been called
+    pub fn shutdown_requested(&self) -> impl std::future::Future<Output = ()> + Send + Sync + 'static{
+        let mut rx = self.shutdown.subscribe();
+        async move {
+            while !*rx.borrow(){
+                if rx.changed().await.is_err(){
+                    return;
+                }
+            }
+        }
+    }
+}
+
+// track registers a controller and marks it synced once the store of its
+// watched resource received the initial list
+pub fn track<K>(controller: &str, store: Store<K>)
+where
+K: kube::Resource + Clone + Send + Sync + 'static,
+K::DynamicType: std::hash::Hash + Eq + Clone + Send + Sync,
+{
+    health().register(controller);
+    let controller = controller.to_string();
+    tokio::spawn(async move {
+        if store.wait_until_ready().await.is_ok(){
+            health().synced(&controller);
+        }
+    });
+}
+
+static HEALTH: OnceLock<Health> = OnceLock::new();
+
+pub fn health() -> &'static Health{
+    HEALTH.get_or_init(Health::new)
+}
+
+pub fn routes() -> impl Filter<Extract = (impl Reply,), Error = warp::Rejection> + Clone{
+    let healthz = warp::path("healthz")
+        .and(warp::get())
+        .map(|| probe(health().is_healthy()));
+    let readyz = warp::path("readyz")
+        .and(warp::get())
+        .map(|| probe(health().is_ready() && !health().is_shutting_down()));
+    healthz.or(readyz)
+}
+
+fn probe(ok: bool) -> warp::reply::WithStatus<&'static str>{
+    if ok{
+        warp::reply::with_status("ok", warp::http::StatusCode::OK)
+    } else {
+        warp::reply::with_status("not ok", warp::http::StatusCode::SERVICE_UNAVAILABLE)
+    }
+}
+
+// shutdown_signal resolves on SIGTERM or ctrl-c
+pub async fn shutdown_signal() -> anyhow::Result<()>{
+    let mut sigterm = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+    tokio::select! {
+        _ = sigterm.recv() => {},
+        res = tokio::signal::ctrl_c() => res?,
+    }
+    Ok(())
+}
@@ -0,0 +1,222 @@
+use crate::controllers::controllers::{self, already_exists};
+use crate::health::health;
+use k8s_openapi::api::coordination::v1 as coordination_v1;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1 as meta_v1;
+use k8s_openapi::chrono::{Duration as ChronoDuration, Utc};
+use kube::{Api, Client};
+use kube::api::PostParams;
+use std::future::Future;
+use tokio::time::{sleep, Duration};
+use tracing::*;
+
+pub struct LeaderElection{
+    client: Client,
+    name: String,
+    namespace: String,
+    identity: String,
+    lease_duration: Duration,
+    // the leader steps down when it could not renew the lease for this long,
+    // before the lease expires and another replica may take over
+    renew_deadline: Duration,
+    retry_period: Duration,
+}
+
+impl LeaderElection{
+    pub fn new(client: Client, name: String, namespace: String, identity: String) -> Self{
+        Self{
+            client,
+            name,
+            namespace,
+            identity,
+            lease_duration: Duration::from_secs(15),
+            renew_deadline: Duration::from_secs(10),
+            retry_period: Duration::from_secs(2),
+        }
+    }
+
+    // run blocks until the lease is acquired, then drives f while renewing the lease.
+    // Losing the lease or missing the renew deadline aborts f and returns an error
+    // so the process can restart as standby. The lease is released when f ends
+    // on shutdown.
+    pub async fn run<F>(&self, f: F) -> anyhow::Result<()>
+    where
+    F: Future<Output = anyhow::Result<()>> + Send + 'static,
+    {
+        info!("{} waiting for lease {}/{}", self.identity, self.namespace, self.name);
+        loop {
+            match self.try_acquire_or_renew().await{
+                Ok(true) => break,
+                Ok(false) => {},
+                Err(e) => {
+                    warn!("failed to acquire lease: {:?}", e);
+                },
+            }
+            if health::health().is_shutting_down(){
+                return Ok(());
+            }
+            sleep(self.retry_period).await;
+        }
+        info!("{} acquired lease {}/{}", self.identity, self.namespace, self.name);
+
+        let mut handle = tokio::spawn(f);
+        let mut last_renew = tokio::time::Instant::now();
+        loop {
+            tokio::select! {
+                res = &mut handle => {
+                    if health::health().is_shutting_down(){
+                        if let Err(e) = self.release().await{
+                            warn!("failed to release lease: {:?}", e);
+                        }
+                    }
+                    return match res{
+                        Ok(res) => res,
+                        Err(e) => Err(e.into()),
+                    };
+                },
+                _ = sleep(self.retry_period) => {
+                    // a hanging request must not hold the leader past the deadline
+                    let remaining = self.renew_deadline.saturating_sub(last_renew.elapsed());
+                    let res = match tokio::time::timeout(remaining, self.try_acquire_or_renew()).await{
+                        Ok(res) => res,
+                        Err(_) => Err(anyhow::anyhow!("timed out")),
+                    };
+                    match res{
+                        Ok(true) => {
+                            last_renew = tokio::time::Instant::now();
+                        },
+                        Ok(false) => {
+                            handle.abort();
+                            return Err(anyhow::anyhow!("lease {} taken over by another replica", self.name));
+                        },
+                        Err(e) => {
+                            warn!("failed to renew lease: {:?}", e);
+                            if last_renew.elapsed() >= self.renew_deadline{
+                                handle.abort();
+                                return Err(anyhow::anyhow!("failed to renew lease {} within {:?}", self.name, self.renew_deadline));
+                            }
+                        },
+                    }
+                },
+            }
+        }
+    }
+
+    // release gives up the lease held by this replica, so that a standby
+    // takes over without waiting for the lease to expire
+    pub async fn release(&self) -> anyhow::Result<()>{
+        let lease_api: Api<coordination_v1::Lease> = Api::namespaced(self.client.clone(), &self.namespace);
+        let mut lease = match controllers::get::<coordination_v1::Lease>(self.namespace.clone(), self.name.clone(), self.client.clone()).await{
+            Ok(Some((lease, _))) => lease,
+            Ok(None) => return Ok(()),
+            Err(e) => return Err(e.into_error()),
+        };
+        let mut spec = lease.spec.clone().unwrap_or_default();
+        if spec.holder_identity.as_ref() != Some(&self.identity){
+            return Ok(());
+        }
+        spec.holder_identity = None;
+        spec.lease_duration_seconds = Some(1);
+        spec.renew_time = Some(meta_v1::MicroTime(Utc::now()));
+        lease.spec = Some(spec);
+        lease_api.replace(&self.name, &PostParams::default(), &lease).await?;
+        info!("{} released lease {}/{}", self.identity, self.namespace, self.name);
+        Ok(())
+    }
+
+    pub async fn try_acquire_or_renew(&self) -> anyhow::Result<bool>{
+        let now = Utc::now();
+        let lease_duration_seconds = self.lease_duration.as_secs() as i32;
+        let lease_api: Api<coordination_v1::Lease> = Api::namespaced(self.client.clone(), &self.namespace);
+        let lease = match controllers::get::<coordination_v1::Lease>(self.namespace.clone(), self.name.clone(), self.client.clone()).await{
+            Ok(lease) => lease,
+            Err(e) => return Err(e.into_error()),
+        };
+        let mut lease = match lease{
+            Some((lease, _)) => lease,
+            None => {
+                let lease = coordination_v1::Lease{
+                    metadata: meta_v1::ObjectMeta{
+                        name: Some(self.name.clone()),
+                        namespace: Some(self.namespace.clone()),
+                        ..Default::default()
+                    },
+                    spec: Some(coordination_v1::LeaseSpec{
+                        holder_identity: Some(self.identity.clone()),
+                        lease_duration_seconds: Some(lease_duration_seconds),
+                        acquire_time: Some(meta_v1::MicroTime(now)),
+                        renew_time: Some(meta_v1::MicroTime(now)),
+                        lease_transitions: Some(0),
+                    }),
+                };
+                return match lease_api.create(&PostParams::default(), &lease).await{
+                    Ok(_) => Ok(true),
+                    Err(e) => {
+                        if already_exists(&e){
+                            Ok(false)
+                        } else {
+                            Err(e.into())
+                        }
+                    },
+                };
+            },
+        };
+
+        let mut spec = lease.spec.clone().unwrap_or_default();
+        if spec.holder_identity.as_ref() != Some(&self.identity){
+            let expired = match &spec.renew_time{
+                Some(renew_time) => {
+                    let duration = spec.lease_duration_seconds.unwrap_or(lease_duration_seconds);
+                    renew_time.0 + ChronoDuration::seconds(duration as i64) < now
+                },
+                None => true,
+            };
+            if spec.holder_identity.is_some() && !expired{
+                return Ok(false);
+            }
+            info!("lease {} held by {:?} expired, taking over", self.name, spec.holder_identity);
+            spec.holder_identity = Some(self.identity.clone());
+            spec.acquire_time = Some(meta_v1::MicroTime(now));
+            spec.lease_transitions = Some(spec.lease_transitions.unwrap_or(0) + 1);
+        }
+        spec.renew_time = Some(meta_v1::MicroTime(now));
+        spec.lease_duration_seconds = Some(lease_duration_seconds);
+        lease.spec = Some(spec);
+
+        // replace carries the resourceVersion, so a concurrent writer makes this fail with a conflict
+        match lease_api.replace(&self.name, &PostParams::default(), &lease).await{
+            Ok(_) => Ok(true),
+            Err(kube::Error::Api(ae)) if ae.code == 409 => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::controllers::test_utils::{ApiServer, NAMESPACE};
+
+    fn leader_election(server: &ApiServer, identity: &str) -> LeaderElection{
+        LeaderElection::new(server.client(), "cnm".to_string(), NAMESPACE.to_string(), identity.to_string())
+    }
+
+    #[tokio::test]
+    async fn released_lease_is_taken_over_right_away(){
+        let server = ApiServer::new();
+        let leader = leader_election(&server, "replica1");
+        let standby = leader_election(&server, "replica2");
+        assert!(leader.try_acquire_or_renew().await.unwrap());
+        assert!(!standby.try_acquire_or_renew().await.unwrap());
+
+        // releasing a lease held by another replica leaves it alone
+        standby.release().await.unwrap();
+        assert!(!standby.try_acquire_or_renew().await.unwrap());
+
+        leader.release().await.unwrap();
+        assert!(standby.try_acquire_or_renew().await.unwrap());
+        let lease = server.get::<coordination_v1::Lease>("cnm").unwrap();
+        let spec = lease.spec.unwrap();
+        assert_eq!(spec.holder_identity, Some("replica2".to_string()));
+        assert_eq!(spec.lease_transitions, Some(1));
+    }
+}
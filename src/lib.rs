@@ -1,4 +1,8 @@
 pub mod resources;
 pub mod controllers;
 pub mod cert;
-pub mod admission;
\ No newline at end of file
+pub mod admission;
+pub mod leader_election;
+pub mod metrics;
+pub mod health;
+pub mod config;
\ No newline at end of file
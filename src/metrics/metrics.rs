@@ -0,0 +1,145 @@
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use std::sync::OnceLock;
+use tokio::time::Instant;
+use tracing::*;
+use warp::{Filter, Reply};
+use crate::health::health;
+
+pub struct Metrics{
+    registry: Registry,
+    reconciliations: IntCounterVec,
+    failures: IntCounterVec,
+    duration: HistogramVec,
+    // number of objects handed to a reconciler which have not finished yet
+    in_flight: IntGaugeVec,
+    // number of failed objects waiting for their requeue
+    queue_depth: IntGaugeVec,
+    jet_calls: IntCounterVec,
+}
+
+impl Metrics{
+    fn new() -> anyhow::Result<Self>{
+        let registry = Registry::new_custom(Some("cnm".to_string()), None)?;
+        let reconciliations = IntCounterVec::new(
+            Opts::new("reconciliations_total", "reconciliations per controller"),
+            &["controller"])?;
+        let failures = IntCounterVec::new(
+            Opts::new("reconcile_failures_total", "failed reconciliations per controller"),
+            &["controller"])?;
+        let duration = HistogramVec::new(
+            HistogramOpts::new("reconcile_duration_seconds", "reconcile duration per controller")
+                .buckets(vec![0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0]),
+            &["controller"])?;
+        let in_flight = IntGaugeVec::new(
+            Opts::new("reconcile_in_flight", "reconciliations in progress per controller"),
+            &["controller"])?;
+        let queue_depth = IntGaugeVec::new(
+            Opts::new("reconcile_queue_depth", "failed objects waiting for a requeue per controller"),
+            &["controller"])?;
+        let jet_calls = IntCounterVec::new(
+            Opts::new("jet_calls_total", "JET gRPC calls per rpc and result"),
+            &["rpc", "result"])?;
+        registry.register(Box::new(reconciliations.clone()))?;
+        registry.register(Box::new(failures.clone()))?;
+        registry.register(Box::new(duration.clone()))?;
+        registry.register(Box::new(in_flight.clone()))?;
+        registry.register(Box::new(queue_depth.clone()))?;
+        registry.register(Box::new(jet_calls.clone()))?;
+        Ok(Metrics{
+            registry,
+            reconciliations,
+            failures,
+            duration,
+            in_flight,
+            queue_depth,
+            jet_calls,
+        })
+    }
+
+    pub fn reconcile_start(&self, controller: &str) -> ReconcileMeasurer{
+        self.reconciliations.with_label_values(&[controller]).inc();
+        self.in_flight.with_label_values(&[controller]).inc();
+        ReconcileMeasurer{
+            controller: controller.to_string(),
+            start: Instant::now(),
+        }
+    }
+
+    pub fn reconcile_failure(&self, controller: &str){
+        self.failures.with_label_values(&[controller]).inc();
+    }
+
+    pub fn queue_depth(&self, controller: &str, depth: usize){
+        self.queue_depth.with_label_values(&[controller]).set(depth as i64);
+    }
+
+    pub fn jet_call<T, E>(&self, rpc: &str, res: &Result<T, E>){
+        let result = if res.is_ok() { "ok" } else { "error" };
+        self.jet_calls.with_label_values(&[rpc, result]).inc();
+    }
+
+    pub fn render(&self) -> anyhow::Result<String>{
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(String::from_utf8(buffer)?)
+    }
+}
+
+// ReconcileMeasurer records the reconcile duration when it goes out of scope
+pub struct ReconcileMeasurer{
+    controller: String,
+    start: Instant,
+}
+
+impl Drop for ReconcileMeasurer{
+    fn drop(&mut self){
+        let metrics = metrics();
+        metrics.duration.with_label_values(&[&self.controller]).observe(self.start.elapsed().as_secs_f64());
+        metrics.in_flight.with_label_values(&[&self.controller]).dec();
+    }
+}
+
+static METRICS: OnceLock<Metrics> = OnceLock::new();
+
+pub fn metrics() -> &'static Metrics{
+    METRICS.get_or_init(|| Metrics::new().expect("failed to register metrics"))
+}
+
+pub fn routes() -> impl Filter<Extract = (impl Reply,), Error = warp::Rejection> + Clone{
+    warp::path("metrics")
+        .and(warp::get())
+        .map(|| {
+            match metrics().render(){
+                Ok(body) => warp::reply::with_status(body, warp::http::StatusCode::OK),
+                Err(e) => warp::reply::with_status(e.to_string(), warp::http::StatusCode::INTERNAL_SERVER_ERROR),
+            }
+        })
+}
+
+// serve exposes /metrics together with the /healthz and /readyz probes
+pub async fn serve(address: String, port: u16) -> anyhow::Result<()>{
+    info!("Starting metrics server on {}:{}", address, port);
+    let addr = format!("{}:{}", address, port);
+    warp::serve(routes().or(health::routes()))
+        .run(addr.parse::<std::net::SocketAddr>()?)
+        .await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn render_reports_in_flight_reconciliations_and_jet_connects(){
+        let measurer = metrics().reconcile_start("metrics_test");
+        metrics().jet_call::<(), &str>("connect", &Err("refused"));
+        let rendered = metrics().render().unwrap();
+        assert!(rendered.contains(r#"cnm_reconcile_in_flight{controller="metrics_test"} 1"#));
+        assert!(rendered.contains(r#"cnm_jet_calls_total{result="error",rpc="connect"}"#));
+        drop(measurer);
+        assert!(metrics().render().unwrap().contains(r#"cnm_reconcile_in_flight{controller="metrics_test"} 0"#));
+    }
+}
@@ -1,17 +1,14 @@
 use garde::Validate;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use std::time::Duration;
-use tokio::time::sleep;
-use tracing::*;
 use k8s_openapi::apiextensions_apiserver::pkg::apis::apiextensions::v1::CustomResourceDefinition;
 use kube::{
-    api::{Api, PostParams, ResourceExt},
     core::crd::CustomResourceExt,
     Client, CustomResource,
 };
 use async_trait::async_trait;
 use k8s_openapi::api::core::v1 as core_v1;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1 as meta_v1;
 use std::fmt::{Display, Result, Formatter};
 
 use crate::resources::resources::Resource;
@@ -27,6 +24,23 @@ pub enum AddressFamily{
     Inet6Vpn,
 }
 
+// IpFamily is the family of an ip address, named as in service.spec.ipFamilies
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, JsonSchema)]
+pub enum IpFamily{
+    IPv4,
+    IPv6,
+}
+
+impl IpFamily{
+    pub fn of(address: &str) -> Option<IpFamily>{
+        match address.parse::<std::net::IpAddr>(){
+            Ok(std::net::IpAddr::V4(_)) => Some(IpFamily::IPv4),
+            Ok(std::net::IpAddr::V6(_)) => Some(IpFamily::IPv6),
+            Err(_) => None,
+        }
+    }
+}
+
 #[derive(Deserialize, Serialize, Clone, Debug, JsonSchema)]
 pub enum BgpRouterType{
     Crpd,
@@ -54,6 +68,7 @@ impl Display for BgpRouterType {
 #[kube(printcolumn = r#"{"name":"RouterId", "jsonPath": ".spec.routerId", "type": "string"}"#)]
 #[kube(printcolumn = r#"{"name":"Address", "jsonPath": ".spec.address", "type": "string"}"#)]
 #[kube(printcolumn = r#"{"name":"Type", "jsonPath": ".spec.routerType", "type": "string"}"#)]
+#[kube(printcolumn = r#"{"name":"Ready", "jsonPath": ".status.conditions[?(@.type==\"Ready\")].status", "type": "string"}"#)]
 pub struct BgpRouterSpec {
     #[schemars(length(min = 1))]
     #[garde(skip)]
@@ -64,6 +79,15 @@ pub struct BgpRouterSpec {
     #[garde(skip)]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub address: Option<String>,
+    // addresses peered from, one per family. Only address when not set.
+    #[garde(skip)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub addresses: Option<Vec<String>>,
+    // families a BgpRouterGroup takes the addresses of a discovered router
+    // from, the first one is its address. The pod's primary address when not set.
+    #[garde(skip)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ip_families: Option<Vec<IpFamily>>,
     #[garde(skip)]
     pub address_families: Vec<AddressFamily>,
     #[garde(skip)]
@@ -97,6 +121,10 @@ pub struct BgpSessionAttributes{
 #[serde(rename_all = "camelCase")]
 pub struct BgpRouterStatus {
     pub bgp_peer_references: Option<Vec<BgpPeeringReference>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub observed_generation: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub conditions: Option<Vec<meta_v1::Condition>>,
 }
 
 pub struct BgpRouterResource{
@@ -134,20 +162,7 @@ impl Resource for BgpRouterResource{
     fn version(&self) -> String {
         self.version.clone()
     }
-    async fn create(&self) -> anyhow::Result<()>{
-        let crds: Api<CustomResourceDefinition> = Api::all(self.client.clone());
-        let crd = BgpRouter::crd();
-        info!("Creating CRD: {}",self.name);
-        let pp = PostParams::default();
-        match crds.create(&pp, &crd).await {
-            Ok(o) => {
-                info!("Created {}", o.name_any());
-            }
-            Err(kube::Error::Api(ae)) => assert_eq!(ae.code, 409), // if you skipped delete, for instance
-            Err(e) => return Err(e.into()),                        // any other case is probably bad
-        }
-        // Wait for the api to catch up
-        sleep(Duration::from_secs(1)).await;
-        Ok(())
+    fn crd(&self) -> CustomResourceDefinition{
+        BgpRouter::crd()
     }
 }
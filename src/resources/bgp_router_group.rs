@@ -1,13 +1,9 @@
 use garde::Validate;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use std::time::Duration;
-use tokio::time::sleep;
-use tracing::*;
 
 use k8s_openapi::apiextensions_apiserver::pkg::apis::apiextensions::v1::CustomResourceDefinition;
 use kube::{
-    api::{Api, PostParams, ResourceExt},
     core::crd::CustomResourceExt,
     Client, CustomResource,
 };
@@ -22,7 +18,7 @@ use k8s_openapi::apimachinery::pkg::apis::meta::v1 as meta_v1;
 #[kube(group = "cnm.juniper.net", version = "v1", kind = "BgpRouterGroup", namespaced)]
 #[kube(status = "BgpRouterGroupStatus")]
 #[serde(rename_all = "camelCase")]
-//#[kube(printcolumn = r#"{"name":"Team", "jsonPath": ".spec.metadata.team", "type": "string"}"#)]
+#[kube(printcolumn = r#"{"name":"Ready", "jsonPath": ".status.conditions[?(@.type==\"Ready\")].status", "type": "string"}"#)]
 pub struct BgpRouterGroupSpec {
     #[schemars(length(min = 1))]
     #[garde(skip)]
@@ -37,13 +33,20 @@ pub struct BgpRouterGroupSpec {
 #[serde(rename_all = "camelCase")]
 pub struct BgpRouterGroupStatus {
     pub bgp_router_references: Vec<BgpRouterReference>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub observed_generation: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub conditions: Option<Vec<meta_v1::Condition>>,
 }
 
 #[derive(Deserialize, Serialize, Clone, Debug, Default, JsonSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct BgpRouterReference{
     pub bgp_router_reference: core_v1::ObjectReference,
-    pub local_address: String
+    pub local_address: String,
+    // every address of a dual-stack router, local_address included
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub local_addresses: Vec<String>,
 }
 
 pub struct BgpRouterGroupResource{
@@ -81,20 +84,7 @@ impl Resource for BgpRouterGroupResource{
     fn version(&self) -> String {
         self.version.clone()
     }
-    async fn create(&self) -> anyhow::Result<()>{
-        let crds: Api<CustomResourceDefinition> = Api::all(self.client.clone());
-        let crd = BgpRouterGroup::crd();
-        info!("Creating CRD: {}",self.name);
-        let pp = PostParams::default();
-        match crds.create(&pp, &crd).await {
-            Ok(o) => {
-                info!("Created {}", o.name_any());
-            }
-            Err(kube::Error::Api(ae)) => assert_eq!(ae.code, 409), // if you skipped delete, for instance
-            Err(e) => return Err(e.into()),                        // any other case is probably bad
-        }
-        // Wait for the api to catch up
-        sleep(Duration::from_secs(1)).await;
-        Ok(())
+    fn crd(&self) -> CustomResourceDefinition{
+        BgpRouterGroup::crd()
     }
 }
@@ -1,49 +1,211 @@
 use garde::Validate;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use std::{time::Duration, collections::BTreeMap};
-use tokio::time::sleep;
-use tracing::*;
+use std::collections::BTreeMap;
 use k8s_openapi::apiextensions_apiserver::pkg::apis::apiextensions::v1::CustomResourceDefinition;
 use kube::{
-    api::{Api, PostParams, ResourceExt},
     core::crd::CustomResourceExt,
     Client, CustomResource,
 };
 use async_trait::async_trait;
 use k8s_openapi::api::apps::v1 as apps_v1;
+use k8s_openapi::api::core::v1 as core_v1;
+use k8s_openapi::apimachinery::pkg::api::resource::Quantity;
+use k8s_openapi::apimachinery::pkg::util::intstr::IntOrString;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1 as meta_v1;
 use k8s_openapi::Metadata;
 use kube::api::ObjectMeta;
 use std::collections::HashMap;
 
 use crate::resources::resources::Resource;
+use crate::resources::bgp_router::IpFamily;
 
 #[derive(CustomResource, Deserialize, Serialize, Clone, Debug, Validate, JsonSchema)]
 #[kube(group = "cnm.juniper.net", version = "v1", kind = "Crpd", namespaced)]
 #[kube(status = "CrpdStatus")]
 #[serde(rename_all = "camelCase")]
-//#[kube(printcolumn = r#"{"name":"Team", "jsonPath": ".spec.metadata.team", "type": "string"}"#)]
+#[kube(printcolumn = r#"{"name":"Ready", "jsonPath": ".status.conditions[?(@.type==\"Ready\")].status", "type": "string"}"#)]
 pub struct CrpdSpec {
     #[garde(skip)]
+    #[serde(default)]
+    pub mode: CrpdMode,
+    // number of instances in StatefulSet mode, ignored in DaemonSet mode
+    #[garde(skip)]
+    #[serde(default)]
     pub replicas: i32,
     #[garde(skip)]
     pub image: String,
     #[garde(skip)]
     pub init_image: String,
+    // resources of the crpd container
+    #[garde(skip)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resources: Option<core_v1::ResourceRequirements>,
+    #[garde(skip)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub node_selector: Option<BTreeMap<String, String>>,
+    #[garde(skip)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub affinity: Option<core_v1::Affinity>,
+    // spreading of the instances, a constraint without labelSelector selects
+    // the pods of the Crpd. Without affinity and topologySpreadConstraints
+    // the instances prefer different nodes and zones.
+    #[garde(skip)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub topology_spread_constraints: Option<Vec<core_v1::TopologySpreadConstraint>>,
+    // instances a voluntary disruption like a node drain may take down at
+    // once, 1 when not set
+    #[garde(skip)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_unavailable: Option<IntOrString>,
+    // tolerations added to the default control plane toleration
+    #[garde(skip)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tolerations: Option<Vec<core_v1::Toleration>>,
+    // env of the crpd container, a variable replaces a generated one of the same name
+    #[garde(skip)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub env: Option<Vec<core_v1::EnvVar>>,
+    // volumes added to the pod, mounted into the crpd container by volumeMounts
+    #[garde(skip)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub volumes: Option<Vec<core_v1::Volume>>,
+    #[garde(skip)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub volume_mounts: Option<Vec<core_v1::VolumeMount>>,
+    #[garde(skip)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image_pull_secrets: Option<Vec<core_v1::LocalObjectReference>>,
+    #[garde(skip)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub priority_class_name: Option<String>,
+    // annotations of the pod template
+    #[garde(skip)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub annotations: Option<BTreeMap<String, String>>,
+    // persistent volume for /config, an emptyDir is used when not set.
+    // Only supported in StatefulSet mode.
+    #[garde(skip)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub config_storage: Option<ConfigStorage>,
+    // time a replaced instance has to come back during an upgrade before the
+    // upgrade halts, 600 when not set. Only supported in StatefulSet mode,
+    // the DaemonSet controller rolls the pods of a DaemonSet.
+    #[garde(skip)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upgrade_timeout_seconds: Option<i64>,
+    // secret key holding the Junos license installed on every instance
+    #[garde(skip)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub license_secret_ref: Option<core_v1::SecretKeySelector>,
 }
 
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ConfigStorage {
+    // storage class of the claim, the cluster default when not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub storage_class_name: Option<String>,
+    pub size: Quantity,
+}
 
+
+// CrpdMode selects the workload the cRPD instances run in: a StatefulSet
+// with spec.replicas pods or a DaemonSet with one pod per node
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq, JsonSchema)]
+pub enum CrpdMode {
+    #[default]
+    StatefulSet,
+    DaemonSet,
+}
+
+// the StatefulSet status was served as stateful_set before the status used
+// camelCase, the controller writes it again as statefulSet on the next reconcile
 #[derive(Deserialize, Serialize, Clone, Debug, Default, JsonSchema)]
+#[serde(rename_all = "camelCase")]
 pub struct CrpdStatus {
     pub stateful_set: Option<apps_v1::StatefulSetStatus>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub daemon_set: Option<apps_v1::DaemonSetStatus>,
     pub instances: Option<Vec<Instance>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub observed_generation: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub conditions: Option<Vec<meta_v1::Condition>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upgrade: Option<UpgradeStatus>,
+}
+
+// UpgradeStatus tracks the instance replaced by an ongoing upgrade
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct UpgradeStatus {
+    // StatefulSet revision the instances are upgraded to
+    pub revision: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub instance: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub started: Option<meta_v1::Time>,
+    // BgpRouters the instance had before it was replaced
+    #[serde(default)]
+    pub bgp_routers: i32,
+    // established BGP sessions of the instance before it was replaced
+    #[serde(default)]
+    pub bgp_sessions: i32,
 }
 
 #[derive(Deserialize, Serialize, Clone, Debug, Default, JsonSchema)]
 pub struct Instance{
     pub name: String,
+    // the primary pod ip
     pub address: String,
     pub uuid: String,
+    // all pod ips, two on dual-stack clusters
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub addresses: Vec<InstanceAddress>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub license: Option<LicenseStatus>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub operational: Option<OperationalStatus>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct InstanceAddress{
+    pub address: String,
+    pub family: IpFamily,
+}
+
+// OperationalStatus is the state of an instance as read over JET
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct OperationalStatus{
+    // the controller could connect and log in to the instance
+    pub authenticated: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uptime: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_commit: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub established_bgp_sessions: Option<i32>,
+    // why the state could not be read
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct LicenseStatus{
+    pub installed: bool,
+    // earliest end date of the licensed features, none for permanent licenses
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expiry: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub features: Vec<String>,
+    // why the license state could not be read or the license not installed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
 }
 
 pub struct CrpdResource{
@@ -82,19 +244,7 @@ impl Resource for CrpdResource{
     fn version(&self) -> String {
         self.version.clone()
     }
-    async fn create(&self) -> anyhow::Result<()>{
-        let crds: Api<CustomResourceDefinition> = Api::all(self.client.clone());
-        let crd = Crpd::crd();
-        info!("Creating CRD: {}",self.name);
-        let pp = PostParams::default();
-        match crds.create(&pp, &crd).await {
-            Ok(o) => {
-                info!("Created {}", o.name_any());
-            }
-            Err(kube::Error::Api(ae)) => assert_eq!(ae.code, 409), // if you skipped delete, for instance
-            Err(e) => return Err(e.into()),                        // any other case is probably bad
-        }
-        sleep(Duration::from_secs(1)).await;
-        Ok(())
+    fn crd(&self) -> CustomResourceDefinition{
+        Crpd::crd()
     }
 }
@@ -1,13 +1,30 @@
 use std::time::Duration;
-use tokio::time::sleep;
 use tracing::*;
 
 use k8s_openapi::apiextensions_apiserver::pkg::apis::apiextensions::v1::CustomResourceDefinition;
 use kube::{
-    api::{Api, DeleteParams, ResourceExt, PatchParams},
+    api::{Api, Patch, PatchParams},
+    core::crd::merge_crds,
+    runtime::wait::{await_condition, conditions},
     Client,
 };
 use async_trait::async_trait;
+use crate::resources::routing_instance;
+
+pub const GROUP: &str = "cnm.juniper.net";
+pub const SERVED_VERSIONS: [&str; 2] = ["v1alpha1", "v1"];
+pub const STORAGE_VERSION: &str = "v1";
+// kinds whose versions differ in shape and are converted by the webhook.
+// The apiserver converts the other kinds itself (strategy None), only
+// rewriting their apiVersion.
+pub const CONVERTED_KINDS: [&str; 1] = ["RoutingInstance"];
+// the multi version CRDs by plural name and kind
+pub const CRDS: [(&str, &str); 4] = [
+    ("crpds", "Crpd"),
+    ("bgprouters", "BgpRouter"),
+    ("bgproutergroups", "BgpRouterGroup"),
+    ("routinginstances", "RoutingInstance"),
+];
 
 #[async_trait]
 pub trait Resource: Send + Sync{
@@ -15,38 +32,180 @@ pub trait Resource: Send + Sync{
     fn name(&self) -> String;
     fn group(&self) -> String;
     fn version(&self) -> String;
-    async fn delete(&self) -> anyhow::Result<()>{
-        let client = self.client();
-        let crds: Api<CustomResourceDefinition> = Api::all(client.clone());
+    fn crd(&self) -> CustomResourceDefinition;
+    // older_crds returns the CRDs of the served versions whose schema differs
+    // from the one of the storage version
+    fn older_crds(&self) -> Vec<CustomResourceDefinition>{
+        Vec::new()
+    }
+    // apply installs or upgrades the CRD with server side apply. Existing CRDs
+    // and their instances are never deleted.
+    async fn apply(&self) -> anyhow::Result<()>{
+        let crds: Api<CustomResourceDefinition> = Api::all(self.client());
         let fqdn = format!("{}.{}", self.name(), self.group());
-        let dp = DeleteParams::default();
-        let _ = crds.get(fqdn.as_str()).await.map(|res|{
-            let pp = PatchParams::default();
-        });
-        let _ = crds.delete(fqdn.as_str(), &dp).await.map(|res| {
-            res.map_left(|o| {
-                info!(
-                    "Deleting {}: ({:?})",
-                    o.name_any(),
-                    o.status.unwrap().conditions.unwrap().last()
-                );
-            })
-            .map_right(|s| {
-                info!("Deleted foos.clux.dev: ({:?})", s);
-            })
-        });
-        sleep(Duration::from_secs(2)).await;
+        info!("Applying CRD: {}", fqdn);
+        let params = PatchParams::apply("cnm").force();
+        let crd = served_crd(self.crd(), self.older_crds())?;
+        crds.patch(fqdn.as_str(), &params, &Patch::Apply(&crd)).await?;
+        let established = await_condition(crds, fqdn.as_str(), conditions::is_crd_established());
+        match tokio::time::timeout(Duration::from_secs(30), established).await{
+            Ok(res) => {
+                res?;
+                info!("CRD {} established", fqdn);
+            },
+            Err(_) => {
+                return Err(anyhow::anyhow!("timed out waiting for CRD {} to be established", fqdn));
+            },
+        }
         Ok(())
     }
-    async fn create(&self) -> anyhow::Result<()>;
 }
 
 pub async fn init_resources(resource_list: Vec<Box<dyn Resource>>) -> anyhow::Result<()>{
     for resource in &resource_list{
-        resource.delete().await?;
-    }
-    for resource in &resource_list{
-        resource.create().await?;
+        resource.apply().await?;
     }
     Ok(())
-}
\ No newline at end of file
+}
+
+
+// served_crd turns the CRD generated for the storage version into a CRD serving
+// all SERVED_VERSIONS. Versions share the storage schema unless older_crds has
+// one of their own, convert migrates between the shapes.
+pub fn served_crd(crd: CustomResourceDefinition, older_crds: Vec<CustomResourceDefinition>) -> anyhow::Result<CustomResourceDefinition>{
+    let crds = SERVED_VERSIONS.iter().map(|version| {
+        let older = older_crds.iter().find(|older| older.spec.versions.iter().any(|crd_version| crd_version.name == *version));
+        if let Some(older) = older{
+            return older.clone();
+        }
+        let mut crd = crd.clone();
+        for crd_version in crd.spec.versions.iter_mut(){
+            crd_version.name = version.to_string();
+        }
+        crd
+    }).collect();
+    Ok(merge_crds(crds, STORAGE_VERSION)?)
+}
+
+// convert migrates a custom resource to the desired apiVersion. It is called by
+// the conversion webhook for every object of the CONVERTED_KINDS the apiserver
+// needs in another version.
+pub fn convert(mut object: serde_json::Value, desired_api_version: &str) -> anyhow::Result<serde_json::Value>{
+    let desired_version = match desired_api_version.split_once('/'){
+        Some((group, version)) if group == GROUP => version,
+        _ => return Err(anyhow::anyhow!("unsupported apiVersion {}", desired_api_version)),
+    };
+    if !SERVED_VERSIONS.contains(&desired_version){
+        return Err(anyhow::anyhow!("unsupported version {}", desired_version));
+    }
+    let kind = match object.get("kind").and_then(|kind| kind.as_str()){
+        Some(kind) => kind.to_string(),
+        None => return Err(anyhow::anyhow!("object without kind")),
+    };
+    if !CONVERTED_KINDS.contains(&kind.as_str()){
+        return Err(anyhow::anyhow!("unsupported kind {}", kind));
+    }
+    let version = match object.get("apiVersion").and_then(|api_version| api_version.as_str()).and_then(|api_version| api_version.split_once('/')){
+        Some((_, version)) => version.to_string(),
+        None => return Err(anyhow::anyhow!("{} without apiVersion", kind)),
+    };
+    match (kind.as_str(), version.as_str(), desired_version){
+        ("RoutingInstance", "v1", "v1alpha1") => routing_instance::to_v1alpha1(&mut object)?,
+        ("RoutingInstance", "v1alpha1", "v1") => routing_instance::to_v1(&mut object)?,
+        _ => {},
+    }
+    object["apiVersion"] = serde_json::Value::String(desired_api_version.to_string());
+    Ok(object)
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::resources::bgp_router::BgpRouter;
+    use crate::resources::crpd::crpd::Crpd;
+    use crate::resources::routing_instance::RoutingInstance;
+    use kube::core::crd::CustomResourceExt;
+
+    #[test]
+    fn served_crd_serves_all_versions_and_stores_one(){
+        let crd = served_crd(BgpRouter::crd(), Vec::new()).unwrap();
+        let mut versions: Vec<(&str, bool, bool)> = crd.spec.versions.iter()
+            .map(|version| (version.name.as_str(), version.served, version.storage))
+            .collect();
+        versions.sort();
+        assert_eq!(versions, vec![("v1", true, true), ("v1alpha1", true, false)]);
+        assert_eq!(crd.spec.versions[0].schema, crd.spec.versions[1].schema);
+    }
+
+    #[test]
+    fn crpd_status_is_camel_case(){
+        let crd = Crpd::crd();
+        let schema = crd.spec.versions[0].schema.as_ref().unwrap().open_api_v3_schema.as_ref().unwrap();
+        let status = &schema.properties.as_ref().unwrap()["status"];
+        let fields: Vec<&String> = status.properties.as_ref().unwrap().keys().collect();
+        for field in ["statefulSet", "daemonSet", "observedGeneration"]{
+            assert!(fields.iter().any(|f| f.as_str() == field), "{:?}", fields);
+        }
+        assert!(!fields.iter().any(|f| f.contains('_')), "{:?}", fields);
+    }
+
+    #[test]
+    fn served_crd_keeps_the_schema_of_older_versions(){
+        let crd = served_crd(RoutingInstance::crd(), vec![routing_instance::v1alpha1::RoutingInstance::crd()]).unwrap();
+        let spec_fields = |name: &str| -> Vec<String>{
+            let version = crd.spec.versions.iter().find(|version| version.name == name).unwrap();
+            let schema = version.schema.as_ref().unwrap().open_api_v3_schema.as_ref().unwrap();
+            schema.properties.as_ref().unwrap()["spec"].properties.as_ref().unwrap().keys().cloned().collect()
+        };
+        assert_eq!(spec_fields("v1alpha1"), vec!["crpdSelector", "instance"]);
+        assert_eq!(spec_fields("v1"), vec!["instance", "selector"]);
+        assert!(crd.spec.versions.iter().any(|version| version.name == "v1" && version.storage));
+    }
+
+    #[test]
+    fn convert_migrates_routing_instance_selectors(){
+        let v1alpha1 = serde_json::json!({
+            "apiVersion": "cnm.juniper.net/v1alpha1",
+            "kind": "RoutingInstance",
+            "metadata": {"name": "ri1"},
+            "spec": {"instance": {"name": "vrf1"}, "crpdSelector": {"app": "crpd"}},
+        });
+        let v1 = convert(v1alpha1.clone(), "cnm.juniper.net/v1").unwrap();
+        assert_eq!(v1["apiVersion"], "cnm.juniper.net/v1");
+        assert_eq!(v1["spec"], serde_json::json!({"instance": {"name": "vrf1"}, "selector": {"matchLabels": {"app": "crpd"}}}));
+        assert_eq!(convert(v1, "cnm.juniper.net/v1alpha1").unwrap(), v1alpha1);
+    }
+
+    #[test]
+    fn convert_keeps_selector_expressions_across_a_round_trip(){
+        let v1 = serde_json::json!({
+            "apiVersion": "cnm.juniper.net/v1",
+            "kind": "RoutingInstance",
+            "metadata": {"name": "ri1"},
+            "spec": {"instance": {"name": "vrf1"}, "selector": {
+                "matchLabels": {"app": "crpd"},
+                "matchExpressions": [{"key": "tier", "operator": "In", "values": ["edge"]}],
+            }},
+        });
+        let v1alpha1 = convert(v1.clone(), "cnm.juniper.net/v1alpha1").unwrap();
+        assert_eq!(v1alpha1["spec"]["crpdSelector"], serde_json::json!({"app": "crpd"}));
+        assert!(v1alpha1["spec"].get("selector").is_none());
+        assert_eq!(convert(v1alpha1, "cnm.juniper.net/v1").unwrap(), v1);
+    }
+
+    #[test]
+    fn convert_rejects_unknown_versions_and_kinds(){
+        let object = serde_json::json!({
+            "apiVersion": "cnm.juniper.net/v1",
+            "kind": "RoutingInstance",
+            "metadata": {"name": "ri1"},
+            "spec": {"instance": {"name": "vrf1"}, "selector": {}},
+        });
+        assert!(convert(object.clone(), "cnm.juniper.net/v2").is_err());
+        assert!(convert(object.clone(), "other.group/v1").is_err());
+        // kinds sharing one schema are converted by the apiserver
+        let mut bgp_router = object;
+        bgp_router["kind"] = serde_json::json!("BgpRouter");
+        assert!(convert(bgp_router, "cnm.juniper.net/v1alpha1").is_err());
+    }
+}
@@ -1,14 +1,10 @@
 use garde::Validate;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use std::time::Duration;
-use tokio::time::sleep;
-use tracing::*;
 
 use k8s_openapi::apiextensions_apiserver::pkg::apis::apiextensions::v1::CustomResourceDefinition;
 use k8s_openapi::apimachinery::pkg::apis::meta::v1 as meta_v1;
 use kube::{
-    api::{Api, PostParams, ResourceExt},
     core::crd::CustomResourceExt,
     Client, CustomResource,
 };
@@ -32,6 +28,74 @@ pub struct RoutingInstanceSpec {
 pub struct RoutingInstanceStatus {
 }
 
+// v1alpha1 selected the cRPDs by their labels only, v1 takes a full
+// LabelSelector
+pub mod v1alpha1{
+    use super::*;
+    use std::collections::BTreeMap;
+
+    #[derive(CustomResource, Deserialize, Serialize, Clone, Debug, JsonSchema)]
+    #[kube(group = "cnm.juniper.net", version = "v1alpha1", kind = "RoutingInstance", namespaced)]
+    #[kube(status = "RoutingInstanceStatus")]
+    #[serde(rename_all = "camelCase")]
+    pub struct RoutingInstanceSpec {
+        pub instance: Instance,
+        #[serde(default)]
+        pub crpd_selector: BTreeMap<String, String>,
+    }
+}
+
+// v1alpha1 objects keep the selector of a v1 object which has expressions in
+// this annotation, so that converting them back to v1 loses nothing
+const SELECTOR_ANNOTATION: &str = "cnm.juniper.net/selector";
+
+// to_v1alpha1 migrates the spec of a v1 RoutingInstance to v1alpha1
+pub fn to_v1alpha1(object: &mut serde_json::Value) -> anyhow::Result<()>{
+    let spec = match object.get_mut("spec").and_then(|spec| spec.as_object_mut()){
+        Some(spec) => spec,
+        None => return Err(anyhow::anyhow!("RoutingInstance without spec")),
+    };
+    let selector = spec.remove("selector").unwrap_or(serde_json::Value::Null);
+    let match_labels = selector.get("matchLabels").cloned().unwrap_or_else(|| serde_json::json!({}));
+    spec.insert("crpdSelector".to_string(), match_labels);
+    let has_expressions = selector.get("matchExpressions")
+        .and_then(|expressions| expressions.as_array())
+        .map(|expressions| !expressions.is_empty())
+        .unwrap_or(false);
+    if has_expressions{
+        let annotations = object["metadata"].as_object_mut()
+            .ok_or(anyhow::anyhow!("RoutingInstance without metadata"))?
+            .entry("annotations")
+            .or_insert_with(|| serde_json::json!({}));
+        annotations[SELECTOR_ANNOTATION] = serde_json::Value::String(selector.to_string());
+    }
+    Ok(())
+}
+
+// to_v1 migrates the spec of a v1alpha1 RoutingInstance to v1
+pub fn to_v1(object: &mut serde_json::Value) -> anyhow::Result<()>{
+    let mut saved = None;
+    if let Some(metadata) = object.get_mut("metadata").and_then(|metadata| metadata.as_object_mut()){
+        if let Some(annotations) = metadata.get_mut("annotations").and_then(|annotations| annotations.as_object_mut()){
+            saved = annotations.remove(SELECTOR_ANNOTATION);
+            if annotations.is_empty(){
+                metadata.remove("annotations");
+            }
+        }
+    }
+    let spec = match object.get_mut("spec").and_then(|spec| spec.as_object_mut()){
+        Some(spec) => spec,
+        None => return Err(anyhow::anyhow!("RoutingInstance without spec")),
+    };
+    let crpd_selector = spec.remove("crpdSelector").unwrap_or_else(|| serde_json::json!({}));
+    let selector = match saved.as_ref().and_then(|saved| saved.as_str()){
+        Some(saved) => serde_json::from_str(saved)?,
+        None => serde_json::json!({"matchLabels": crpd_selector}),
+    };
+    spec.insert("selector".to_string(), selector);
+    Ok(())
+}
+
 pub struct RoutingInstanceResource{
     client: Client,
     name: String,
@@ -67,20 +131,10 @@ impl Resource for RoutingInstanceResource{
     fn version(&self) -> String {
         self.version.clone()
     }
-    async fn create(&self) -> anyhow::Result<()>{
-        let crds: Api<CustomResourceDefinition> = Api::all(self.client.clone());
-        let crd = RoutingInstance::crd();
-        info!("Creating CRD: {}",self.name);
-        let pp = PostParams::default();
-        match crds.create(&pp, &crd).await {
-            Ok(o) => {
-                info!("Created {}", o.name_any());
-            }
-            Err(kube::Error::Api(ae)) => assert_eq!(ae.code, 409), // if you skipped delete, for instance
-            Err(e) => return Err(e.into()),                        // any other case is probably bad
-        }
-        // Wait for the api to catch up
-        sleep(Duration::from_secs(1)).await;
-        Ok(())
+    fn crd(&self) -> CustomResourceDefinition{
+        RoutingInstance::crd()
+    }
+    fn older_crds(&self) -> Vec<CustomResourceDefinition>{
+        vec![v1alpha1::RoutingInstance::crd()]
     }
 }
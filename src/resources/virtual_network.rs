@@ -1,13 +1,9 @@
 use garde::Validate;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use std::time::Duration;
-use tokio::time::sleep;
-use tracing::*;
 
 use k8s_openapi::apiextensions_apiserver::pkg::apis::apiextensions::v1::CustomResourceDefinition;
 use kube::{
-    api::{Api, PostParams, ResourceExt},
     core::crd::CustomResourceExt,
     Client, CustomResource,
 };
@@ -66,20 +62,7 @@ impl Resource for VirtualNetworkResource{
     fn version(&self) -> String {
         self.version.clone()
     }
-    async fn create(&self) -> anyhow::Result<()>{
-        let crds: Api<CustomResourceDefinition> = Api::all(self.client.clone());
-        let crd = VirtualNetwork::crd();
-        info!("Creating CRD: {}",self.name);
-        let pp = PostParams::default();
-        match crds.create(&pp, &crd).await {
-            Ok(o) => {
-                info!("Created {}", o.name_any());
-            }
-            Err(kube::Error::Api(ae)) => assert_eq!(ae.code, 409), // if you skipped delete, for instance
-            Err(e) => return Err(e.into()),                        // any other case is probably bad
-        }
-        // Wait for the api to catch up
-        sleep(Duration::from_secs(1)).await;
-        Ok(())
+    fn crd(&self) -> CustomResourceDefinition{
+        VirtualNetwork::crd()
     }
 }